@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use sqlx::FromRow;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    pub action: String,
+    pub entity_type: String,
+    pub entity_id: i64,
+    #[serde(serialize_with = "crate::models::serialize_timestamp")]
+    pub timestamp: String,
+    pub before_json: Option<String>,
+    pub after_json: Option<String>,
+}
+
+#[derive(Debug)]
+struct PendingEntry {
+    action: &'static str,
+    entity_type: &'static str,
+    entity_id: i64,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+}
+
+/// Handle for appending to the audit log.
+///
+/// Entries are queued to a background writer so recording a mutation costs a
+/// channel send rather than an extra INSERT on the request path.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: mpsc::UnboundedSender<PendingEntry>,
+}
+
+impl AuditLog {
+    pub fn spawn(db: SqlitePool) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<PendingEntry>();
+
+        tokio::spawn(async move {
+            while let Some(entry) = receiver.recv().await {
+                // No user accounts exist yet, so user_id is left NULL
+                let result = sqlx::query(
+                    "INSERT INTO audit_log (user_id, action, entity_type, entity_id, before_json, after_json)
+                     VALUES (NULL, ?, ?, ?, ?, ?)"
+                )
+                .bind(entry.action)
+                .bind(entry.entity_type)
+                .bind(entry.entity_id)
+                .bind(entry.before.map(|v| v.to_string()))
+                .bind(entry.after.map(|v| v.to_string()))
+                .execute(&db)
+                .await;
+
+                if let Err(e) = result {
+                    tracing::error!("Failed to write audit entry {:?}: {}", entry.action, e);
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    pub fn record<B: Serialize, A: Serialize>(
+        &self,
+        action: &'static str,
+        entity_type: &'static str,
+        entity_id: i64,
+        before: Option<&B>,
+        after: Option<&A>,
+    ) {
+        let entry = PendingEntry {
+            action,
+            entity_type,
+            entity_id,
+            before: before.and_then(|b| serde_json::to_value(b).ok()),
+            after: after.and_then(|a| serde_json::to_value(a).ok()),
+        };
+
+        if self.sender.send(entry).is_err() {
+            tracing::error!("Audit writer has stopped; dropping entry");
+        }
+    }
+}
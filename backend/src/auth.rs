@@ -0,0 +1,62 @@
+use crate::AppState;
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+
+/// Extractor that only succeeds for requests carrying the configured admin token.
+///
+/// Admin routes are disabled entirely (403) when ADMIN_TOKEN is not set.
+pub struct AdminToken;
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminToken {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let expected = state.config.admin_token.as_deref().ok_or(StatusCode::FORBIDDEN)?;
+
+        let provided = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+            Ok(AdminToken)
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+// Longest editor name kept, in characters
+const MAX_EDITOR_NAME_LEN: usize = 100;
+
+/// Display name of the person making a request, from the X-Editor header.
+///
+/// There are no user accounts yet, so this is whatever the client reports;
+/// it is only used for "last edited by" provenance, never for access control.
+pub struct Editor(pub Option<String>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Editor {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &AppState) -> Result<Self, Self::Rejection> {
+        let name = parts
+            .headers
+            .get("x-editor")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().chars().take(MAX_EDITOR_NAME_LEN).collect::<String>())
+            .filter(|value| !value.is_empty());
+
+        Ok(Editor(name))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
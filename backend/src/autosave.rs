@@ -0,0 +1,162 @@
+use crate::audit::AuditLog;
+use crate::models::Content;
+use crate::query::traced;
+use sqlx::sqlite::SqlitePool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+// A node under continuous autosave is still written at least this many windows apart
+const MAX_DELAY_WINDOWS: u32 = 4;
+
+struct PendingSave {
+    content_json: String,
+    word_count: i64,
+    editor: Option<String>,
+    generation: u64,
+    queued_at: Instant,
+    waiters: Vec<oneshot::Sender<Option<Content>>>,
+}
+
+/// Server-side coalescing of content saves.
+///
+/// A coalesced save waits out a short window; if another save for the same
+/// node arrives in the meantime, only the latest content is written and every
+/// waiting request is answered with that row.
+#[derive(Clone)]
+pub struct SaveCoalescer {
+    db: SqlitePool,
+    audit: AuditLog,
+    window: Duration,
+    pending: Arc<Mutex<HashMap<i64, PendingSave>>>,
+    generation: Arc<AtomicU64>,
+}
+
+impl SaveCoalescer {
+    pub fn new(db: SqlitePool, audit: AuditLog, window: Duration) -> Self {
+        Self {
+            db,
+            audit,
+            window,
+            pending: Arc::default(),
+            generation: Arc::default(),
+        }
+    }
+
+    /// Queue a save and resolve with the content eventually written for the
+    /// node (None if that write failed)
+    pub async fn save(&self, node_id: i64, content_json: String, word_count: i64, editor: Option<String>) -> Option<Content> {
+        let (sender, receiver) = oneshot::channel();
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            let entry = pending.entry(node_id).or_insert_with(|| PendingSave {
+                content_json: String::new(),
+                word_count: 0,
+                editor: None,
+                generation,
+                queued_at: Instant::now(),
+                waiters: Vec::new(),
+            });
+            entry.content_json = content_json;
+            entry.word_count = word_count;
+            entry.editor = editor;
+            entry.generation = generation;
+            entry.waiters.push(sender);
+        }
+
+        // The flush runs on its own task so it still happens if the client goes away
+        let coalescer = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(coalescer.window).await;
+            coalescer.flush_if_due(node_id, generation).await;
+        });
+
+        receiver.await.ok().flatten()
+    }
+
+    /// Nodes with a save still waiting out its window
+    pub fn pending_node_ids(&self) -> Vec<i64> {
+        self.pending.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Write immediately, superseding (and answering) any queued save for the node
+    pub async fn save_now(&self, node_id: i64, content_json: String, word_count: i64, editor: Option<String>) -> Option<Content> {
+        let superseded = self.pending.lock().unwrap().remove(&node_id);
+
+        let content = self.write(node_id, &content_json, word_count, editor.as_deref()).await;
+        if let Some(save) = superseded {
+            for waiter in save.waiters {
+                let _ = waiter.send(content.clone());
+            }
+        }
+
+        content
+    }
+
+    async fn flush_if_due(&self, node_id: i64, generation: u64) {
+        let save = {
+            let mut pending = self.pending.lock().unwrap();
+            let Some(entry) = pending.get(&node_id) else {
+                return;
+            };
+
+            // A newer save restarted the window, unless the node has waited long enough
+            let overdue = entry.queued_at.elapsed() >= self.window * MAX_DELAY_WINDOWS;
+            if entry.generation != generation && !overdue {
+                return;
+            }
+
+            pending.remove(&node_id).unwrap()
+        };
+
+        let content = self.write(node_id, &save.content_json, save.word_count, save.editor.as_deref()).await;
+        for waiter in save.waiters {
+            let _ = waiter.send(content.clone());
+        }
+    }
+
+    async fn write(&self, node_id: i64, content_json: &str, word_count: i64, editor: Option<&str>) -> Option<Content> {
+        match write_content(&self.db, node_id, content_json, word_count, editor).await {
+            Ok(content) => {
+                // Content bodies are not copied into the log; autosave would duplicate them on every keystroke burst
+                self.audit.record("save", "content", node_id, None::<&()>, None::<&()>);
+                Some(content)
+            }
+            Err(e) => {
+                tracing::warn!("Failed to save content for node {}: {}", node_id, e);
+                None
+            }
+        }
+    }
+}
+
+async fn write_content(db: &SqlitePool, node_id: i64, content_json: &str, word_count: i64, editor: Option<&str>) -> sqlx::Result<Content> {
+    // One transaction, so the cached word counts never disagree with the content
+    let mut tx = db.begin().await?;
+
+    traced("upsert", "content", sqlx::query(
+        "INSERT INTO content (node_id, content_json) VALUES (?, ?)
+         ON CONFLICT(node_id) DO UPDATE SET content_json = ?, version = version + 1, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')"
+    )
+    .bind(node_id)
+    .bind(content_json)
+    .bind(content_json)
+    .execute(&mut *tx))
+    .await?;
+
+    crate::handlers::set_word_count(&mut tx, node_id, word_count).await?;
+    crate::handlers::record_node_edit(&mut tx, node_id, editor).await?;
+
+    let content = sqlx::query_as::<_, Content>("SELECT * FROM content WHERE node_id = ?")
+        .bind(node_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(content)
+}
@@ -0,0 +1,22 @@
+//! Copies of the SQLite database taken while the server is using it
+
+use sqlx::sqlite::SqlitePool;
+use std::path::{Path, PathBuf};
+
+/// Write a consistent copy of the database into `dir`, creating it if
+/// needed, and return the copy's path and size in bytes. VACUUM INTO reads
+/// in one transaction, so writes racing the copy are either in it or not.
+pub async fn write_copy(db: &SqlitePool, dir: &Path) -> anyhow::Result<(PathBuf, u64)> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    let name = format!("type_editor-{}.db", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+    let path = dir.join(name);
+
+    sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy())
+        .execute(db)
+        .await?;
+
+    let size = tokio::fs::metadata(&path).await?.len();
+    Ok((path, size))
+}
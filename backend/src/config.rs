@@ -0,0 +1,311 @@
+//! Startup configuration. Every setting is read from the environment once,
+//! here, and a bad value stops the server with a list of everything that is
+//! wrong rather than quietly falling back to a default.
+
+use crate::models::ChildSort;
+use crate::storage::Backend;
+use axum::http::{HeaderName, HeaderValue, Method};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub struct Config {
+    /// Address and port to listen on, unless `listen_uds` is set
+    pub host: IpAddr,
+    pub port: u16,
+    /// Unix socket to listen on instead of TCP, and its permission bits
+    pub listen_uds: Option<PathBuf>,
+    pub listen_uds_mode: u32,
+
+    pub db_path: String,
+    pub db_acquire_timeout: Duration,
+    /// Connections in a separate query_only pool for GET handlers, which
+    /// also switches the database to WAL so reads don't wait on a writer.
+    /// Without it everything shares the one pool.
+    pub read_pool_size: Option<u32>,
+    pub query_timeout: Duration,
+    /// Queries taking at least this long are logged as warnings
+    pub slow_query_threshold: Duration,
+    pub seed_welcome_doc: bool,
+    /// Copy the database into `backup_dir` when the server shuts down
+    pub backup_on_shutdown: bool,
+    pub backup_dir: PathBuf,
+
+    pub allowed_origins: Vec<HeaderValue>,
+    /// Accept any localhost origin instead of `allowed_origins`
+    pub cors_dev_mode: bool,
+    pub cors_allowed_methods: Vec<Method>,
+    pub cors_allowed_headers: Vec<HeaderName>,
+
+    /// Serve the built single-page app from `frontend_dir` for everything
+    /// outside /api and /uploads, so both ship as one unit
+    pub serve_frontend: bool,
+    pub frontend_dir: PathBuf,
+
+    pub storage_backend: Backend,
+    /// Directory for uploads with the local backend
+    pub uploads_dir: PathBuf,
+    pub max_upload_bytes: usize,
+    /// Uploads declaring more pixels than this are refused before any decoding
+    pub max_image_pixels: u64,
+    /// Images imported from a URL: overall time limit, and how many imports
+    /// the instance starts per minute
+    pub import_url_timeout: Duration,
+    pub import_url_per_minute: u32,
+
+    pub admin_token: Option<String>,
+    pub max_node_depth: i64,
+    /// Deeper content_json is refused on save before anything parses it
+    pub max_content_depth: usize,
+    /// Store saved content_json with sorted keys and no whitespace
+    pub normalize_content: bool,
+    /// Sibling order for documents without their own child_sort
+    pub default_child_sort: ChildSort,
+    /// Without user accounts every document belongs to the one implicit user,
+    /// so the per-user quota caps the instance. Unlimited when unset.
+    pub max_documents: Option<i64>,
+    /// Keeps one runaway document from slowing listings for everyone. Unlimited when unset.
+    pub max_nodes_per_document: Option<i64>,
+    pub autosave_window: Duration,
+    /// Saved cursor/selection state: largest accepted, and how long it is kept
+    pub editor_state_max_bytes: usize,
+    pub editor_state_ttl: Duration,
+
+    /// Exports of documents written more recently than this are flagged as stale
+    pub export_settle: Duration,
+    pub export_job_ttl: Duration,
+
+    /// How long each webhook delivery attempt may take
+    pub webhook_timeout: Duration,
+
+    pub request_timeout: Duration,
+    pub max_in_flight: usize,
+
+    /// Secret for documents created with "encrypted": true, and the one it
+    /// replaced while a rotation is under way
+    pub content_encryption_key: Option<String>,
+    pub content_encryption_key_previous: Option<String>,
+
+    /// Dark-launched features switched on through FEATURES
+    pub features: BTreeSet<String>,
+    /// Development aid: indent every JSON response, not just ?pretty=true ones
+    pub pretty_json: bool,
+    /// Support aid: log /api request and response bodies at debug level
+    pub log_bodies: bool,
+}
+
+/// An IP address to bind, e.g. 127.0.0.1 for local-only access or :: for IPv6
+fn parse_ip(value: &str) -> Result<IpAddr, String> {
+    value.parse().map_err(|_| "must be an IP address such as 0.0.0.0 or 127.0.0.1".to_string())
+}
+
+/// Every setting that failed to parse or validate
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut env = Env::default();
+
+        let config = Config {
+            // HOST is the older name for BIND_ADDR, still honoured when it is unset
+            host: match env.optional("BIND_ADDR", parse_ip) {
+                Some(addr) => addr,
+                None => env.parse_with("HOST", IpAddr::from([0, 0, 0, 0]), parse_ip),
+            },
+            port: env.parse("PORT", 3001),
+            listen_uds: env.string("LISTEN_UDS").map(PathBuf::from),
+            listen_uds_mode: env.parse_with("LISTEN_UDS_MODE", 0o660, |v| {
+                u32::from_str_radix(v, 8).map_err(|_| "must be octal permission bits, e.g. 660".to_string())
+            }),
+
+            db_path: env.string("DB_PATH").unwrap_or_else(|| "../type_editor.db".to_string()),
+            db_acquire_timeout: Duration::from_millis(env.positive("DB_ACQUIRE_TIMEOUT_MS", 3000)),
+            read_pool_size: env.optional("READ_POOL_SIZE", |v| match v.parse::<u32>() {
+                Ok(0) => Err("must be greater than zero; leave it unset to share one pool".to_string()),
+                Ok(n) => Ok(n),
+                Err(e) => Err(e.to_string()),
+            }),
+            query_timeout: Duration::from_millis(env.positive("DB_QUERY_TIMEOUT_MS", 5000)),
+            slow_query_threshold: Duration::from_millis(env.positive("SLOW_QUERY_MS", 200)),
+            seed_welcome_doc: env.flag("SEED_WELCOME_DOC", true),
+            backup_on_shutdown: env.flag("BACKUP_ON_SHUTDOWN", false),
+            backup_dir: env.string("BACKUP_DIR").map(PathBuf::from).unwrap_or_else(|| "../backups".into()),
+
+            allowed_origins: env.origins("ALLOWED_ORIGINS", "http://localhost:5000,http://localhost:3000"),
+            cors_dev_mode: env.flag("CORS_DEV_MODE", false),
+            cors_allowed_methods: env.list("CORS_ALLOWED_METHODS", "GET,POST,PUT,PATCH,DELETE,OPTIONS", |item| {
+                item.to_ascii_uppercase().parse::<Method>().map_err(|e| e.to_string())
+            }),
+            cors_allowed_headers: env.list(
+                "CORS_ALLOWED_HEADERS",
+                "content-type,authorization,x-editor,x-envelope,x-changed-fields",
+                // "*" can't be combined with credentialed requests
+                |item| match item {
+                    "*" => Err("wildcards are not allowed with credentials; list each header".to_string()),
+                    _ => item.parse::<HeaderName>().map_err(|e| e.to_string()),
+                },
+            ),
+
+            serve_frontend: env.flag("SERVE_FRONTEND", false),
+            frontend_dir: env.string("FRONTEND_DIR").map(PathBuf::from).unwrap_or_else(|| "../frontend/dist".into()),
+
+            storage_backend: env.parse_with("STORAGE_BACKEND", Backend::Local, |v| {
+                Backend::parse(v).ok_or_else(|| "must be local or s3".to_string())
+            }),
+            uploads_dir: env.string("UPLOADS_DIR").map(PathBuf::from).unwrap_or_else(|| "../uploads".into()),
+            max_upload_bytes: env.positive("MAX_UPLOAD_BYTES", 10 * 1024 * 1024),
+            max_image_pixels: env.positive("MAX_IMAGE_PIXELS", 50_000_000),
+            import_url_timeout: Duration::from_secs(env.positive("IMPORT_URL_TIMEOUT_SECS", 10)),
+            import_url_per_minute: env.positive("IMPORT_URL_PER_MINUTE", 10),
+
+            admin_token: env.string("ADMIN_TOKEN"),
+            max_node_depth: env.parse_with("MAX_NODE_DEPTH", 8, non_negative),
+            max_content_depth: env.positive("MAX_CONTENT_DEPTH", 64),
+            normalize_content: env.flag("NORMALIZE_CONTENT", false),
+            default_child_sort: env.parse_with("DEFAULT_CHILD_SORT", ChildSort::Manual, |v| {
+                ChildSort::parse(v).ok_or_else(|| "must be one of manual, title_asc, title_desc, created".to_string())
+            }),
+            max_documents: env.optional("MAX_DOCS_PER_USER", non_negative),
+            max_nodes_per_document: env.optional("MAX_NODES_PER_DOCUMENT", non_negative),
+            autosave_window: Duration::from_millis(env.parse("AUTOSAVE_COALESCE_MS", 500)),
+            editor_state_max_bytes: env.positive("EDITOR_STATE_MAX_BYTES", 4096),
+            editor_state_ttl: Duration::from_secs(env.positive("EDITOR_STATE_TTL_DAYS", 30) * 24 * 60 * 60),
+
+            export_settle: Duration::from_secs(env.parse("EXPORT_SETTLE_SECS", 5)),
+            export_job_ttl: Duration::from_secs(env.positive("EXPORT_JOB_TTL_SECS", 3600)),
+
+            webhook_timeout: Duration::from_secs(env.positive("WEBHOOK_TIMEOUT_SECS", 10)),
+
+            request_timeout: Duration::from_secs(env.positive("REQUEST_TIMEOUT_SECS", 30)),
+            max_in_flight: env.positive("MAX_IN_FLIGHT_REQUESTS", 256),
+
+            content_encryption_key: env.string("CONTENT_ENCRYPTION_KEY"),
+            content_encryption_key_previous: env.string("CONTENT_ENCRYPTION_KEY_PREVIOUS"),
+
+            // Comma-separated names, e.g. FEATURES=epub_export
+            features: env.string("FEATURES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            pretty_json: env.flag("DEBUG_PRETTY_JSON", false),
+            log_bodies: env.flag("DEBUG_LOG_BODIES", false),
+        };
+
+        if config.content_encryption_key_previous.is_some() && config.content_encryption_key.is_none() {
+            env.problem("CONTENT_ENCRYPTION_KEY_PREVIOUS is set without CONTENT_ENCRYPTION_KEY");
+        }
+
+        if env.problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(env.problems))
+        }
+    }
+}
+
+fn non_negative(value: &str) -> Result<i64, String> {
+    match value.parse::<i64>() {
+        Ok(n) if n >= 0 => Ok(n),
+        Ok(_) => Err("must not be negative".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Reads variables, collecting a problem for each bad one instead of stopping
+/// at the first
+#[derive(Default)]
+struct Env {
+    problems: Vec<String>,
+}
+
+impl Env {
+    fn problem(&mut self, message: impl Into<String>) {
+        self.problems.push(message.into());
+    }
+
+    /// The variable's value, with unset and blank treated alike
+    fn string(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+    }
+
+    fn optional<T>(&mut self, name: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Option<T> {
+        let value = self.string(name)?;
+        match parse(value.trim()) {
+            Ok(parsed) => Some(parsed),
+            Err(reason) => {
+                self.problem(format!("{}={:?}: {}", name, value, reason));
+                None
+            }
+        }
+    }
+
+    fn parse_with<T>(&mut self, name: &str, default: T, parse: impl FnOnce(&str) -> Result<T, String>) -> T {
+        self.optional(name, parse).unwrap_or(default)
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, default: T) -> T
+    where
+        T::Err: fmt::Display,
+    {
+        self.parse_with(name, default, |v| v.parse().map_err(|e: T::Err| e.to_string()))
+    }
+
+    /// A number that must be above zero, such as a timeout or a limit
+    fn positive<T: FromStr + PartialOrd + Default>(&mut self, name: &str, default: T) -> T
+    where
+        T::Err: fmt::Display,
+    {
+        self.parse_with(name, default, |v| match v.parse::<T>() {
+            Ok(n) if n > T::default() => Ok(n),
+            Ok(_) => Err("must be greater than zero".to_string()),
+            Err(e) => Err(e.to_string()),
+        })
+    }
+
+    fn flag(&mut self, name: &str, default: bool) -> bool {
+        self.parse_with(name, default, |v| match v.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err("must be true or false".to_string()),
+        })
+    }
+
+    /// Comma-separated CORS origins
+    fn origins(&mut self, name: &str, default: &str) -> Vec<HeaderValue> {
+        self.list(name, default, |item| item.parse::<HeaderValue>().map_err(|e| e.to_string()))
+    }
+
+    /// Comma-separated items; each bad entry is its own problem
+    fn list<T>(&mut self, name: &str, default: &str, parse: impl Fn(&str) -> Result<T, String>) -> Vec<T> {
+        let value = self.string(name).unwrap_or_else(|| default.to_string());
+        let mut items = Vec::new();
+        for item in value.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            match parse(item) {
+                Ok(parsed) => items.push(parsed),
+                Err(e) => self.problem(format!("{}: {:?} is not valid: {}", name, item, e)),
+            }
+        }
+        if items.is_empty() {
+            self.problem(format!("{} must list at least one entry", name));
+        }
+        items
+    }
+}
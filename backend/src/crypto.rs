@@ -0,0 +1,174 @@
+//! Encryption at rest for the content of documents flagged `encrypted`.
+//!
+//! Each document gets its own AES-256-GCM key, derived with HKDF from the
+//! server secret and the document id. Sealed content is stored in place of
+//! the JSON as `enc:v1:<key id>:<nonce>:<ciphertext>`, where the key id is
+//! derived with HKDF like the keys, so it can't be used to check guesses of
+//! the secret. Rotating CONTENT_ENCRYPTION_KEY while keeping
+//! the old secret as CONTENT_ENCRYPTION_KEY_PREVIOUS lets existing content
+//! still be read; it is re-sealed under the new key on its next save.
+
+use crate::error::ApiError;
+use axum::http::StatusCode;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::hkdf;
+
+const PREFIX: &str = "enc:v1:";
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The document is encrypted but the server has no key configured
+    NotConfigured,
+    /// Sealed under a key this server doesn't have, e.g. rotated out
+    UnknownKey(String),
+    /// The key matched but the content failed authentication: it was
+    /// tampered with, corrupted, or moved from another document
+    Undecryptable,
+}
+
+impl From<CryptoError> for ApiError {
+    fn from(error: CryptoError) -> Self {
+        match error {
+            CryptoError::NotConfigured => ApiError::Message(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Content encryption is not configured on this server".to_string(),
+            ),
+            CryptoError::UnknownKey(key_id) => ApiError::Message(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Content was encrypted with key {}, which is no longer configured", key_id),
+            ),
+            CryptoError::Undecryptable => ApiError::Message(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Content could not be decrypted: it is corrupted or was encrypted for another document".to_string(),
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::NotConfigured => write!(f, "content encryption is not configured"),
+            CryptoError::UnknownKey(key_id) => write!(f, "content was encrypted with unknown key {}", key_id),
+            CryptoError::Undecryptable => write!(f, "content could not be decrypted"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+// HKDF info for the key id; document keys use the 8-byte document id
+const KEY_ID_INFO: &[u8] = b"type_editor content key id";
+const KEY_ID_LEN: usize = 4;
+
+struct KeyIdLen;
+
+impl hkdf::KeyType for KeyIdLen {
+    fn len(&self) -> usize {
+        KEY_ID_LEN
+    }
+}
+
+struct Secret {
+    id: String,
+    prk: hkdf::Prk,
+}
+
+impl Secret {
+    fn new(secret: &str) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"type_editor content encryption").extract(secret.as_bytes());
+
+        let mut id = [0u8; KEY_ID_LEN];
+        prk.expand(&[KEY_ID_INFO], KeyIdLen)
+            .and_then(|okm| okm.fill(&mut id))
+            .expect("key id length is valid for HKDF");
+
+        Self { id: hex(&id), prk }
+    }
+
+    fn document_key(&self, document_id: i64) -> LessSafeKey {
+        let info = document_id.to_be_bytes();
+        let info = [&info[..]];
+        let okm = self.prk.expand(&info, &AES_256_GCM).expect("AES-256 key length is valid for HKDF");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+pub struct ContentCipher {
+    current: Secret,
+    previous: Option<Secret>,
+    rng: SystemRandom,
+}
+
+impl ContentCipher {
+    /// Cipher sealing with `secret`; content sealed with `previous` can
+    /// still be opened during a rotation
+    pub fn new(secret: &str, previous: Option<&str>) -> Self {
+        Self {
+            current: Secret::new(secret),
+            previous: previous.map(Secret::new),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.current.id
+    }
+
+    /// Seal content under the current key
+    pub fn seal(&self, document_id: i64, plaintext: &str) -> Result<String, CryptoError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| CryptoError::Undecryptable)?;
+
+        let mut data = plaintext.as_bytes().to_vec();
+        self.current.document_key(document_id)
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| CryptoError::Undecryptable)?;
+
+        Ok(format!("{}{}:{}:{}", PREFIX, self.current.id, STANDARD.encode(nonce), STANDARD.encode(data)))
+    }
+
+    /// Open content sealed by `seal` under the current or previous key
+    pub fn open(&self, document_id: i64, stored: &str) -> Result<String, CryptoError> {
+        let sealed = stored.strip_prefix(PREFIX).ok_or(CryptoError::Undecryptable)?;
+        let mut parts = sealed.splitn(3, ':');
+        let (Some(key_id), Some(nonce), Some(data)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(CryptoError::Undecryptable);
+        };
+
+        let secret = std::iter::once(&self.current)
+            .chain(self.previous.as_ref())
+            .find(|secret| secret.id == key_id)
+            .ok_or_else(|| CryptoError::UnknownKey(key_id.to_string()))?;
+
+        let nonce: [u8; NONCE_LEN] = STANDARD.decode(nonce).ok()
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or(CryptoError::Undecryptable)?;
+        let mut data = STANDARD.decode(data).map_err(|_| CryptoError::Undecryptable)?;
+
+        let plaintext = secret.document_key(document_id)
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| CryptoError::Undecryptable)?;
+
+        String::from_utf8(plaintext.to_vec()).map_err(|_| CryptoError::Undecryptable)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether stored content_json is sealed rather than plain JSON
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(PREFIX)
+}
+
+/// Plain content_json from what is stored, opening it when it is sealed
+pub fn reveal(cipher: Option<&ContentCipher>, document_id: i64, stored: String) -> Result<String, CryptoError> {
+    if !is_sealed(&stored) {
+        return Ok(stored);
+    }
+    cipher.ok_or(CryptoError::NotConfigured)?.open(document_id, &stored)
+}
@@ -1,22 +1,34 @@
-use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
-use sqlx::Row;
+use crate::config::Config;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use sqlx::{ConnectOptions, Row};
+use std::str::FromStr;
 
-pub async fn init_db() -> anyhow::Result<SqlitePool> {
-    // Get database path from environment variable or use default
-    let db_path = std::env::var("DB_PATH").unwrap_or_else(|_| "../type_editor.db".to_string());
-    
+fn connect_options(config: &Config) -> anyhow::Result<SqliteConnectOptions> {
     // Ensure the path is absolute or relative to the project root
-    let database_url = if db_path.starts_with("sqlite:") {
-        db_path
+    let database_url = if config.db_path.starts_with("sqlite:") {
+        config.db_path.clone()
     } else {
-        format!("sqlite:{}", db_path)
+        format!("sqlite:{}", config.db_path)
     };
-    
-    tracing::info!("Connecting to database: {}", database_url);
+
+    Ok(SqliteConnectOptions::from_str(&database_url)?
+        .log_slow_statements(log::LevelFilter::Warn, config.slow_query_threshold))
+}
+
+pub async fn init_db(config: &Config) -> anyhow::Result<SqlitePool> {
+    let mut options = connect_options(config)?.create_if_missing(true);
+    // Readers on the separate pool only run alongside a writer in WAL mode;
+    // the mode is stored in the database file, so it sticks once set
+    if config.read_pool_size.is_some() {
+        options = options.journal_mode(SqliteJournalMode::Wal);
+    }
+
+    tracing::info!("Connecting to database: {}", config.db_path);
     
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(&database_url)
+        .acquire_timeout(config.db_acquire_timeout)
+        .connect_with(options)
         .await?;
 
     // Create tables
@@ -25,8 +37,8 @@ pub async fn init_db() -> anyhow::Result<SqlitePool> {
         CREATE TABLE IF NOT EXISTS documents (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             title TEXT NOT NULL,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
         )
         "#
     )
@@ -41,11 +53,12 @@ pub async fn init_db() -> anyhow::Result<SqlitePool> {
             parent_id INTEGER,
             node_type TEXT NOT NULL,
             title TEXT NOT NULL,
-            order_index INTEGER NOT NULL,
+            order_index REAL NOT NULL,
             indent_level INTEGER NOT NULL DEFAULT 0,
             image_url TEXT,
-            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            collapsed INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
             FOREIGN KEY (document_id) REFERENCES documents(id) ON DELETE CASCADE,
             FOREIGN KEY (parent_id) REFERENCES nodes(id) ON DELETE CASCADE
         )
@@ -60,13 +73,266 @@ pub async fn init_db() -> anyhow::Result<SqlitePool> {
         .await
         .ok(); // Ignore error if column already exists
 
+    migrate_order_index_to_real(&pool).await?;
+
+    sqlx::query("ALTER TABLE nodes ADD COLUMN collapsed INTEGER NOT NULL DEFAULT 0")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Node listing (and search within it) walks one document in display order
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_nodes_document_order ON nodes(document_id, order_index)")
+        .execute(&pool)
+        .await?;
+
+    // Provenance for the "last edited by" display
+    sqlx::query("ALTER TABLE documents ADD COLUMN last_edited_by TEXT")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE documents ADD COLUMN last_edited_at DATETIME")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // NULL follows the server's DEFAULT_CHILD_SORT
+    sqlx::query("ALTER TABLE documents ADD COLUMN child_sort TEXT")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Editorial workflow: documents start as drafts
+    sqlx::query("ALTER TABLE documents ADD COLUMN status TEXT NOT NULL DEFAULT 'draft'")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_documents_status ON documents(status, updated_at)")
+        .execute(&pool)
+        .await?;
+
+    // Explicit cover for the document grid; NULL falls back to the first figure
+    sqlx::query("ALTER TABLE documents ADD COLUMN cover_image_url TEXT")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Sensitive documents keep their content sealed at rest (see crypto.rs)
+    sqlx::query("ALTER TABLE documents ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Sum of its nodes' content.word_count, kept current on every content write
+    sqlx::query("ALTER TABLE documents ADD COLUMN word_count INTEGER NOT NULL DEFAULT 0")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    sqlx::query("ALTER TABLE nodes ADD COLUMN last_edited_by TEXT")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Free-form per-type fields (figure caption, equation label, ...) as a JSON object
+    sqlx::query("ALTER TABLE nodes ADD COLUMN attributes TEXT")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS content (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             node_id INTEGER NOT NULL UNIQUE,
             content_json TEXT NOT NULL,
-            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            FOREIGN KEY (node_id) REFERENCES nodes(id) ON DELETE CASCADE
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    // Bumped on every write so op-based saves can detect concurrent changes
+    sqlx::query("ALTER TABLE content ADD COLUMN version INTEGER NOT NULL DEFAULT 0")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Counted from the plaintext, so sealed content still has a count
+    sqlx::query("ALTER TABLE content ADD COLUMN word_count INTEGER NOT NULL DEFAULT 0")
+        .execute(&pool)
+        .await
+        .ok(); // Ignore error if column already exists
+
+    // Extra images for multi-panel figures, alongside the node's own image_url
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS node_attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            node_id INTEGER NOT NULL,
+            url TEXT NOT NULL,
+            position INTEGER NOT NULL,
+            caption TEXT,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            FOREIGN KEY (node_id) REFERENCES nodes(id) ON DELETE CASCADE
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_node_attachments_node ON node_attachments(node_id, position)")
+        .execute(&pool)
+        .await?;
+
+    // Review comments on nodes. author is the X-Editor name, as there are no accounts.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS comments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            node_id INTEGER NOT NULL,
+            author TEXT,
+            body TEXT NOT NULL,
+            resolved INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            FOREIGN KEY (node_id) REFERENCES nodes(id) ON DELETE CASCADE
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_comments_node ON comments(node_id, resolved)")
+        .execute(&pool)
+        .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS document_tags (
+            document_id INTEGER NOT NULL,
+            tag TEXT NOT NULL,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            PRIMARY KEY (document_id, tag),
+            FOREIGN KEY (document_id) REFERENCES documents(id) ON DELETE CASCADE
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_document_tags_tag ON document_tags(tag)")
+        .execute(&pool)
+        .await?;
+
+    // Full copies of a document's nodes and content that it can be rolled back to
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS document_snapshots (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            document_id INTEGER NOT NULL,
+            reason TEXT NOT NULL,
+            node_count INTEGER NOT NULL,
+            snapshot_json TEXT NOT NULL,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            FOREIGN KEY (document_id) REFERENCES documents(id) ON DELETE CASCADE
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_document_snapshots_document ON document_snapshots(document_id)")
+        .execute(&pool)
+        .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER,
+            action TEXT NOT NULL,
+            entity_type TEXT NOT NULL,
+            entity_id INTEGER NOT NULL,
+            timestamp DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            before_json TEXT,
+            after_json TEXT
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_audit_log_entity ON audit_log(entity_type, entity_id)")
+        .execute(&pool)
+        .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_audit_log_timestamp ON audit_log(timestamp)")
+        .execute(&pool)
+        .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS uploads (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            filename TEXT NOT NULL,
+            url TEXT NOT NULL UNIQUE,
+            original_name TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            alt_text TEXT,
+            display_name TEXT,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    // Reusable custom CSS for exports
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS stylesheets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            css TEXT NOT NULL,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    // Tombstones let sync clients learn about documents deleted since their last cursor
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS deleted_documents (
+            document_id INTEGER PRIMARY KEY,
+            deleted_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_deleted_documents_deleted_at ON deleted_documents(deleted_at)")
+        .execute(&pool)
+        .await?;
+
+    // Cursor/selection per editor and node, for resuming where they left off.
+    // Without user accounts the editor is the X-Editor name ('' when absent).
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS editor_state (
+            editor TEXT NOT NULL,
+            node_id INTEGER NOT NULL,
+            state TEXT NOT NULL,
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+            PRIMARY KEY (editor, node_id),
             FOREIGN KEY (node_id) REFERENCES nodes(id) ON DELETE CASCADE
         )
         "#
@@ -74,7 +340,319 @@ pub async fn init_db() -> anyhow::Result<SqlitePool> {
     .execute(&pool)
     .await?;
 
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_editor_state_updated_at ON editor_state(updated_at)")
+        .execute(&pool)
+        .await?;
+
+    // Starter content for new nodes created with ?seed_content=true. Defaults
+    // are only inserted when missing, so edited rows survive restarts.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS node_content_templates (
+            node_type TEXT PRIMARY KEY,
+            content_json TEXT NOT NULL,
+            updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    for (node_type, paragraphs) in CONTENT_TEMPLATES {
+        sqlx::query("INSERT OR IGNORE INTO node_content_templates (node_type, content_json) VALUES (?, ?)")
+            .bind(node_type)
+            .bind(paragraph_blocks(paragraphs))
+            .execute(&pool)
+            .await?;
+    }
+
+    // events is a JSON array of event names, e.g. ["document.created"]
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS webhooks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url TEXT NOT NULL,
+            events TEXT NOT NULL,
+            secret TEXT NOT NULL,
+            created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS app_meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )
+        "#
+    )
+    .execute(&pool)
+    .await?;
+
+    migrate_timestamps_to_millis(&pool).await?;
+
+    if config.seed_welcome_doc {
+        seed_welcome_document(&pool).await?;
+    }
+
     tracing::info!("Database initialized successfully");
 
     Ok(pool)
 }
+
+/// The pool GET handlers read through: a separate one of READ_POOL_SIZE
+/// query_only connections when configured, otherwise the primary pool itself.
+/// Open it after init_db, which creates the tables and switches to WAL.
+pub async fn open_read_pool(config: &Config, primary: &SqlitePool) -> anyhow::Result<SqlitePool> {
+    let Some(size) = config.read_pool_size else {
+        return Ok(primary.clone());
+    };
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(size)
+        .acquire_timeout(config.db_acquire_timeout)
+        .connect_with(connect_options(config)?.pragma("query_only", "ON"))
+        .await?;
+
+    tracing::info!("Read pool enabled with {} connections", size);
+    Ok(pool)
+}
+
+/// Rebuild the nodes table when order_index is still declared INTEGER.
+///
+/// SQLite cannot change a column's type in place, and an INTEGER column keeps
+/// integral ranks stored as integers, which would not decode as f64.
+async fn migrate_order_index_to_real(pool: &SqlitePool) -> anyhow::Result<()> {
+    let columns = sqlx::query("PRAGMA table_info(nodes)")
+        .fetch_all(pool)
+        .await?;
+
+    let needs_migration = columns.iter().any(|col| {
+        col.get::<String, _>("name") == "order_index"
+            && col.get::<String, _>("type").eq_ignore_ascii_case("INTEGER")
+    });
+
+    if !needs_migration {
+        return Ok(());
+    }
+
+    tracing::info!("Migrating nodes.order_index from INTEGER to REAL");
+
+    // Foreign keys must be off while the table is swapped, otherwise dropping
+    // the old table would cascade into content. The pragma is per connection.
+    let mut conn = pool.acquire().await?;
+    sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await?;
+
+    let result: anyhow::Result<()> = async {
+        let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE nodes_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                document_id INTEGER NOT NULL,
+                parent_id INTEGER,
+                node_type TEXT NOT NULL,
+                title TEXT NOT NULL,
+                order_index REAL NOT NULL,
+                indent_level INTEGER NOT NULL DEFAULT 0,
+                image_url TEXT,
+                created_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+                updated_at DATETIME DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+                FOREIGN KEY (document_id) REFERENCES documents(id) ON DELETE CASCADE,
+                FOREIGN KEY (parent_id) REFERENCES nodes(id) ON DELETE CASCADE
+            )
+            "#
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO nodes_new (id, document_id, parent_id, node_type, title, order_index, indent_level, image_url, created_at, updated_at)
+             SELECT id, document_id, parent_id, node_type, title, order_index, indent_level, image_url, created_at, updated_at FROM nodes"
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DROP TABLE nodes").execute(&mut *tx).await?;
+        sqlx::query("ALTER TABLE nodes_new RENAME TO nodes").execute(&mut *tx).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+    .await;
+
+    sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await?;
+
+    result
+}
+
+/// Rebuild tables whose timestamp columns still default to
+/// CURRENT_TIMESTAMP, which only has second precision, so that new rows get
+/// milliseconds like the explicit writes do.
+///
+/// SQLite cannot change a column default in place, so each table is copied
+/// into one declared with the new default and swapped in, keeping its
+/// indexes and AUTOINCREMENT counter. Existing values stay as they are; they
+/// still sort correctly against the longer ones.
+async fn migrate_timestamps_to_millis(pool: &SqlitePool) -> anyhow::Result<()> {
+    let tables: Vec<(String, String)> = sqlx::query_as(
+        "SELECT name, sql FROM sqlite_master WHERE type = 'table' AND sql LIKE '%DEFAULT CURRENT_TIMESTAMP%'"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if tables.is_empty() {
+        return Ok(());
+    }
+
+    tracing::info!("Migrating timestamp defaults to millisecond precision in {} tables", tables.len());
+
+    // As in migrate_order_index_to_real, foreign keys stay off while tables are swapped
+    let mut conn = pool.acquire().await?;
+    sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await?;
+
+    let result: anyhow::Result<()> = async {
+        let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+
+        for (name, sql) in &tables {
+            let indexes: Vec<String> = sqlx::query_scalar(
+                "SELECT sql FROM sqlite_master WHERE type = 'index' AND tbl_name = ? AND sql IS NOT NULL"
+            )
+            .bind(name)
+            .fetch_all(&mut *tx)
+            .await?;
+            let sequence: Option<i64> = sqlx::query_scalar("SELECT seq FROM sqlite_sequence WHERE name = ?")
+                .bind(name)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+            let columns = &sql[sql.find('(').ok_or_else(|| anyhow::anyhow!("Unexpected schema for {}", name))?..];
+            let columns = columns.replace("DEFAULT CURRENT_TIMESTAMP", "DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))");
+
+            sqlx::query(&format!("CREATE TABLE \"{}_new\" {}", name, columns)).execute(&mut *tx).await?;
+            sqlx::query(&format!("INSERT INTO \"{0}_new\" SELECT * FROM \"{0}\"", name)).execute(&mut *tx).await?;
+            sqlx::query(&format!("DROP TABLE \"{}\"", name)).execute(&mut *tx).await?;
+            sqlx::query(&format!("ALTER TABLE \"{0}_new\" RENAME TO \"{0}\"", name)).execute(&mut *tx).await?;
+
+            for index in &indexes {
+                sqlx::query(index).execute(&mut *tx).await?;
+            }
+            // The copy only carries the counter up to the highest id still present
+            if let Some(sequence) = sequence {
+                sqlx::query("DELETE FROM sqlite_sequence WHERE name = ?")
+                    .bind(name)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("INSERT INTO sqlite_sequence (name, seq) VALUES (?, ?)")
+                    .bind(name)
+                    .bind(sequence)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+    .await;
+
+    sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await?;
+
+    result
+}
+
+// Starter content shown to new users: (node_type, title, paragraphs)
+const WELCOME_NODES: &[(&str, &str, &[&str])] = &[
+    ("section", "Getting started", &[
+        "Welcome to the editor! Documents are organised as an outline of sections, equations and figures.",
+        "Use the sidebar to add, rename, reorder and nest nodes. Everything you type is saved automatically.",
+    ]),
+    ("section", "Equations and figures", &[
+        "Add an equation node to write LaTeX, or upload an image to create a figure with a caption.",
+    ]),
+    ("equation", "Example equation", &["E = mc^2"]),
+];
+
+// Default node content templates: (node_type, paragraphs)
+const CONTENT_TEMPLATES: &[(&str, &[&str])] = &[
+    ("section", &["Summarise what this section covers, then expand on each point."]),
+    ("equation", &["x = \\frac{-b \\pm \\sqrt{b^2 - 4ac}}{2a}"]),
+    ("figure", &["Describe what the figure shows and what the reader should notice."]),
+];
+
+fn paragraph_blocks(paragraphs: &[&str]) -> String {
+    let blocks: Vec<serde_json::Value> = paragraphs
+        .iter()
+        .map(|text| serde_json::json!({
+            "type": "paragraph",
+            "props": {},
+            "content": [{ "type": "text", "text": text, "styles": {} }],
+            "children": []
+        }))
+        .collect();
+    serde_json::Value::Array(blocks).to_string()
+}
+
+/// Insert a welcome document on a fresh database.
+///
+/// The seed is recorded in app_meta so it only ever happens once, even if the
+/// user later deletes every document.
+async fn seed_welcome_document(pool: &SqlitePool) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+
+    let already_seeded: Option<String> = sqlx::query_scalar("SELECT value FROM app_meta WHERE key = 'welcome_seeded'")
+        .fetch_optional(&mut *tx)
+        .await?;
+    if already_seeded.is_some() {
+        return Ok(());
+    }
+
+    let document_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM documents")
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if document_count == 0 {
+        let document_id = sqlx::query("INSERT INTO documents (title) VALUES ('Welcome')")
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+
+        for (index, (node_type, title, paragraphs)) in WELCOME_NODES.iter().enumerate() {
+            let node_id = sqlx::query(
+                "INSERT INTO nodes (document_id, parent_id, node_type, title, order_index, indent_level)
+                 VALUES (?, NULL, ?, ?, ?, 0)"
+            )
+            .bind(document_id)
+            .bind(node_type)
+            .bind(title)
+            .bind(index as f64)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+
+            let content_json = paragraph_blocks(paragraphs);
+            sqlx::query("INSERT INTO content (node_id, content_json, word_count) VALUES (?, ?, ?)")
+                .bind(node_id)
+                .bind(&content_json)
+                .bind(crate::text::word_count(&crate::text::plain_text(&content_json)) as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        crate::handlers::refresh_word_count(&mut tx, document_id).await?;
+
+        tracing::info!("Seeded welcome document {}", document_id);
+    }
+
+    // Existing installs are marked too, so a later empty table is never reseeded
+    sqlx::query("INSERT INTO app_meta (key, value) VALUES ('welcome_seeded', strftime('%Y-%m-%d %H:%M:%f', 'now'))")
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
@@ -0,0 +1,356 @@
+//! Structural comparison of two documents' node trees, for reviewing a
+//! revision against a duplicate of the same document.
+//!
+//! Nodes carry no identifier that survives duplication, so they are paired
+//! heuristically, in document order: first by type and title path (the
+//! titles from the root down), then by type and title alone. Titles compare
+//! case- and whitespace-insensitively.
+//!
+//! `word_diff` compares two versions of one node's text.
+
+use crate::models::Node;
+use crate::tree;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeRef {
+    pub id: i64,
+    pub node_type: String,
+    pub title: String,
+    pub parent_id: Option<i64>,
+    /// Titles from the root down to this node
+    pub path: Vec<String>,
+}
+
+/// A node paired across the two documents
+#[derive(Debug, Clone, Serialize)]
+pub struct NodePair {
+    pub left: NodeRef,
+    pub right: NodeRef,
+    /// Any of parent, position, title, content, image_url, attributes
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub moved: usize,
+    pub modified: usize,
+    pub unchanged: usize,
+}
+
+/// Every left node is removed or in a pair and every right node is added or
+/// in a pair. A pair that moved is listed under `moved` even if it was also
+/// modified; its `changes` say so.
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentDiff {
+    pub summary: DiffSummary,
+    pub added: Vec<NodeRef>,
+    pub removed: Vec<NodeRef>,
+    pub moved: Vec<NodePair>,
+    pub modified: Vec<NodePair>,
+    pub unchanged: Vec<NodePair>,
+}
+
+type PairKey = (String, Vec<String>);
+
+/// A document's nodes in display order, with their repaired parents and title paths
+struct Outline<'a> {
+    nodes: Vec<&'a Node>,
+    parents: HashMap<i64, Option<i64>>,
+    paths: HashMap<i64, Vec<String>>,
+}
+
+impl<'a> Outline<'a> {
+    fn new(nodes: &'a [Node]) -> Self {
+        // Orphans and cycles are repaired the same way the tree check does
+        let (_, placements) = tree::analyze(nodes);
+        let by_id: HashMap<i64, &Node> = nodes.iter().map(|n| (n.id, n)).collect();
+
+        let mut groups: HashMap<Option<i64>, Vec<&tree::Placement>> = HashMap::new();
+        for placement in &placements {
+            groups.entry(placement.parent_id).or_default().push(placement);
+        }
+        for group in groups.values_mut() {
+            group.sort_by(|a, b| a.order_index.total_cmp(&b.order_index));
+        }
+
+        let mut outline = Outline { nodes: Vec::new(), parents: HashMap::new(), paths: HashMap::new() };
+        let children = |parent: Option<i64>| groups.get(&parent).into_iter().flatten().rev();
+
+        // Depth first, pushed in reverse so the first child is walked first
+        let mut pending: Vec<(&tree::Placement, Vec<String>)> = children(None).map(|p| (*p, Vec::new())).collect();
+        while let Some((placement, mut path)) = pending.pop() {
+            let node = by_id[&placement.id];
+            path.push(node.title.clone());
+            pending.extend(children(Some(node.id)).map(|child| (*child, path.clone())));
+
+            outline.nodes.push(node);
+            outline.parents.insert(node.id, placement.parent_id);
+            outline.paths.insert(node.id, path);
+        }
+
+        outline
+    }
+
+    fn node_ref(&self, node: &Node) -> NodeRef {
+        NodeRef {
+            id: node.id,
+            node_type: node.node_type.clone(),
+            title: node.title.clone(),
+            parent_id: self.parents[&node.id],
+            path: self.paths[&node.id].clone(),
+        }
+    }
+
+    /// What a node is paired on: its type and normalized title path, or
+    /// just its own title
+    fn pair_key(&self, node: &Node, by_path: bool) -> PairKey {
+        let titles = match by_path {
+            true => self.paths[&node.id].iter().map(|t| normalize(t)).collect(),
+            false => vec![normalize(&node.title)],
+        };
+        (node.node_type.clone(), titles)
+    }
+}
+
+fn normalize(title: &str) -> String {
+    title.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Compare two documents' nodes; `content` maps node ids of either
+/// document to plain (decrypted) content_json
+pub fn diff(left: &[Node], right: &[Node], content: &HashMap<i64, String>) -> DocumentDiff {
+    let left = Outline::new(left);
+    let right = Outline::new(right);
+
+    // left id -> right id, filled by the two passes
+    let mut pairs: HashMap<i64, i64> = HashMap::new();
+    let mut paired_right: HashSet<i64> = HashSet::new();
+
+    for by_path in [true, false] {
+        let mut candidates: HashMap<PairKey, VecDeque<i64>> = HashMap::new();
+        for node in right.nodes.iter().filter(|n| !paired_right.contains(&n.id)) {
+            candidates.entry(right.pair_key(node, by_path)).or_default().push_back(node.id);
+        }
+        let unpaired: Vec<&Node> = left.nodes.iter().copied().filter(|n| !pairs.contains_key(&n.id)).collect();
+        for node in unpaired {
+            if let Some(right_id) = candidates.get_mut(&left.pair_key(node, by_path)).and_then(VecDeque::pop_front) {
+                pairs.insert(node.id, right_id);
+                paired_right.insert(right_id);
+            }
+        }
+    }
+
+    let right_by_id: HashMap<i64, &Node> = right.nodes.iter().map(|n| (n.id, *n)).collect();
+    let same_parent = |left_id: i64, right_id: i64| match (left.parents[&left_id], right.parents[&right_id]) {
+        (None, None) => true,
+        (Some(l), Some(r)) => pairs.get(&l) == Some(&r),
+        _ => false,
+    };
+
+    // Among pairs that kept their parent, the ones outside the longest run
+    // still in order are the ones that changed position
+    let mut sibling_runs: HashMap<Option<i64>, Vec<(i64, usize)>> = HashMap::new();
+    let right_rank: HashMap<i64, usize> = right.nodes.iter().enumerate().map(|(i, n)| (n.id, i)).collect();
+    for node in &left.nodes {
+        if let Some(&right_id) = pairs.get(&node.id).filter(|&&r| same_parent(node.id, r)) {
+            sibling_runs.entry(right.parents[&right_id]).or_default().push((node.id, right_rank[&right_id]));
+        }
+    }
+    let mut repositioned: HashSet<i64> = HashSet::new();
+    for run in sibling_runs.values() {
+        let in_order: HashSet<usize> = longest_increasing(&run.iter().map(|&(_, rank)| rank).collect::<Vec<_>>())
+            .into_iter()
+            .collect();
+        repositioned.extend(run.iter().enumerate().filter(|(i, _)| !in_order.contains(i)).map(|(_, &(id, _))| id));
+    }
+
+    let mut result = DocumentDiff {
+        summary: DiffSummary { added: 0, removed: 0, moved: 0, modified: 0, unchanged: 0 },
+        added: Vec::new(),
+        removed: Vec::new(),
+        moved: Vec::new(),
+        modified: Vec::new(),
+        unchanged: Vec::new(),
+    };
+
+    for node in &left.nodes {
+        let Some(&right_id) = pairs.get(&node.id) else {
+            result.removed.push(left.node_ref(node));
+            continue;
+        };
+        let other = right_by_id[&right_id];
+
+        let mut changes = Vec::new();
+        if !same_parent(node.id, right_id) {
+            changes.push("parent");
+        } else if repositioned.contains(&node.id) {
+            changes.push("position");
+        }
+        if node.title != other.title {
+            changes.push("title");
+        }
+        if !same_content(content.get(&node.id), content.get(&right_id)) {
+            changes.push("content");
+        }
+        if node.image_url != other.image_url {
+            changes.push("image_url");
+        }
+        if node.attributes.as_ref().map(|a| &a.0) != other.attributes.as_ref().map(|a| &a.0) {
+            changes.push("attributes");
+        }
+
+        let pair = NodePair { left: left.node_ref(node), right: right.node_ref(other), changes };
+        if pair.changes.iter().any(|c| matches!(*c, "parent" | "position")) {
+            result.moved.push(pair);
+        } else if pair.changes.is_empty() {
+            result.unchanged.push(pair);
+        } else {
+            result.modified.push(pair);
+        }
+    }
+
+    result.added = right.nodes.iter()
+        .filter(|n| !paired_right.contains(&n.id))
+        .map(|n| right.node_ref(n))
+        .collect();
+
+    result.summary = DiffSummary {
+        added: result.added.len(),
+        removed: result.removed.len(),
+        moved: result.moved.len(),
+        modified: result.modified.len(),
+        unchanged: result.unchanged.len(),
+    };
+    result
+}
+
+/// Content is the same if it parses to the same JSON, so key order and
+/// whitespace don't count; no content and an empty array are the same too
+fn same_content(left: Option<&String>, right: Option<&String>) -> bool {
+    let parse = |content: Option<&String>| match content {
+        None => serde_json::Value::Array(Vec::new()),
+        Some(json) => serde_json::from_str(json).unwrap_or_else(|_| serde_json::Value::String(json.clone())),
+    };
+    parse(left) == parse(right)
+}
+
+/// Indexes of one longest strictly increasing subsequence
+fn longest_increasing(values: &[usize]) -> Vec<usize> {
+    // tails[k]: index of the smallest tail of an increasing run of length k + 1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; values.len()];
+    for (i, &value) in values.iter().enumerate() {
+        let k = tails.partition_point(|&t| values[t] < value);
+        previous[i] = k.checked_sub(1).map(|k| tails[k]);
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+
+    let mut run = Vec::with_capacity(tails.len());
+    let mut at = tails.last().copied();
+    while let Some(i) = at {
+        run.push(i);
+        at = previous[i];
+    }
+    run.reverse();
+    run
+}
+
+/// A run of text that both sides share, or that only one side has
+#[derive(Debug, Clone, Serialize)]
+pub struct WordChange {
+    /// equal, insert (only in the new text) or delete (only in the old)
+    pub op: &'static str,
+    pub text: String,
+}
+
+// Above this many comparisons the middle of the texts is shown as replaced
+// wholesale rather than aligned word by word
+const MAX_WORD_DIFF_CELLS: usize = 4_000_000;
+
+/// Word-level diff of two texts. Whitespace runs are tokens too, so the
+/// `equal` and `delete` runs concatenate back to `old` and the `equal` and
+/// `insert` runs to `new`.
+pub fn word_diff(old: &str, new: &str) -> Vec<WordChange> {
+    let old = tokens(old);
+    let new = tokens(new);
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut changes = Vec::new();
+    let mut push = |op: &'static str, token: &str| match changes.last_mut() {
+        Some(WordChange { op: last, text }) if *last == op => text.push_str(token),
+        _ => changes.push(WordChange { op, text: token.to_string() }),
+    };
+
+    old[..prefix].iter().for_each(|t| push("equal", t));
+    if old_middle.len().saturating_mul(new_middle.len()) > MAX_WORD_DIFF_CELLS {
+        old_middle.iter().for_each(|t| push("delete", t));
+        new_middle.iter().for_each(|t| push("insert", t));
+    } else {
+        // Longest common subsequence table, filled from the end
+        let width = new_middle.len() + 1;
+        let mut common = vec![0u32; (old_middle.len() + 1) * width];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                common[i * width + j] = if old_middle[i] == new_middle[j] {
+                    common[(i + 1) * width + j + 1] + 1
+                } else {
+                    common[(i + 1) * width + j].max(common[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < old_middle.len() && j < new_middle.len() {
+            if old_middle[i] == new_middle[j] {
+                push("equal", old_middle[i]);
+                i += 1;
+                j += 1;
+            } else if common[(i + 1) * width + j] >= common[i * width + j + 1] {
+                push("delete", old_middle[i]);
+                i += 1;
+            } else {
+                push("insert", new_middle[j]);
+                j += 1;
+            }
+        }
+        old_middle[i..].iter().for_each(|t| push("delete", t));
+        new_middle[j..].iter().for_each(|t| push("insert", t));
+    }
+    old[old.len() - suffix..].iter().for_each(|t| push("equal", t));
+
+    changes
+}
+
+/// Alternating runs of whitespace and non-whitespace
+fn tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_space = None;
+    for (i, c) in text.char_indices() {
+        let space = c.is_whitespace();
+        if in_space.is_some_and(|s| s != space) {
+            tokens.push(&text[start..i]);
+            start = i;
+        }
+        in_space = Some(space);
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
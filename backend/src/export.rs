@@ -0,0 +1,1070 @@
+use crate::crypto::ContentCipher;
+use crate::models::{Attachment, Document, EquationExport, FigurePlacement, Node, NodeTree, OutlineEntry};
+use crate::storage::{valid_key, Storage};
+use crate::text::{block_text, blocks_text};
+use axum::body::Bytes;
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct ExportJob {
+    pub document_id: i64,
+    pub template: String,
+    pub bookmarks: bool,
+    pub figure_placement: FigurePlacement,
+    pub status: JobStatus,
+    pub progress: u8, // percent
+    pub error: Option<String>,
+    pub file_path: Option<PathBuf>,
+    finished_at: Option<Instant>,
+    abort: Option<tokio::task::AbortHandle>,
+}
+
+/// How a PDF export job renders its document
+pub struct PdfOptions {
+    pub template: String,
+    pub bookmarks: bool,
+    /// Already sanitized
+    pub css: Option<String>,
+    pub figure_placement: FigurePlacement,
+}
+
+/// In-memory registry of export jobs, shared through AppState
+#[derive(Clone, Default)]
+pub struct ExportJobs {
+    jobs: Arc<Mutex<HashMap<String, ExportJob>>>,
+}
+
+static JOB_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn generate_job_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:x}{:04x}", nanos, JOB_COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff)
+}
+
+fn export_dir() -> PathBuf {
+    std::env::temp_dir().join("type_editor_exports")
+}
+
+impl ExportJobs {
+    /// Register a job and start rendering it on a background task
+    pub fn start(
+        &self,
+        db: SqlitePool,
+        cipher: Option<Arc<ContentCipher>>,
+        document_id: i64,
+        options: PdfOptions,
+    ) -> String {
+        let id = generate_job_id();
+
+        self.jobs.lock().unwrap().insert(id.clone(), ExportJob {
+            document_id,
+            template: options.template.clone(),
+            bookmarks: options.bookmarks,
+            figure_placement: options.figure_placement,
+            status: JobStatus::Pending,
+            progress: 0,
+            error: None,
+            file_path: None,
+            finished_at: None,
+            abort: None,
+        });
+
+        let jobs = self.clone();
+        let job_id = id.clone();
+        let handle = tokio::spawn(async move {
+            jobs.update(&job_id, |job| job.status = JobStatus::Running);
+
+            let result = run_export(&db, cipher.as_deref(), &jobs, &job_id, document_id, &options).await;
+
+            jobs.update(&job_id, |job| {
+                match result {
+                    Ok(path) => {
+                        job.status = JobStatus::Done;
+                        job.progress = 100;
+                        job.file_path = Some(path);
+                    }
+                    Err(e) => {
+                        tracing::warn!("Export job {} failed: {}", job_id, e);
+                        job.status = JobStatus::Failed;
+                        job.error = Some(e.to_string());
+                    }
+                }
+                job.finished_at = Some(Instant::now());
+                job.abort = None;
+            });
+        });
+
+        self.update(&id, |job| {
+            if job.finished_at.is_none() {
+                job.abort = Some(handle.abort_handle());
+            }
+        });
+
+        id
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut ExportJob)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job);
+        }
+    }
+
+    /// Snapshot a job's status as JSON, or None if the id is unknown
+    pub fn status(&self, id: &str) -> Option<serde_json::Value> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id)?;
+
+        Some(serde_json::json!({
+            "id": id,
+            "document_id": job.document_id,
+            "template": job.template,
+            "bookmarks": job.bookmarks,
+            "figure_placement": job.figure_placement,
+            "status": job.status,
+            "progress": job.progress,
+            "error": job.error,
+            "download_url": (job.status == JobStatus::Done)
+                .then(|| format!("/api/export/jobs/{}/download", id)),
+        }))
+    }
+
+    /// Path of the rendered file for a finished job
+    pub fn output_path(&self, id: &str) -> Option<PathBuf> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(id)
+            .filter(|job| job.status == JobStatus::Done)
+            .and_then(|job| job.file_path.clone())
+    }
+
+    /// Cancel a job if it is still running and forget it. Returns false if unknown.
+    pub fn cancel(&self, id: &str) -> bool {
+        let Some(job) = self.jobs.lock().unwrap().remove(id) else {
+            return false;
+        };
+
+        if let Some(abort) = job.abort {
+            abort.abort();
+        }
+        if let Some(path) = job.file_path {
+            let _ = std::fs::remove_file(path);
+        }
+
+        true
+    }
+
+    /// Drop finished jobs (and their files) older than the TTL
+    pub fn prune(&self, ttl: Duration) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|id, job| {
+            let expired = job.finished_at.is_some_and(|at| at.elapsed() > ttl);
+            if expired {
+                tracing::debug!("Pruning export job {}", id);
+                if let Some(path) = &job.file_path {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            !expired
+        });
+    }
+}
+
+async fn run_export(
+    db: &SqlitePool,
+    cipher: Option<&ContentCipher>,
+    jobs: &ExportJobs,
+    job_id: &str,
+    document_id: i64,
+    options: &PdfOptions,
+) -> anyhow::Result<PathBuf> {
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(document_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Document {} not found", document_id))?;
+
+    let nodes = sqlx::query_as::<_, Node>(
+        "SELECT nodes.*, uploads.alt_text AS image_alt_text, uploads.display_name AS image_display_name
+         FROM nodes LEFT JOIN uploads ON uploads.url = nodes.image_url
+         WHERE nodes.document_id = ? ORDER BY nodes.order_index"
+    )
+    .bind(document_id)
+    .fetch_all(db)
+    .await?;
+
+    let attachments = crate::handlers::document_attachments(db, document_id).await?;
+
+    // Outline level of each section, so headings can carry bookmark metadata
+    let mut bookmark_levels = HashMap::new();
+    if options.bookmarks {
+        fn collect(entries: &[OutlineEntry], levels: &mut HashMap<i64, usize>) {
+            for entry in entries {
+                levels.insert(entry.node_id, entry.level);
+                collect(&entry.children, levels);
+            }
+        }
+        collect(&section_outline(crate::handlers::build_tree(nodes.clone())), &mut bookmark_levels);
+    }
+
+    let (figures, flow): (Vec<&Node>, Vec<&Node>) = nodes.iter()
+        .partition(|node| options.figure_placement.collects() && node.node_type == "figure");
+
+    let mut body = String::new();
+    for (i, node) in flow.iter().enumerate() {
+        let content: Option<String> = sqlx::query_scalar("SELECT content_json FROM content WHERE node_id = ?")
+            .bind(node.id)
+            .fetch_optional(db)
+            .await?;
+        let content = content.map(|stored| crate::crypto::reveal(cipher, document_id, stored)).transpose()?;
+
+        render_node(
+            &mut body,
+            node,
+            content.as_deref(),
+            attachments.get(&node.id).map(Vec::as_slice).unwrap_or_default(),
+            bookmark_levels.get(&node.id).copied(),
+        );
+
+        let progress = ((i + 1) * 90 / flow.len()) as u8;
+        jobs.update(job_id, |job| job.progress = progress);
+    }
+    render_figure_list(&mut body, &figures, &attachments, options.figure_placement);
+
+    // Custom CSS was sanitized when the export was requested; the guard
+    // comes after it so it can't collapse the page flow
+    let style = options.css.as_deref()
+        .map(|css| format!("<style>\n{}\n{}</style>\n", css, PAGINATION_GUARD_CSS))
+        .unwrap_or_default();
+
+    let html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n{}</head>\n<body class=\"template-{}\">\n<h1 class=\"document-title\">{}</h1>\n{}</body>\n</html>\n",
+        escape_html(&document.title),
+        style,
+        escape_html(&options.template),
+        escape_html(&document.title),
+        body,
+    );
+
+    // TODO: hand the rendered HTML to headless_chrome for PDF conversion,
+    // with generate_document_outline set from `options.bookmarks` so Chrome builds the
+    // outline (and its page numbers) from the marked-up section headings
+    let dir = export_dir();
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("{}.html", job_id));
+    tokio::fs::write(&path, html).await?;
+
+    Ok(path)
+}
+
+/// Rough page metrics of an export template, measured in lines of body text
+pub struct PageGeometry {
+    pub lines_per_page: f64,
+    pub words_per_line: f64,
+    /// Space taken by the title block on the first page
+    pub title_lines: f64,
+    pub heading_lines: f64,
+    pub figure_lines: f64,
+    pub equation_lines: f64,
+}
+
+/// Geometry of the paper, report and resume templates
+pub fn page_geometry(template: &str) -> Option<PageGeometry> {
+    match template {
+        // Single column, 11pt, A4 with normal margins
+        "paper" => Some(PageGeometry {
+            lines_per_page: 46.0,
+            words_per_line: 12.0,
+            title_lines: 8.0,
+            heading_lines: 3.0,
+            figure_lines: 16.0,
+            equation_lines: 3.0,
+        }),
+        // Wider margins and roomier spacing; figures get more of the page
+        "report" => Some(PageGeometry {
+            lines_per_page: 40.0,
+            words_per_line: 11.0,
+            title_lines: 12.0,
+            heading_lines: 4.0,
+            figure_lines: 20.0,
+            equation_lines: 3.0,
+        }),
+        // Dense and small
+        "resume" => Some(PageGeometry {
+            lines_per_page: 56.0,
+            words_per_line: 13.0,
+            title_lines: 5.0,
+            heading_lines: 2.0,
+            figure_lines: 10.0,
+            equation_lines: 2.0,
+        }),
+        _ => None,
+    }
+}
+
+/// Approximate page count from what a document contains, without rendering
+/// it. Paragraph breaks and page-break waste are ignored, so it leans short
+/// for documents of many small nodes.
+pub fn estimate_pages(geometry: &PageGeometry, words: usize, headings: usize, figures: usize, equations: usize) -> u64 {
+    let lines = geometry.title_lines
+        + words as f64 / geometry.words_per_line
+        + headings as f64 * geometry.heading_lines
+        + figures as f64 * geometry.figure_lines
+        + equations as f64 * geometry.equation_lines;
+
+    (lines / geometry.lines_per_page).ceil().max(1.0) as u64
+}
+
+// Largest custom stylesheet accepted for an export, in bytes
+pub const MAX_CUSTOM_CSS_LEN: usize = 64 * 1024;
+
+// Appended after custom CSS: whatever it does to the page elements, the
+// document still flows from page to page
+const PAGINATION_GUARD_CSS: &str = "html, body { display: block !important; position: static !important; \
+height: auto !important; max-height: none !important; overflow: visible !important; }\n";
+
+/// Check user-supplied export CSS. It may not break out of its `<style>`
+/// element, pull in other stylesheets or scripts, or load anything but
+/// uploads, since the exporter renders it server-side.
+pub fn sanitize_css(css: &str) -> Result<String, String> {
+    if css.len() > MAX_CUSTOM_CSS_LEN {
+        return Err(format!("must be at most {} bytes", MAX_CUSTOM_CSS_LEN));
+    }
+    if css.contains('<') {
+        return Err("must not contain '<'".to_string());
+    }
+    // Escapes could spell the checks below in a way they don't recognise
+    if css.contains('\\') {
+        return Err("must not contain backslash escapes".to_string());
+    }
+
+    let lower = css.to_lowercase();
+    for forbidden in ["@import", "expression(", "javascript:", "behavior:", "-moz-binding"] {
+        if lower.contains(forbidden) {
+            return Err(format!("must not use {}", forbidden));
+        }
+    }
+
+    for (i, _) in lower.match_indices("url(") {
+        let target = lower[i + 4..].trim_start().trim_start_matches(['"', '\'']);
+        if !target.starts_with("/uploads/") {
+            return Err("url() may only reference /uploads/".to_string());
+        }
+    }
+
+    Ok(css.trim().to_string())
+}
+
+/// Write a zip backup of every document to a temporary file: one JSON file
+/// per document (metadata, node tree and content) plus the uploads its
+/// figures reference under assets/. Entries are added one at a time, so
+/// memory is bounded by the largest document rather than the whole archive.
+pub async fn write_backup_archive(db: &SqlitePool, storage: &dyn Storage) -> anyhow::Result<PathBuf> {
+    let dir = export_dir();
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("backup-{}.zip", generate_job_id()));
+
+    // Compression and file writes block, so the zip lives on a blocking
+    // thread and is fed entries as they're read; the small queue keeps the
+    // reader from running far ahead of it
+    let (entries, mut received) = tokio::sync::mpsc::channel::<(String, Bytes)>(4);
+    let file = std::fs::File::create(&path)?;
+    let writer = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        while let Some((name, data)) = received.blocking_recv() {
+            zip.start_file(name, options)?;
+            zip.write_all(&data)?;
+        }
+        zip.finish()?.flush()?;
+        Ok(())
+    });
+
+    let read = read_backup_entries(db, storage, &entries).await;
+    drop(entries);
+    // A write error closes the queue, so it explains a failed read too
+    writer.await??;
+    read?;
+
+    Ok(path)
+}
+
+async fn read_backup_entries(
+    db: &SqlitePool,
+    storage: &dyn Storage,
+    entries: &tokio::sync::mpsc::Sender<(String, Bytes)>,
+) -> anyhow::Result<()> {
+    let document_ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM documents ORDER BY id")
+        .fetch_all(db)
+        .await?;
+
+    let mut assets = HashSet::new();
+    for document_id in document_ids {
+        let Some(document) = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+            .bind(document_id)
+            .fetch_optional(db)
+            .await?
+        else {
+            continue; // deleted while the backup was running
+        };
+
+        let mut nodes = sqlx::query_as::<_, Node>(
+            "SELECT nodes.*, uploads.alt_text AS image_alt_text, uploads.display_name AS image_display_name
+             FROM nodes LEFT JOIN uploads ON uploads.url = nodes.image_url
+             WHERE nodes.document_id = ? ORDER BY nodes.order_index"
+        )
+        .bind(document_id)
+        .fetch_all(db)
+        .await?;
+
+        let mut attachments = crate::handlers::document_attachments(db, document_id).await?;
+        for node in &mut nodes {
+            node.attachments = attachments.remove(&node.id).unwrap_or_default();
+        }
+
+        let content: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT content.node_id, content.content_json FROM content
+             JOIN nodes ON nodes.id = content.node_id
+             WHERE nodes.document_id = ?"
+        )
+        .bind(document_id)
+        .fetch_all(db)
+        .await?;
+
+        let content: HashMap<String, serde_json::Value> = content.into_iter()
+            .map(|(node_id, json)| {
+                let value = serde_json::from_str(&json).unwrap_or(serde_json::Value::String(json));
+                (node_id.to_string(), value)
+            })
+            .collect();
+
+        let mut referenced = Vec::new();
+        let urls = nodes.iter().flat_map(|node| {
+            node.image_url.iter().chain(node.attachments.iter().map(|a| &a.url))
+        });
+        for url in urls {
+            // Upload URLs end in their storage key, whichever backend issued them
+            let Some(name) = url.rsplit('/').next() else {
+                continue;
+            };
+            if valid_key(name) && assets.insert(name.to_string()) {
+                referenced.push(name.to_string());
+            }
+        }
+
+        let entry = serde_json::json!({
+            "document": document,
+            "nodes": crate::handlers::build_tree(nodes),
+            "content": content,
+        });
+
+        let name = format!("documents/{}-{}.json", document.id, slugify(&document.title));
+        entries.send((name, serde_json::to_vec_pretty(&entry)?.into())).await?;
+
+        for name in referenced {
+            let Some(image) = storage.get(&name).await? else {
+                tracing::warn!("Backup skipping missing upload {}", name);
+                continue;
+            };
+            entries.send((format!("assets/{}", name), image)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lowercase ASCII title fragment safe to use in a file name
+pub fn slugify(title: &str) -> String {
+    let slug: String = title.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let slug: Vec<&str> = slug.split('-').filter(|part| !part.is_empty()).collect();
+
+    if slug.is_empty() {
+        "untitled".to_string()
+    } else {
+        slug.join("-").chars().take(60).collect()
+    }
+}
+
+/// Table of contents built from the section nodes of a document tree.
+/// Sections nested under other node types attach to the nearest section
+/// above them.
+pub fn section_outline(tree: Vec<NodeTree>) -> Vec<OutlineEntry> {
+    fn walk(trees: Vec<NodeTree>, level: usize, out: &mut Vec<OutlineEntry>) {
+        for tree in trees {
+            if tree.node.node_type == "section" {
+                let mut children = Vec::new();
+                walk(tree.children, level + 1, &mut children);
+                out.push(OutlineEntry {
+                    node_id: tree.node.id,
+                    anchor: format!("node-{}", tree.node.id),
+                    title: tree.node.title,
+                    level,
+                    children,
+                });
+            } else {
+                walk(tree.children, level, out);
+            }
+        }
+    }
+
+    let mut outline = Vec::new();
+    walk(tree, 1, &mut outline);
+    outline
+}
+
+/// Take the figures out of a tree, in outline order, when `placement`
+/// collects them at the end. A figure's own children move up into its place.
+pub fn take_figures(tree: &mut Vec<NodeTree>, placement: FigurePlacement) -> Vec<Node> {
+    fn take(trees: Vec<NodeTree>, figures: &mut Vec<Node>) -> Vec<NodeTree> {
+        let mut kept = Vec::new();
+        for mut tree in trees {
+            let children = take(std::mem::take(&mut tree.children), figures);
+            if tree.node.node_type == "figure" {
+                figures.push(tree.node);
+                kept.extend(children);
+            } else {
+                tree.children = children;
+                kept.push(tree);
+            }
+        }
+        kept
+    }
+
+    let mut figures = Vec::new();
+    if placement.collects() {
+        *tree = take(std::mem::take(tree), &mut figures);
+    }
+    figures
+}
+
+/// Collected figures as a closing List of Figures section; nothing when
+/// there are none
+fn render_figure_list(
+    out: &mut String,
+    figures: &[&Node],
+    attachments: &HashMap<i64, Vec<Attachment>>,
+    placement: FigurePlacement,
+) {
+    if figures.is_empty() {
+        return;
+    }
+
+    out.push_str("<section class=\"list-of-figures\">\n<h2 id=\"list-of-figures\">List of Figures</h2>\n");
+    let separate = placement == FigurePlacement::SeparateList;
+    if separate {
+        out.push_str("<ol class=\"figure-captions\">\n");
+        for figure in figures {
+            let caption = figure.image_display_name.as_deref().unwrap_or(&figure.title);
+            out.push_str(&format!("<li><a href=\"#node-{}\">{}</a></li>\n", figure.id, escape_html(caption)));
+        }
+        out.push_str("</ol>\n");
+    }
+    for figure in figures {
+        out.push_str(&format!(
+            "<div id=\"node-{}\" class=\"collected-figure\"{}>\n",
+            figure.id,
+            if separate { " style=\"break-before: page\"" } else { "" },
+        ));
+        render_node(out, figure, None, attachments.get(&figure.id).map(Vec::as_slice).unwrap_or_default(), None);
+        out.push_str("</div>\n");
+    }
+    out.push_str("</section>\n");
+}
+
+fn render_node(
+    out: &mut String,
+    node: &Node,
+    content_json: Option<&str>,
+    attachments: &[Attachment],
+    bookmark_level: Option<usize>,
+) {
+    let blocks: Vec<serde_json::Value> = content_json
+        .and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default();
+
+    match node.node_type.as_str() {
+        "figure" => {
+            out.push_str("<figure>\n");
+            if let Some(url) = &node.image_url {
+                let alt = node.image_alt_text.as_deref().unwrap_or(&node.title);
+                out.push_str(&format!("<img src=\"{}\" alt=\"{}\" />\n", escape_html(url), escape_html(alt)));
+            }
+            // Extra panels of a multi-panel figure, each with its own caption
+            for attachment in attachments {
+                let alt = attachment.caption.as_deref().unwrap_or(&node.title);
+                out.push_str(&format!(
+                    "<figure class=\"panel\">\n<img src=\"{}\" alt=\"{}\" />\n",
+                    escape_html(&attachment.url),
+                    escape_html(alt),
+                ));
+                if let Some(caption) = &attachment.caption {
+                    out.push_str(&format!("<figcaption>{}</figcaption>\n", escape_html(caption)));
+                }
+                out.push_str("</figure>\n");
+            }
+            let caption = node.image_display_name.as_deref().unwrap_or(&node.title);
+            out.push_str(&format!("<figcaption>{}</figcaption>\n</figure>\n", escape_html(caption)));
+        }
+        "equation" => match crate::math::blocks_mathml(&blocks) {
+            Some(mathml) => out.push_str(&format!("<div class=\"equation\">{}</div>\n", mathml)),
+            None => {
+                let latex = blocks_text(&blocks);
+                out.push_str(&format!("<div class=\"equation\">{}</div>\n", escape_html(&latex)));
+            }
+        },
+        _ => {
+            let level = (node.indent_level + 2).clamp(2, 6);
+            match bookmark_level {
+                // bookmark-level/-label are the CSS paged media outline properties
+                Some(bookmark) => out.push_str(&format!(
+                    "<h{level} id=\"node-{}\" style=\"bookmark-level: {bookmark}; bookmark-label: content(text)\">{}</h{level}>\n",
+                    node.id,
+                    escape_html(&node.title),
+                )),
+                None => out.push_str(&format!("<h{level}>{}</h{level}>\n", escape_html(&node.title))),
+            }
+            for block in &blocks {
+                render_block(out, block);
+            }
+        }
+    }
+}
+
+/// One node as HTML on its own, the way it appears in an exported document
+pub fn node_html(node: &Node, content_json: Option<&str>, attachments: &[Attachment]) -> String {
+    let mut out = String::new();
+    render_node(&mut out, node, content_json, attachments, None);
+    out
+}
+
+fn render_block(out: &mut String, block: &serde_json::Value) {
+    let text = escape_html(&block_text(block));
+
+    match block.get("type").and_then(|t| t.as_str()) {
+        Some("equation") | Some("math") => match crate::math::block_mathml(block) {
+            Some(mathml) => out.push_str(&format!("<div class=\"equation\">{}</div>\n", mathml)),
+            None => {
+                let latex = blocks_text(std::slice::from_ref(block));
+                if !latex.trim().is_empty() {
+                    out.push_str(&format!("<div class=\"equation\">{}</div>\n", escape_html(&latex)));
+                }
+            }
+        },
+        Some("heading") => {
+            let level = block.pointer("/props/level").and_then(|l| l.as_i64()).unwrap_or(1);
+            let level = (level + 2).clamp(3, 6);
+            out.push_str(&format!("<h{level}>{}</h{level}>\n", text));
+        }
+        Some("bulletListItem") | Some("numberedListItem") | Some("checkListItem") => {
+            out.push_str(&format!("<ul><li>{}</li></ul>\n", text));
+        }
+        _ if !text.is_empty() => out.push_str(&format!("<p>{}</p>\n", text)),
+        _ => {}
+    }
+
+    if let Some(children) = block.get("children").and_then(|c| c.as_array()) {
+        for child in children {
+            render_block(out, child);
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Package a document as an EPUB 3 book. Each top-level section starts a
+/// chapter (anything before the first one opens the book), the navigation
+/// document follows the section outline, and figure images are embedded.
+/// Nodes in `tree` should carry their attachments. Collected figures get a
+/// closing chapter of their own.
+pub async fn render_epub(
+    document: &Document,
+    mut tree: Vec<NodeTree>,
+    content: &HashMap<i64, String>,
+    storage: &dyn Storage,
+    figure_placement: FigurePlacement,
+) -> anyhow::Result<Vec<u8>> {
+    // Point images at their copies inside the book
+    let mut images = Vec::new();
+    fn relink(trees: &mut [NodeTree], images: &mut Vec<String>) {
+        for tree in trees {
+            let node = &mut tree.node;
+            let urls = node.image_url.iter_mut().chain(node.attachments.iter_mut().map(|a| &mut a.url));
+            for url in urls {
+                if let Some(key) = url.rsplit('/').next().filter(|key| valid_key(key)) {
+                    if !images.iter().any(|image| image == key) {
+                        images.push(key.to_string());
+                    }
+                    *url = format!("images/{}", key);
+                }
+            }
+            relink(&mut tree.children, images);
+        }
+    }
+    relink(&mut tree, &mut images);
+    let figures = take_figures(&mut tree, figure_placement);
+
+    let outline = section_outline(tree.clone());
+    let mut levels = HashMap::new();
+    fn collect(entries: &[OutlineEntry], levels: &mut HashMap<i64, usize>) {
+        for entry in entries {
+            levels.insert(entry.node_id, entry.level);
+            collect(&entry.children, levels);
+        }
+    }
+    collect(&outline, &mut levels);
+
+    let mut chapters: Vec<Vec<NodeTree>> = Vec::new();
+    for node in tree {
+        if node.node.node_type == "section" || chapters.is_empty() {
+            chapters.push(Vec::new());
+        }
+        chapters.last_mut().unwrap().push(node);
+    }
+
+    // Which chapter file each node ends up in, for the navigation links
+    let mut chapter_of = HashMap::new();
+    fn assign(trees: &[NodeTree], chapter: usize, chapter_of: &mut HashMap<i64, usize>) {
+        for tree in trees {
+            chapter_of.insert(tree.node.id, chapter);
+            assign(&tree.children, chapter, chapter_of);
+        }
+    }
+    for (i, chapter) in chapters.iter().enumerate() {
+        assign(chapter, i + 1, &mut chapter_of);
+    }
+
+    fn render_tree(
+        out: &mut String,
+        trees: &[NodeTree],
+        content: &HashMap<i64, String>,
+        levels: &HashMap<i64, usize>,
+    ) {
+        for tree in trees {
+            let node = &tree.node;
+            render_node(out, node, content.get(&node.id).map(String::as_str), &node.attachments, levels.get(&node.id).copied());
+            render_tree(out, &tree.children, content, levels);
+        }
+    }
+
+    let title = escape_html(&document.title);
+    let xhtml = |heading: &str, body: &str| format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n<head>\n<meta charset=\"utf-8\" />\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+        heading, body,
+    );
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let deflated = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+
+    // The mimetype entry must come first and be stored uncompressed
+    zip.start_file("mimetype", zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored))?;
+    zip.write_all(b"application/epub+zip")?;
+
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(concat!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+        "<container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n",
+        "<rootfiles>\n<rootfile full-path=\"OEBPS/content.opf\" media-type=\"application/oebps-package+xml\" />\n</rootfiles>\n",
+        "</container>\n",
+    ).as_bytes())?;
+
+    let mut manifest = String::from("<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\" />\n");
+    let mut spine = String::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let number = i + 1;
+        let mut body = String::new();
+        if number == 1 {
+            body.push_str(&format!("<h1 class=\"document-title\">{}</h1>\n", title));
+        }
+        render_tree(&mut body, chapter, content, &levels);
+
+        zip.start_file(format!("OEBPS/chapter-{}.xhtml", number), deflated)?;
+        zip.write_all(xhtml(&title, &body).as_bytes())?;
+
+        // EPUB 3 wants chapters with embedded MathML flagged in the manifest
+        let properties = if body.contains("<math ") { " properties=\"mathml\"" } else { "" };
+        manifest.push_str(&format!(
+            "<item id=\"chapter-{0}\" href=\"chapter-{0}.xhtml\" media-type=\"application/xhtml+xml\"{1} />\n",
+            number,
+            properties,
+        ));
+        spine.push_str(&format!("<itemref idref=\"chapter-{}\" />\n", number));
+    }
+
+    let mut figures_entry = String::new();
+    if !figures.is_empty() {
+        let number = chapters.len() + 1;
+        let attachments: HashMap<i64, Vec<Attachment>> = figures.iter()
+            .map(|figure| (figure.id, figure.attachments.clone()))
+            .collect();
+        let mut body = String::new();
+        render_figure_list(&mut body, &figures.iter().collect::<Vec<_>>(), &attachments, figure_placement);
+
+        zip.start_file(format!("OEBPS/chapter-{}.xhtml", number), deflated)?;
+        zip.write_all(xhtml(&title, &body).as_bytes())?;
+        manifest.push_str(&format!(
+            "<item id=\"chapter-{0}\" href=\"chapter-{0}.xhtml\" media-type=\"application/xhtml+xml\" />\n",
+            number,
+        ));
+        spine.push_str(&format!("<itemref idref=\"chapter-{}\" />\n", number));
+        figures_entry = format!("<li><a href=\"chapter-{}.xhtml#list-of-figures\">List of Figures</a></li>\n", number);
+    }
+
+    for (i, key) in images.iter().enumerate() {
+        let Some(data) = storage.get(key).await? else {
+            tracing::warn!("EPUB export skipping missing upload {}", key);
+            continue;
+        };
+        let extension = std::path::Path::new(key)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| format!(".{}", ext.to_lowercase()))
+            .unwrap_or_default();
+
+        zip.start_file(format!("OEBPS/images/{}", key), deflated)?;
+        zip.write_all(&data)?;
+        manifest.push_str(&format!(
+            "<item id=\"image-{}\" href=\"images/{}\" media-type=\"{}\" />\n",
+            i + 1,
+            escape_html(key),
+            crate::handlers::image_content_type(&extension),
+        ));
+    }
+
+    // `extra` closes the list with entries that aren't sections
+    fn nav_list(out: &mut String, entries: &[OutlineEntry], chapter_of: &HashMap<i64, usize>, extra: &str) {
+        out.push_str("<ol>\n");
+        for entry in entries {
+            out.push_str(&format!(
+                "<li><a href=\"chapter-{}.xhtml#{}\">{}</a>\n",
+                chapter_of.get(&entry.node_id).copied().unwrap_or(1),
+                entry.anchor,
+                escape_html(&entry.title),
+            ));
+            if !entry.children.is_empty() {
+                nav_list(out, &entry.children, chapter_of, "");
+            }
+            out.push_str("</li>\n");
+        }
+        out.push_str(extra);
+        out.push_str("</ol>\n");
+    }
+    let mut nav = String::from("<nav epub:type=\"toc\" id=\"toc\">\n<h1>Contents</h1>\n");
+    if outline.is_empty() {
+        // The navigation document needs at least one entry
+        nav.push_str(&format!("<ol>\n<li><a href=\"chapter-1.xhtml\">{}</a></li>\n{}</ol>\n", title, figures_entry));
+    } else {
+        nav_list(&mut nav, &outline, &chapter_of, &figures_entry);
+    }
+    nav.push_str("</nav>\n");
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(xhtml(&title, &nav).as_bytes())?;
+
+    let creator = document.last_edited_by.as_deref()
+        .map(|name| format!("<dc:creator>{}</dc:creator>\n", escape_html(name)))
+        .unwrap_or_default();
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"book-id\">\n<metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n<dc:identifier id=\"book-id\">urn:type-editor:document:{}</dc:identifier>\n<dc:title>{}</dc:title>\n{}<dc:language>en</dc:language>\n<meta property=\"dcterms:modified\">{}</meta>\n</metadata>\n<manifest>\n{}</manifest>\n<spine>\n{}</spine>\n</package>\n",
+        document.id,
+        title,
+        creator,
+        chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        manifest,
+        spine,
+    ).as_bytes())?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Render a document as a standalone LaTeX source file. Nodes are emitted in
+/// outline order; figures reference their images by upload key, so the .tex
+/// compiles once the uploads sit next to it.
+pub fn render_latex(
+    document: &Document,
+    mut tree: Vec<NodeTree>,
+    content: &HashMap<i64, String>,
+    attachments: &HashMap<i64, Vec<Attachment>>,
+    template: &str,
+    figure_placement: FigurePlacement,
+) -> String {
+    let class = match template {
+        "report" => "report",
+        _ => "article",
+    };
+
+    let mut out = format!(
+        "\\documentclass{{{}}}\n\\usepackage[utf8]{{inputenc}}\n\\usepackage{{amsmath}}\n\\usepackage{{graphicx}}\n\n\\title{{{}}}\n\\date{{}}\n\n\\begin{{document}}\n\\maketitle\n\n",
+        class,
+        escape_latex(&document.title),
+    );
+
+    fn walk(
+        out: &mut String,
+        trees: &[NodeTree],
+        content: &HashMap<i64, String>,
+        attachments: &HashMap<i64, Vec<Attachment>>,
+    ) {
+        for tree in trees {
+            let node = &tree.node;
+            let blocks: Vec<serde_json::Value> = content.get(&node.id)
+                .and_then(|json| serde_json::from_str(json).ok())
+                .unwrap_or_default();
+
+            match node.node_type.as_str() {
+                "equation" => {
+                    let latex = blocks_text(&blocks);
+                    if !latex.trim().is_empty() {
+                        out.push_str(&format!("\\begin{{equation}}\n{}\n\\end{{equation}}\n\n", latex.trim()));
+                    }
+                }
+                "figure" => latex_figure(out, node, attachments, "htbp"),
+                _ => {
+                    let command = match node.indent_level {
+                        0 => "section",
+                        1 => "subsection",
+                        2 => "subsubsection",
+                        _ => "paragraph",
+                    };
+                    out.push_str(&format!("\\{}{{{}}}\n\\label{{node-{}}}\n\n", command, escape_latex(&node.title), node.id));
+                    for block in &blocks {
+                        render_latex_block(out, block);
+                    }
+                }
+            }
+
+            walk(out, &tree.children, content, attachments);
+        }
+    }
+
+    let figures = take_figures(&mut tree, figure_placement);
+    walk(&mut out, &tree, content, attachments);
+
+    if !figures.is_empty() {
+        match figure_placement {
+            FigurePlacement::SeparateList => out.push_str("\\clearpage\n\\listoffigures\n\n"),
+            _ => out.push_str("\\section*{List of Figures}\n\n"),
+        }
+    }
+    for figure in &figures {
+        match figure_placement {
+            // A float page each
+            FigurePlacement::SeparateList => {
+                out.push_str("\\clearpage\n");
+                latex_figure(&mut out, figure, attachments, "p");
+            }
+            // Never at the top of a page, where it could land above the heading
+            _ => latex_figure(&mut out, figure, attachments, "hbp"),
+        }
+    }
+
+    out.push_str("\\end{document}\n");
+    out
+}
+
+fn latex_figure(out: &mut String, node: &Node, attachments: &HashMap<i64, Vec<Attachment>>, placement: &str) {
+    let images = node.image_url.iter()
+        .chain(attachments.get(&node.id).into_iter().flatten().map(|a| &a.url));
+    out.push_str(&format!("\\begin{{figure}}[{}]\n\\centering\n", placement));
+    for url in images {
+        let key = url.rsplit('/').next().unwrap_or(url);
+        out.push_str(&format!("\\includegraphics[width=\\linewidth]{{{}}}\n", key));
+    }
+    let caption = node.image_display_name.as_deref().unwrap_or(&node.title);
+    out.push_str(&format!("\\caption{{{}}}\n\\label{{node-{}}}\n\\end{{figure}}\n\n", escape_latex(caption), node.id));
+}
+
+/// A document's equations as one LaTeX file for review: each in its own
+/// equation environment, labelled like render_latex labels nodes and preceded
+/// by a comment naming it and its section. Empty equations are left out.
+pub fn render_equations(document: &Document, equations: &[EquationExport]) -> String {
+    // Comments end at a newline, so titles are kept on one line
+    let one_line = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let mut out = format!("% Equations from \"{}\"\n\n", one_line(&document.title));
+    for equation in equations.iter().filter(|e| !e.latex.trim().is_empty()) {
+        if let Some(section) = &equation.section {
+            out.push_str(&format!("% Section: {}\n", one_line(section)));
+        }
+        out.push_str(&format!(
+            "% {}\n\\begin{{equation}}\n{}\n\\label{{node-{}}}\n\\end{{equation}}\n\n",
+            one_line(&equation.title),
+            equation.latex.trim(),
+            equation.node_id,
+        ));
+    }
+    out
+}
+
+fn render_latex_block(out: &mut String, block: &serde_json::Value) {
+    match block.get("type").and_then(|t| t.as_str()) {
+        Some("equation") | Some("math") => {
+            let latex = blocks_text(std::slice::from_ref(block));
+            if !latex.trim().is_empty() {
+                out.push_str(&format!("\\begin{{equation*}}\n{}\n\\end{{equation*}}\n\n", latex.trim()));
+            }
+        }
+        Some("heading") => {
+            out.push_str(&format!("\\paragraph{{{}}}\n\n", escape_latex(&block_text(block))));
+        }
+        Some("bulletListItem") | Some("numberedListItem") | Some("checkListItem") => {
+            out.push_str(&format!("\\begin{{itemize}}\n\\item {}\n\\end{{itemize}}\n\n", escape_latex(&block_text(block))));
+        }
+        _ => {
+            let text = block_text(block);
+            if !text.trim().is_empty() {
+                out.push_str(&format!("{}\n\n", escape_latex(&text)));
+            }
+        }
+    }
+
+    if let Some(children) = block.get("children").and_then(|c| c.as_array()) {
+        for child in children {
+            render_latex_block(out, child);
+        }
+    }
+}
+
+/// Escape LaTeX's special characters in plain text
+fn escape_latex(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '~' => out.push_str("\\textasciitilde{}"),
+            '^' => out.push_str("\\textasciicircum{}"),
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
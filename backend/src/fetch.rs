@@ -0,0 +1,187 @@
+//! Server-side fetching of remote images for URL imports.
+//!
+//! The server fetching URLs on a client's behalf is an SSRF risk, so only
+//! http(s) URLs are followed, every address a host resolves to must be
+//! public, the connection is pinned to the address that was checked (so a
+//! second DNS answer can't swap in an internal one), redirects are re-checked
+//! hop by hop, and the download is bounded in size and time.
+
+use crate::error::ApiError;
+use axum::body::Bytes;
+use axum::http::StatusCode;
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+const MAX_REDIRECTS: usize = 3;
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// Not an absolute http(s) URL, or one with credentials in it
+    InvalidUrl,
+    /// The host resolves to a loopback, private or otherwise internal address
+    Blocked(String),
+    TooLarge,
+    Timeout,
+    /// The remote server answered with something other than the file
+    Upstream(String),
+}
+
+impl From<FetchError> for ApiError {
+    fn from(error: FetchError) -> Self {
+        match error {
+            FetchError::InvalidUrl => ApiError::Message(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "url must be an absolute http or https URL without credentials".to_string(),
+            ),
+            FetchError::Blocked(host) => ApiError::Message(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("{} resolves to an address that may not be fetched", host),
+            ),
+            FetchError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE.into(),
+            FetchError::Timeout => ApiError::Message(
+                StatusCode::GATEWAY_TIMEOUT,
+                "The remote server took too long to respond".to_string(),
+            ),
+            FetchError::Upstream(reason) => ApiError::Message(
+                StatusCode::BAD_GATEWAY,
+                format!("Could not fetch the remote file: {}", reason),
+            ),
+        }
+    }
+}
+
+pub struct Fetched {
+    /// Where the file was finally fetched from, after redirects
+    pub url: Url,
+    pub content_type: Option<String>,
+    pub data: Bytes,
+}
+
+/// Fetch `url`, refusing internal addresses, following up to a few
+/// redirects, and giving up past `max_bytes` or `timeout` overall
+pub async fn fetch(url: &str, max_bytes: usize, timeout: Duration) -> Result<Fetched, FetchError> {
+    let url = Url::parse(url).map_err(|_| FetchError::InvalidUrl)?;
+    tokio::time::timeout(timeout, follow(url, max_bytes))
+        .await
+        .map_err(|_| FetchError::Timeout)?
+}
+
+async fn follow(mut url: Url, max_bytes: usize) -> Result<Fetched, FetchError> {
+    for _ in 0..=MAX_REDIRECTS {
+        let response = request(&url).await?;
+
+        if response.status().is_redirection() {
+            let location = response.headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| FetchError::Upstream("redirect without a location".to_string()))?;
+            url = url.join(location).map_err(|_| FetchError::InvalidUrl)?;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(FetchError::Upstream(format!("the server answered {}", response.status())));
+        }
+
+        return read(url, response, max_bytes).await;
+    }
+
+    Err(FetchError::Upstream("too many redirects".to_string()))
+}
+
+/// One request without following redirects, to a checked public address
+async fn request(url: &Url) -> Result<reqwest::Response, FetchError> {
+    if !matches!(url.scheme(), "http" | "https") || !url.username().is_empty() || url.password().is_some() {
+        return Err(FetchError::InvalidUrl);
+    }
+    let host = url.host_str().ok_or(FetchError::InvalidUrl)?;
+    let port = url.port_or_known_default().ok_or(FetchError::InvalidUrl)?;
+
+    // A proxy would do its own resolving, so none is used
+    let mut client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy();
+    // IPv6 literals come bracketed, as in http://[::1]/
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        if !is_public(ip) {
+            return Err(FetchError::Blocked(host.to_string()));
+        }
+    } else {
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| FetchError::Upstream(format!("{} could not be resolved", host)))?
+            .collect();
+        if addresses.is_empty() || addresses.iter().any(|address| !is_public(address.ip())) {
+            return Err(FetchError::Blocked(host.to_string()));
+        }
+        client = client.resolve(host, addresses[0]);
+    }
+
+    client.build()
+        .map_err(|e| FetchError::Upstream(e.to_string()))?
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(|e| FetchError::Upstream(without_url(e)))
+}
+
+/// The body, stopping as soon as it is known to be too large
+async fn read(url: Url, mut response: reqwest::Response, max_bytes: usize) -> Result<Fetched, FetchError> {
+    if response.content_length().is_some_and(|length| length > max_bytes as u64) {
+        return Err(FetchError::TooLarge);
+    }
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| FetchError::Upstream(without_url(e)))? {
+        if data.len() + chunk.len() > max_bytes {
+            return Err(FetchError::TooLarge);
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(Fetched { url, content_type, data: data.into() })
+}
+
+fn without_url(error: reqwest::Error) -> String {
+    error.without_url().to_string()
+}
+
+/// Whether an address is on the public internet, as opposed to this host,
+/// a private network, or a reserved range
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_v4(mapped);
+            }
+            let segments = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00 // unique local
+                || (segments[0] & 0xffc0) == 0xfe80 // link-local
+                || (segments[0] == 0x64 && segments[1] == 0xff9b) // NAT64 to any IPv4
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)) // documentation
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b)) // carrier-grade NAT
+        || (a == 198 && (b == 18 || b == 19)) // benchmarking
+        || a >= 240) // reserved
+}
@@ -1,264 +1,4250 @@
+use crate::audit::AuditEntry;
+use crate::auth::{AdminToken, Editor};
+use crate::crypto::{self, CryptoError};
+use crate::error::{ApiError, Json, ValidationErrors};
 use crate::models::*;
+use crate::pagination::Pagination;
+use crate::query::traced;
+use crate::text;
+use crate::tx::Tx;
+use crate::webhooks::{self, CreateWebhookRequest, Webhook};
 use crate::AppState;
 use axum::{
-    extract::{Multipart, Path, State},
-    http::StatusCode,
-    Json,
+    body::Body,
+    extract::{Multipart, OriginalUri, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
 };
+use serde::Serialize;
 use serde_json::json;
-use sqlx::Row;
-use std::io::Write;
+use std::collections::HashMap;
+use tokio_util::io::ReaderStream;
 
 // Document handlers
 pub async fn list_documents(
     State(state): State<AppState>,
-) -> Result<Json<Vec<Document>>, StatusCode> {
-    let documents = sqlx::query_as::<_, Document>("SELECT * FROM documents ORDER BY updated_at DESC")
-        .fetch_all(&state.db)
-        .await
+    Query(page): Query<Pagination>,
+    Query(filter): Query<DocumentListQuery>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<(HeaderMap, Json<Vec<Document>>), StatusCode> {
+    let mut documents = traced("select", "documents", sqlx::query_as::<_, Document>(
+        "SELECT * FROM documents WHERE (? IS NULL OR status = ?) ORDER BY updated_at DESC LIMIT ? OFFSET ?"
+    )
+    .bind(filter.status)
+    .bind(filter.status)
+    .bind(page.sql_limit())
+    .bind(page.sql_offset())
+    .fetch_all(&state.read_db))
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    fill_covers(&state.read_db, &mut documents).await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(documents))
+    let headers = if page.is_paginated() {
+        let total: i64 = traced("count", "documents", sqlx::query_scalar("SELECT COUNT(*) FROM documents WHERE (? IS NULL OR status = ?)")
+            .bind(filter.status)
+            .bind(filter.status)
+            .fetch_optional(&state.read_db))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .unwrap_or(0);
+        page.headers(&uri, total)
+    } else {
+        HeaderMap::new()
+    };
+
+    Ok((headers, Json(documents)))
 }
 
-pub async fn create_document(
+const MAX_BATCH_IDS: usize = 100;
+
+/// Several documents by id in one request, in the order asked for. Ids that
+/// don't exist are left out and listed under "missing". Without user
+/// accounts every document belongs to the one implicit user, so nothing is
+/// filtered by owner.
+pub async fn batch_documents(
     State(state): State<AppState>,
-    Json(payload): Json<CreateDocumentRequest>,
-) -> Result<Json<Document>, StatusCode> {
-    let result = sqlx::query(
-        "INSERT INTO documents (title) VALUES (?)"
+    Json(payload): Json<DocumentBatchRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if payload.ids.len() > MAX_BATCH_IDS {
+        let mut errors = ValidationErrors::new();
+        errors.add("ids", format!("must list at most {} ids", MAX_BATCH_IDS));
+        errors.check()?;
+    }
+
+    let mut ids = payload.ids;
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+
+    let mut documents = traced("select", "documents", sqlx::query_as::<_, Document>(
+        "SELECT * FROM documents WHERE id IN (SELECT value FROM json_each(?))"
     )
-    .bind(&payload.title)
-    .execute(&state.db)
+    .bind(serde_json::to_string(&ids).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?)
+    .fetch_all(&state.read_db))
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
-        .bind(result.last_insert_rowid())
-        .fetch_one(&state.db)
-        .await
+    fill_covers(&state.read_db, &mut documents).await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(doc))
+    let mut by_id: HashMap<i64, Document> = documents.into_iter().map(|doc| (doc.id, doc)).collect();
+    let mut found = Vec::with_capacity(by_id.len());
+    let mut missing = Vec::new();
+    for id in ids {
+        match by_id.remove(&id) {
+            Some(doc) => found.push(doc),
+            None => missing.push(id),
+        }
+    }
+
+    Ok(Json(json!({
+        "documents": found,
+        "missing": missing,
+    })))
 }
 
-pub async fn get_document(
+/// Give documents without an explicit cover the image of their first figure,
+/// in the same order_index order node listings use
+async fn fill_covers(db: &sqlx::SqlitePool, documents: &mut [Document]) -> sqlx::Result<()> {
+    let uncovered: Vec<i64> = documents.iter()
+        .filter(|doc| doc.cover_image_url.is_none())
+        .map(|doc| doc.id)
+        .collect();
+    if uncovered.is_empty() {
+        return Ok(());
+    }
+
+    let figures: Vec<(i64, String)> = traced("select", "nodes", sqlx::query_as(
+        "SELECT document_id, image_url FROM nodes
+         WHERE node_type = 'figure' AND image_url IS NOT NULL
+           AND document_id IN (SELECT value FROM json_each(?))
+         ORDER BY order_index DESC, id DESC"
+    )
+    .bind(serde_json::to_string(&uncovered).unwrap_or_default())
+    .fetch_all(db))
+    .await?;
+
+    // Later rows are earlier figures, so each document ends with its first
+    let covers: HashMap<i64, String> = figures.into_iter().collect();
+    for doc in documents.iter_mut() {
+        if doc.cover_image_url.is_none() {
+            doc.cover_image_url = covers.get(&doc.id).cloned();
+        }
+    }
+
+    Ok(())
+}
+
+/// Set or clear a document's cover image. Only uploaded images can be
+/// covers; clearing returns to the automatic first-figure cover.
+pub async fn set_document_cover(
     State(state): State<AppState>,
     Path(id): Path<i64>,
-) -> Result<Json<Document>, StatusCode> {
-    let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+    Editor(editor): Editor,
+    mut tx: Tx,
+    Json(payload): Json<SetCoverRequest>,
+) -> Result<Json<Document>, ApiError> {
+    let before = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
         .bind(id)
-        .fetch_one(&state.db)
+        .fetch_optional(&mut *tx)
         .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    Ok(Json(doc))
-}
+    if let Some(url) = &payload.image_url {
+        let uploaded: Option<i64> = sqlx::query_scalar("SELECT id FROM uploads WHERE url = ?")
+            .bind(url)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if uploaded.is_none() {
+            let mut errors = ValidationErrors::new();
+            errors.add("image_url", "must be the URL of an uploaded image");
+            return Err(errors.into());
+        }
+    }
 
-pub async fn update_document(
-    State(state): State<AppState>,
-    Path(id): Path<i64>,
-    Json(payload): Json<CreateDocumentRequest>,
-) -> Result<Json<Document>, StatusCode> {
-    sqlx::query("UPDATE documents SET title = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
-        .bind(&payload.title)
+    sqlx::query("UPDATE documents SET cover_image_url = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+        .bind(&payload.image_url)
         .bind(id)
-        .execute(&state.db)
+        .execute(&mut *tx)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    record_document_edit(&mut tx, id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
         .bind(id)
-        .fetch_one(&state.db)
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("update", "document", id, Some(&before), Some(&doc));
 
     Ok(Json(doc))
 }
 
-pub async fn delete_document(
-    State(state): State<AppState>,
-    Path(id): Path<i64>,
-) -> Result<StatusCode, StatusCode> {
-    sqlx::query("DELETE FROM documents WHERE id = ?")
-        .bind(id)
-        .execute(&state.db)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Stamp a document with who last changed it and when
+pub(crate) async fn record_document_edit(
+    conn: &mut sqlx::SqliteConnection,
+    document_id: i64,
+    editor: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query("UPDATE documents SET last_edited_by = ?, last_edited_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+        .bind(editor)
+        .bind(document_id)
+        .execute(&mut *conn)
+        .await?;
 
-    Ok(StatusCode::NO_CONTENT)
+    Ok(())
 }
 
-// Node handlers
-pub async fn list_nodes(
-    State(state): State<AppState>,
-    Path(doc_id): Path<i64>,
-) -> Result<Json<Vec<Node>>, StatusCode> {
-    let nodes = sqlx::query_as::<_, Node>(
-        "SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index"
+/// Stamp a node, and the document it belongs to, with who last changed it
+pub(crate) async fn record_node_edit(
+    conn: &mut sqlx::SqliteConnection,
+    node_id: i64,
+    editor: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query("UPDATE nodes SET last_edited_by = ? WHERE id = ?")
+        .bind(editor)
+        .bind(node_id)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query(
+        "UPDATE documents SET last_edited_by = ?, last_edited_at = strftime('%Y-%m-%d %H:%M:%f', 'now')
+         WHERE id = (SELECT document_id FROM nodes WHERE id = ?)"
     )
-    .bind(doc_id)
-    .fetch_all(&state.db)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .bind(editor)
+    .bind(node_id)
+    .execute(&mut *conn)
+    .await?;
 
-    Ok(Json(nodes))
+    Ok(())
 }
 
-pub async fn create_node(
+// Recomputes documents.word_count from the per-node counts in content
+const REFRESH_WORD_COUNT: &str =
+    "UPDATE documents SET word_count = (
+         SELECT COALESCE(SUM(content.word_count), 0) FROM content
+         JOIN nodes ON nodes.id = content.node_id WHERE nodes.document_id = documents.id
+     )";
+
+/// Bring a document's cached word count up to date with its content
+pub(crate) async fn refresh_word_count(conn: &mut sqlx::SqliteConnection, document_id: i64) -> sqlx::Result<()> {
+    sqlx::query(&format!("{} WHERE id = ?", REFRESH_WORD_COUNT))
+        .bind(document_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Record the word count of a node's just-written content, and its document's new total
+pub(crate) async fn set_word_count(conn: &mut sqlx::SqliteConnection, node_id: i64, words: i64) -> sqlx::Result<()> {
+    sqlx::query("UPDATE content SET word_count = ? WHERE node_id = ?")
+        .bind(words)
+        .bind(node_id)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query(&format!("{} WHERE id = (SELECT document_id FROM nodes WHERE id = ?)", REFRESH_WORD_COUNT))
+        .bind(node_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Words in plaintext content JSON
+fn content_words(content_json: &str) -> i64 {
+    text::word_count(&text::plain_text(content_json)) as i64
+}
+
+/// Words in content as stored, opening it first when it is sealed. Content
+/// that can't be opened (no key configured) counts as empty.
+fn stored_words(state: &AppState, document_id: i64, stored_json: &str) -> i64 {
+    if !crate::crypto::is_sealed(stored_json) {
+        return content_words(stored_json);
+    }
+    match cipher(state).and_then(|cipher| cipher.open(document_id, stored_json)) {
+        Ok(plain) => content_words(&plain),
+        Err(_) => 0,
+    }
+}
+
+// Longest title accepted for documents and nodes, in characters
+const MAX_TITLE_LEN: usize = 500;
+
+fn validate_title(errors: &mut ValidationErrors, title: &str) {
+    if title.trim().is_empty() {
+        errors.add("title", "must not be empty");
+    } else if title.chars().count() > MAX_TITLE_LEN {
+        errors.add("title", format!("must be at most {} characters", MAX_TITLE_LEN));
+    }
+}
+
+// Largest serialized node attributes object accepted, in bytes
+const MAX_ATTRIBUTES_LEN: usize = 16 * 1024;
+
+/// Node attributes as stored: a JSON object of bounded size
+fn validate_attributes(
+    errors: &mut ValidationErrors,
+    attributes: &serde_json::Value,
+) -> Option<sqlx::types::Json<serde_json::Map<String, serde_json::Value>>> {
+    let serde_json::Value::Object(map) = attributes else {
+        errors.add("attributes", "must be an object");
+        return None;
+    };
+    if attributes.to_string().len() > MAX_ATTRIBUTES_LEN {
+        errors.add("attributes", format!("must be at most {} bytes of JSON", MAX_ATTRIBUTES_LEN));
+        return None;
+    }
+    Some(sqlx::types::Json(map.clone()))
+}
+
+pub async fn create_document(
     State(state): State<AppState>,
-    Json(payload): Json<CreateNodeRequest>,
-) -> Result<Json<Node>, StatusCode> {
+    Editor(editor): Editor,
+    Json(payload): Json<CreateDocumentRequest>,
+) -> Result<Json<Document>, ApiError> {
+    let mut errors = ValidationErrors::new();
+    validate_title(&mut errors, &payload.title);
+    if payload.encrypted && state.content_cipher.is_none() {
+        errors.add("encrypted", "content encryption is not configured on this server");
+    }
+    errors.check()?;
+
+    // The quota is checked in the INSERT itself so concurrent creates cannot overshoot it
     let result = sqlx::query(
-        "INSERT INTO nodes (document_id, parent_id, node_type, title, order_index, indent_level, image_url) 
-         VALUES (?, ?, ?, ?, ?, ?, ?)"
+        "INSERT INTO documents (title, child_sort, encrypted, last_edited_by, last_edited_at)
+         SELECT ?, ?, ?, ?, strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE (SELECT COUNT(*) FROM documents) < ?"
     )
-    .bind(payload.document_id)
-    .bind(payload.parent_id)
-    .bind(&payload.node_type)
     .bind(&payload.title)
-    .bind(payload.order_index)
-    .bind(payload.indent_level)
-    .bind(&payload.image_url)
+    .bind(payload.child_sort)
+    .bind(payload.encrypted)
+    .bind(&editor)
+    .bind(state.config.max_documents.unwrap_or(i64::MAX))
     .execute(&state.db)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Message(
+            StatusCode::FORBIDDEN,
+            format!("Document quota reached: at most {} documents allowed", state.config.max_documents.unwrap_or_default()),
+        ));
+    }
+
+    let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
         .bind(result.last_insert_rowid())
         .fetch_one(&state.db)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(node))
+    state.audit.record("create", "document", doc.id, None::<&()>, Some(&doc));
+    state.webhooks.emit("document.created", &doc);
+
+    Ok(Json(doc))
 }
 
-pub async fn get_node(
+/// Instance-wide counters for the home screen. Without user accounts every
+/// document belongs to the one implicit user, so nothing is scoped.
+pub async fn stats_summary(
     State(state): State<AppState>,
-    Path(id): Path<i64>,
-) -> Result<Json<Node>, StatusCode> {
-    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
-        .bind(id)
-        .fetch_one(&state.db)
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let (documents, nodes, content_rows, uploads): (i64, i64, i64, i64) = sqlx::query_as(
+        "SELECT (SELECT COUNT(*) FROM documents), (SELECT COUNT(*) FROM nodes),
+                (SELECT COUNT(*) FROM content), (SELECT COUNT(*) FROM uploads)"
+    )
+    .fetch_one(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let total_words: i64 = sqlx::query_scalar("SELECT COALESCE(SUM(word_count), 0) FROM documents")
+        .fetch_one(&state.read_db)
         .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(node))
+    Ok(Json(json!({
+        "documents": documents,
+        "nodes": nodes,
+        "content_rows": content_rows,
+        "uploads": uploads,
+        "total_words": total_words,
+    })))
 }
 
-pub async fn update_node(
+/// Counters for one document, read from the cached word count rather than
+/// the content itself
+pub async fn document_stats(
     State(state): State<AppState>,
     Path(id): Path<i64>,
-    Json(payload): Json<UpdateNodeRequest>,
-) -> Result<Json<Node>, StatusCode> {
-    if let Some(title) = &payload.title {
-        sqlx::query("UPDATE nodes SET title = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
-            .bind(title)
-            .bind(id)
-            .execute(&state.db)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let (word_count, nodes): (i64, i64) = sqlx::query_as(
+        "SELECT word_count, (SELECT COUNT(*) FROM nodes WHERE document_id = documents.id) FROM documents WHERE id = ?"
+    )
+    .bind(id)
+    .fetch_optional(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
 
-    if let Some(order_index) = payload.order_index {
-        sqlx::query("UPDATE nodes SET order_index = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
-            .bind(order_index)
-            .bind(id)
-            .execute(&state.db)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+    Ok(Json(json!({
+        "document_id": id,
+        "word_count": word_count,
+        "nodes": nodes,
+    })))
+}
 
-    if let Some(indent_level) = payload.indent_level {
-        sqlx::query("UPDATE nodes SET indent_level = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
-            .bind(indent_level)
-            .bind(id)
-            .execute(&state.db)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+pub async fn get_document(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Document>, StatusCode> {
+    let doc = traced("select", "documents", sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.read_db))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    if let Some(parent_id) = payload.parent_id {
-        sqlx::query("UPDATE nodes SET parent_id = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
-            .bind(parent_id)
-            .bind(id)
-            .execute(&state.db)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+    Ok(Json(doc))
+}
 
-    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+pub async fn get_document_last_edit(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
         .bind(id)
-        .fetch_one(&state.db)
+        .fetch_one(&state.read_db)
         .await
         .map_err(|_| StatusCode::NOT_FOUND)?;
 
-    Ok(Json(node))
+    // Serialized through the model so timestamps come out as RFC 3339
+    let doc = json!(doc);
+    let last_edited_at = match &doc["last_edited_at"] {
+        // Documents edited before provenance was tracked fall back to their last update
+        serde_json::Value::Null => doc["updated_at"].clone(),
+        at => at.clone(),
+    };
+
+    Ok(Json(json!({
+        "document_id": doc["id"],
+        "last_edited_by": doc["last_edited_by"],
+        "last_edited_at": last_edited_at,
+    })))
 }
 
-pub async fn delete_node(
+/// Everything needed to open a document: the document, its node tree with
+/// attachments, and every node's content keyed by node id
+pub async fn get_document_full(
     State(state): State<AppState>,
     Path(id): Path<i64>,
-) -> Result<StatusCode, StatusCode> {
-    sqlx::query("DELETE FROM nodes WHERE id = ?")
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
         .bind(id)
-        .execute(&state.db)
+        .fetch_one(&state.read_db)
         .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let order = doc.child_sort.unwrap_or(state.config.default_child_sort).order_by();
+    let mut nodes = sqlx::query_as::<_, Node>(&format!(
+        "{} WHERE nodes.document_id = ? ORDER BY {}",
+        NODE_WITH_UPLOAD_SELECT, order
+    ))
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut attachments = document_attachments(&state.read_db, id).await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let unresolved: HashMap<i64, i64> = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT node_id, COUNT(*) FROM comments
+         WHERE resolved = 0 AND node_id IN (SELECT id FROM nodes WHERE document_id = ?)
+         GROUP BY node_id"
+    )
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .into_iter()
+    .collect();
+    for node in &mut nodes {
+        node.attachments = attachments.remove(&node.id).unwrap_or_default();
+        node.unresolved_comments = Some(unresolved.get(&node.id).copied().unwrap_or(0));
+    }
 
-    Ok(StatusCode::NO_CONTENT)
+    let content = document_content(&state, id, doc.encrypted).await?;
+
+    Ok(Json(json!({
+        "document": doc,
+        "tree": build_tree(nodes),
+        "content": content,
+    })))
 }
 
-// Content handlers
-pub async fn get_content(
+/// Every node's content_json in a document, keyed by node id. Together with
+/// the node list this loads a document in two requests.
+pub async fn get_document_content(
     State(state): State<AppState>,
-    Path(node_id): Path<i64>,
-) -> Result<Json<Content>, StatusCode> {
-    let content = sqlx::query_as::<_, Content>("SELECT * FROM content WHERE node_id = ?")
-        .bind(node_id)
-        .fetch_one(&state.db)
+    Path(id): Path<i64>,
+) -> Result<Json<HashMap<i64, String>>, ApiError> {
+    let encrypted: bool = sqlx::query_scalar("SELECT encrypted FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.read_db)
         .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    Ok(Json(content))
+    Ok(Json(document_content(&state, id, encrypted).await?))
 }
 
-pub async fn save_content(
-    State(state): State<AppState>,
-    Path(node_id): Path<i64>,
-    Json(payload): Json<SaveContentRequest>,
-) -> Result<Json<Content>, StatusCode> {
-    sqlx::query(
-        "INSERT INTO content (node_id, content_json) VALUES (?, ?)
-         ON CONFLICT(node_id) DO UPDATE SET content_json = ?, updated_at = CURRENT_TIMESTAMP"
+/// Plain content_json of a document's nodes, keyed by node id
+async fn document_content(state: &AppState, id: i64, encrypted: bool) -> Result<HashMap<i64, String>, ApiError> {
+    let mut content: HashMap<i64, String> = sqlx::query_as::<_, (i64, String)>(
+        "SELECT content.node_id, content.content_json FROM content
+         JOIN nodes ON content.node_id = nodes.id
+         WHERE nodes.document_id = ?"
     )
-    .bind(node_id)
-    .bind(&payload.content_json)
-    .bind(&payload.content_json)
-    .execute(&state.db)
+    .bind(id)
+    .fetch_all(&state.read_db)
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .into_iter()
+    .collect();
 
-    let content = sqlx::query_as::<_, Content>("SELECT * FROM content WHERE node_id = ?")
-        .bind(node_id)
-        .fetch_one(&state.db)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if encrypted {
+        for stored in content.values_mut().filter(|stored| crypto::is_sealed(stored)) {
+            *stored = cipher(state)?.open(id, stored)?;
+        }
+    }
 
-    Ok(Json(content))
+    Ok(content)
 }
 
-// File validation constants
-const MAX_FILE_SIZE: usize = 10 * 1024 * 1024; // 10MB
-const ALLOWED_EXTENSIONS: &[&str] = &[".jpg", ".jpeg", ".png", ".gif", ".webp"];
+/// Compare two documents' node trees, e.g. a document and an edited
+/// duplicate of it; see the diff module for how nodes are paired
+pub async fn diff_documents(
+    State(state): State<AppState>,
+    Query(params): Query<DocumentDiffQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut sides = Vec::with_capacity(2);
+    for id in [params.left, params.right] {
+        let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&state.read_db)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
 
-// Magic number signatures for image files
-fn verify_image_magic_number(data: &[u8], extension: &str) -> bool {
-    if data.len() < 4 {
-        return false;
+        let nodes = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index, id")
+            .bind(id)
+            .fetch_all(&state.read_db)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let content = document_content(&state, id, document.encrypted).await?;
+        sides.push((document, nodes, content));
     }
-    
-    match extension {
+    let (right, right_nodes, right_content) = sides.pop().expect("two sides");
+    let (left, left_nodes, mut content) = sides.pop().expect("two sides");
+    content.extend(right_content);
+
+    let diff = crate::diff::diff(&left_nodes, &right_nodes, &content);
+
+    Ok(Json(json!({
+        "left": { "id": left.id, "title": left.title },
+        "right": { "id": right.id, "title": right.title },
+        "diff": diff,
+    })))
+}
+
+pub async fn update_document(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    headers: HeaderMap,
+    mut tx: Tx,
+    Json(payload): Json<CreateDocumentRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let before = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query(
+        "UPDATE documents SET title = ?, child_sort = COALESCE(?, child_sort), updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now'),
+         last_edited_by = ?, last_edited_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?"
+    )
+    .bind(&payload.title)
+    .bind(payload.child_sort)
+    .bind(&editor)
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    state.audit.record("update", "document", id, before.as_ref(), Some(&doc));
+    let webhooks = state.webhooks.clone();
+    let updated = doc.clone();
+    tx.after_commit(move || webhooks.emit("document.updated", &updated));
+
+    Ok(updated_response("document", before.as_ref(), &doc, wants_changed_fields(&headers)))
+}
+
+/// Whether the client sent `X-Changed-Fields: true` to get updates back
+/// with the list of fields they changed
+fn wants_changed_fields(headers: &HeaderMap) -> bool {
+    headers.get("x-changed-fields")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+// Stamped by every write, so never reported as changed
+const EDIT_STAMP_FIELDS: &[&str] = &["updated_at", "last_edited_at", "last_edited_by"];
+
+/// The updated row as is, or with `changed_fields` as
+/// `{ "<key>": row, "changed_fields": [...] }` when the client asked for them
+fn updated_response<T: Serialize>(key: &str, before: Option<&T>, after: &T, with_changes: bool) -> Response {
+    if !with_changes {
+        return Json(after).into_response();
+    }
+
+    let before = before.and_then(|before| serde_json::to_value(before).ok());
+    let after_value = serde_json::to_value(after).unwrap_or_default();
+    let changed: Vec<&String> = match after_value.as_object() {
+        Some(fields) => fields.iter()
+            .filter(|(field, value)| {
+                !EDIT_STAMP_FIELDS.contains(&field.as_str())
+                    && before.as_ref().and_then(|before| before.get(field.as_str())) != Some(*value)
+            })
+            .map(|(field, _)| field)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Json(json!({ key: after_value, "changed_fields": changed })).into_response()
+}
+
+/// Move a document from one status to another, refusing with 409 when it
+/// isn't currently in `from`
+async fn transition_status(
+    state: &AppState,
+    id: i64,
+    editor: Option<String>,
+    from: DocumentStatus,
+    to: DocumentStatus,
+) -> Result<Json<Document>, ApiError> {
+    let before = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // Guarded on the current status so concurrent transitions can't both apply
+    let result = sqlx::query(
+        "UPDATE documents SET status = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now'),
+         last_edited_by = ?, last_edited_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ? AND status = ?"
+    )
+    .bind(to)
+    .bind(&editor)
+    .bind(id)
+    .bind(from)
+    .execute(&state.db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::Message(
+            StatusCode::CONFLICT,
+            format!("Document is {}, not {}", before.status.as_str(), from.as_str()),
+        ));
+    }
+
+    let doc = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("status", "document", id, Some(&before), Some(&doc));
+    let event = match to {
+        DocumentStatus::Published => "document.published",
+        _ => "document.updated",
+    };
+    state.webhooks.emit(event, &doc);
+
+    Ok(Json(doc))
+}
+
+pub async fn publish_document(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+) -> Result<Json<Document>, ApiError> {
+    transition_status(&state, id, editor, DocumentStatus::Draft, DocumentStatus::Published).await
+}
+
+pub async fn unpublish_document(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+) -> Result<Json<Document>, ApiError> {
+    transition_status(&state, id, editor, DocumentStatus::Published, DocumentStatus::Draft).await
+}
+
+pub async fn delete_document(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<DeleteQuery>,
+    mut tx: Tx,
+) -> Result<StatusCode, StatusCode> {
+    let before = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let deleted = sqlx::query("DELETE FROM documents WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .rows_affected();
+
+    // Only the request that removed the row records it, even when
+    // concurrent deletes both saw it
+    let Some(before) = before.filter(|_| deleted > 0) else {
+        return if params.ignore_missing { Ok(StatusCode::NO_CONTENT) } else { Err(StatusCode::NOT_FOUND) };
+    };
+
+    // In the same transaction as the delete, so sync clients can't miss it
+    sqlx::query("INSERT OR REPLACE INTO deleted_documents (document_id) VALUES (?)")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let audit = state.audit.clone();
+    tx.after_commit(move || audit.record("delete", "document", id, Some(&before), None::<&()>));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_document_changes(
+    State(state): State<AppState>,
+    Query(params): Query<ChangesQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let since = chrono::DateTime::parse_from_rfc3339(&params.since)
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+        .with_timezone(&chrono::Utc)
+        .format("%Y-%m-%d %H:%M:%S%.3f")
+        .to_string();
+
+    // Timestamps only have millisecond precision, so the cursor is inclusive:
+    // a write landing in the same millisecond as `now` is returned again next time
+    // rather than missed.
+    let now = chrono::Utc::now();
+
+    let documents = sqlx::query_as::<_, Document>(
+        "SELECT * FROM documents WHERE updated_at >= ? ORDER BY updated_at"
+    )
+    .bind(&since)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let deleted: Vec<i64> = sqlx::query_scalar(
+        "SELECT document_id FROM deleted_documents WHERE deleted_at >= ? ORDER BY deleted_at"
+    )
+    .bind(&since)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut body = json!({
+        "documents": documents,
+        "deleted_document_ids": deleted,
+        "now": now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+    });
+
+    if params.include_nodes {
+        let node_ids: Vec<i64> = sqlx::query_scalar(
+            "SELECT id FROM nodes WHERE updated_at >= ? ORDER BY id"
+        )
+        .bind(&since)
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let content_node_ids: Vec<i64> = sqlx::query_scalar(
+            "SELECT node_id FROM content WHERE updated_at >= ? ORDER BY node_id"
+        )
+        .bind(&since)
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        body["node_ids"] = json!(node_ids);
+        body["content_node_ids"] = json!(content_node_ids);
+    }
+
+    Ok(Json(body))
+}
+
+/// Nest a flat node list (already in display order) into a tree.
+/// Nodes whose parent is not in the list become roots.
+pub fn build_tree(nodes: Vec<Node>) -> Vec<NodeTree> {
+    let ids: std::collections::HashSet<i64> = nodes.iter().map(|n| n.id).collect();
+    let mut children: HashMap<Option<i64>, Vec<Node>> = HashMap::new();
+    for node in nodes {
+        let parent = node.parent_id.filter(|p| ids.contains(p) && *p != node.id);
+        children.entry(parent).or_default().push(node);
+    }
+
+    fn attach(parent: Option<i64>, children: &mut HashMap<Option<i64>, Vec<Node>>) -> Vec<NodeTree> {
+        children.remove(&parent)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|node| {
+                let id = node.id;
+                NodeTree { node, children: attach(Some(id), children) }
+            })
+            .collect()
+    }
+
+    attach(None, &mut children)
+}
+
+/// Insert a copy of `node` and its content under `parent_id` in `document_id`,
+/// returning the new node's id
+async fn copy_node(
+    tx: &mut sqlx::SqliteConnection,
+    node: &Node,
+    document_id: i64,
+    parent_id: Option<i64>,
+    order_index: f64,
+) -> Result<i64, StatusCode> {
+    // Sealed content is bound to its own document's key
+    if document_id != node.document_id {
+        let sealed: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM content WHERE node_id = ? AND content_json LIKE 'enc:%')"
+        )
+        .bind(node.id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if sealed {
+            return Err(StatusCode::CONFLICT);
+        }
+    }
+
+    let new_id = sqlx::query(
+        "INSERT INTO nodes (document_id, parent_id, node_type, title, order_index, indent_level, image_url, collapsed, attributes)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(document_id)
+    .bind(parent_id)
+    .bind(&node.node_type)
+    .bind(&node.title)
+    .bind(order_index)
+    .bind(node.indent_level)
+    .bind(&node.image_url)
+    .bind(node.collapsed)
+    .bind(&node.attributes)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .last_insert_rowid();
+
+    sqlx::query(
+        "INSERT INTO content (node_id, content_json, word_count) SELECT ?, content_json, word_count FROM content WHERE node_id = ?"
+    )
+    .bind(new_id)
+    .bind(node.id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query(
+        "INSERT INTO node_attachments (node_id, url, position, caption)
+         SELECT ?, url, position, caption FROM node_attachments WHERE node_id = ?"
+    )
+    .bind(new_id)
+    .bind(node.id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(new_id)
+}
+
+pub async fn merge_documents(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<MergeDocumentRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if payload.source_id == id {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut encrypted = Vec::new();
+    for doc_id in [id, payload.source_id] {
+        let doc_encrypted: bool = sqlx::query_scalar("SELECT encrypted FROM documents WHERE id = ?")
+            .bind(doc_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        encrypted.push(doc_encrypted);
+    }
+    // Sealed content is bound to its document's key, so it can't simply move
+    if encrypted.contains(&true) {
+        return Err(StatusCode::CONFLICT.into());
+    }
+
+    let source_nodes = sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index"
+    )
+    .bind(payload.source_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    check_node_quota(&mut tx, state.config.max_nodes_per_document, id, source_nodes.len()).await?;
+
+    // Shift the source's root ranks so they land entirely before or after the target's roots
+    let (target_min, target_max): (Option<f64>, Option<f64>) = sqlx::query_as(
+        "SELECT MIN(order_index), MAX(order_index) FROM nodes WHERE document_id = ? AND parent_id IS NULL"
+    )
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let source_ids: std::collections::HashSet<i64> = source_nodes.iter().map(|n| n.id).collect();
+    let is_root = |node: &Node| node.parent_id.is_none_or(|p| !source_ids.contains(&p));
+    let source_roots = source_nodes.iter().filter(|n| is_root(n)).map(|n| n.order_index);
+    let source_min = source_roots.clone().fold(f64::INFINITY, f64::min);
+    let source_max = source_roots.fold(f64::NEG_INFINITY, f64::max);
+
+    let root_offset = match (payload.position, target_min, target_max) {
+        (MergePosition::Append, _, Some(max)) => max + 1.0 - source_min,
+        (MergePosition::Prepend, Some(min), _) => min - 1.0 - source_max,
+        _ => 0.0,
+    };
+
+    // Insert parents before children so every copied parent_id can be remapped
+    let mut id_map: HashMap<i64, i64> = HashMap::new();
+    let mut pending: Vec<&Node> = source_nodes.iter().collect();
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<&Node>, Vec<&Node>) = pending.into_iter().partition(|node| {
+            is_root(node) || node.parent_id.is_some_and(|p| id_map.contains_key(&p))
+        });
+
+        // If nothing is ready the rest forms a parent cycle; copy it as roots rather than loop forever
+        let batch = if ready.is_empty() { waiting.clone() } else { ready };
+        for node in &batch {
+            let new_parent = node.parent_id.and_then(|p| id_map.get(&p).copied());
+            let order_index = if new_parent.is_none() { node.order_index + root_offset } else { node.order_index };
+
+            let new_id = copy_node(&mut tx, node, id, new_parent, order_index).await?;
+            id_map.insert(node.id, new_id);
+        }
+
+        let copied: std::collections::HashSet<i64> = batch.iter().map(|n| n.id).collect();
+        pending = waiting.into_iter().filter(|n| !copied.contains(&n.id)).collect();
+    }
+
+    if payload.delete_source {
+        sqlx::query("DELETE FROM documents WHERE id = ?")
+            .bind(payload.source_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        sqlx::query("INSERT OR REPLACE INTO deleted_documents (document_id) VALUES (?)")
+            .bind(payload.source_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    sqlx::query(
+        "UPDATE documents SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now'),
+         last_edited_by = ?, last_edited_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?"
+    )
+    .bind(&editor)
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    refresh_word_count(&mut tx, id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let nodes = sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index"
+    )
+    .bind(id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("merge", "document", id, None::<&()>, Some(&json!({
+        "source_id": payload.source_id,
+        "nodes_copied": id_map.len(),
+        "source_deleted": payload.delete_source
+    })));
+
+    Ok(Json(json!({
+        "document": document,
+        "tree": build_tree(nodes)
+    })))
+}
+
+// Oldest snapshots beyond this many per document are dropped
+const MAX_SNAPSHOTS_PER_DOCUMENT: i64 = 20;
+
+/// Store the current nodes, content and attachments of a document as a snapshot
+async fn capture_snapshot(
+    tx: &mut sqlx::SqliteConnection,
+    document_id: i64,
+    reason: &str,
+) -> Result<DocumentSnapshot, StatusCode> {
+    let mut nodes = sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index"
+    )
+    .bind(document_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let content: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT content.node_id, content.content_json FROM content
+         JOIN nodes ON nodes.id = content.node_id
+         WHERE nodes.document_id = ?"
+    )
+    .bind(document_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    for node in &mut nodes {
+        node.attachments = fetch_attachments(tx, node.id).await?;
+    }
+
+    let data = SnapshotData { nodes, content: content.into_iter().collect() };
+    let snapshot_json = serde_json::to_string(&data)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let snapshot_id = sqlx::query(
+        "INSERT INTO document_snapshots (document_id, reason, node_count, snapshot_json) VALUES (?, ?, ?, ?)"
+    )
+    .bind(document_id)
+    .bind(reason)
+    .bind(data.nodes.len() as i64)
+    .bind(snapshot_json)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .last_insert_rowid();
+
+    sqlx::query(
+        "DELETE FROM document_snapshots WHERE document_id = ? AND id NOT IN (
+             SELECT id FROM document_snapshots WHERE document_id = ? ORDER BY id DESC LIMIT ?
+         )"
+    )
+    .bind(document_id)
+    .bind(document_id)
+    .bind(MAX_SNAPSHOTS_PER_DOCUMENT)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query_as::<_, DocumentSnapshot>(
+        "SELECT id, document_id, reason, node_count, created_at FROM document_snapshots WHERE id = ?"
+    )
+    .bind(snapshot_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn create_snapshot(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<(StatusCode, Json<DocumentSnapshot>), StatusCode> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query("SELECT id FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let snapshot = capture_snapshot(&mut tx, id, "manual").await?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("snapshot", "document", id, None::<&()>, Some(&snapshot));
+
+    Ok((StatusCode::CREATED, Json(snapshot)))
+}
+
+pub async fn list_snapshots(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<DocumentSnapshot>>, StatusCode> {
+    sqlx::query("SELECT id FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let snapshots = sqlx::query_as::<_, DocumentSnapshot>(
+        "SELECT id, document_id, reason, node_count, created_at FROM document_snapshots
+         WHERE document_id = ? ORDER BY id DESC"
+    )
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(snapshots))
+}
+
+/// Replace a document's nodes and content with a snapshot's. The current
+/// state is snapshotted first, so a restore can itself be undone.
+pub async fn restore_snapshot(
+    State(state): State<AppState>,
+    Path((id, snapshot_id)): Path<(i64, i64)>,
+    Editor(editor): Editor,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let snapshot_json: String = sqlx::query_scalar(
+        "SELECT snapshot_json FROM document_snapshots WHERE id = ? AND document_id = ?"
+    )
+    .bind(snapshot_id)
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::NOT_FOUND)?;
+    let data: SnapshotData = serde_json::from_str(&snapshot_json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let backup = capture_snapshot(&mut tx, id, "pre-restore").await?;
+
+    // Content and attachments go with their nodes
+    sqlx::query("DELETE FROM nodes WHERE document_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Node ids are never reused, so the originals can be restored as they were.
+    // Parents are linked in a second pass so insertion order does not matter.
+    for node in &data.nodes {
+        sqlx::query(
+            "INSERT INTO nodes (id, document_id, parent_id, node_type, title, order_index, indent_level, image_url, collapsed, attributes, last_edited_by)
+             VALUES (?, ?, NULL, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(node.id)
+        .bind(id)
+        .bind(&node.node_type)
+        .bind(&node.title)
+        .bind(node.order_index)
+        .bind(node.indent_level)
+        .bind(&node.image_url)
+        .bind(node.collapsed)
+        .bind(&node.attributes)
+        .bind(&editor)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        if let Some(content_json) = data.content.get(&node.id) {
+            sqlx::query("INSERT INTO content (node_id, content_json, word_count) VALUES (?, ?, ?)")
+                .bind(node.id)
+                .bind(content_json)
+                .bind(stored_words(&state, id, content_json))
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+
+        for attachment in &node.attachments {
+            sqlx::query("INSERT INTO node_attachments (node_id, url, position, caption) VALUES (?, ?, ?, ?)")
+                .bind(node.id)
+                .bind(&attachment.url)
+                .bind(attachment.position)
+                .bind(&attachment.caption)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+    }
+
+    for node in data.nodes.iter().filter(|n| n.parent_id.is_some()) {
+        sqlx::query("UPDATE nodes SET parent_id = ? WHERE id = ?")
+            .bind(node.parent_id)
+            .bind(node.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    sqlx::query(
+        "UPDATE documents SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now'),
+         last_edited_by = ?, last_edited_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?"
+    )
+    .bind(&editor)
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    refresh_word_count(&mut tx, id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let nodes = sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index"
+    )
+    .bind(id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("restore", "document", id, None::<&()>, Some(&json!({
+        "snapshot_id": snapshot_id,
+        "backup_snapshot_id": backup.id,
+        "nodes_restored": data.nodes.len()
+    })));
+
+    Ok(Json(json!({
+        "document": document,
+        "backup_snapshot_id": backup.id,
+        "tree": build_tree(nodes)
+    })))
+}
+
+pub async fn get_document_toc(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<OutlineEntry>>, StatusCode> {
+    let order = node_order(&state, id).await?.ok_or(StatusCode::NOT_FOUND)?;
+
+    let nodes = sqlx::query_as::<_, Node>(&format!(
+        "SELECT * FROM nodes WHERE document_id = ? ORDER BY {}",
+        order
+    ))
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(crate::export::section_outline(build_tree(nodes))))
+}
+
+/// Report structural problems in a document's node tree without changing it
+pub async fn validate_tree(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<crate::tree::TreeReport>, StatusCode> {
+    sqlx::query("SELECT id FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let nodes = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE document_id = ?")
+        .bind(id)
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(crate::tree::analyze(&nodes).0))
+}
+
+/// Fix a document's node tree in one transaction: orphans and cycle members
+/// move to the root, sibling order is renumbered and indent levels follow depth
+pub async fn repair_tree(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query("SELECT id FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let nodes = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE document_id = ?")
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (report, placements) = crate::tree::analyze(&nodes);
+
+    let mut updated = 0;
+    let mut renumbered = 0;
+    for (node, placement) in nodes.iter().zip(&placements) {
+        if node.parent_id == placement.parent_id
+            && node.order_index == placement.order_index
+            && node.indent_level == placement.indent_level
+        {
+            continue;
+        }
+        if node.order_index != placement.order_index {
+            renumbered += 1;
+        }
+
+        sqlx::query(
+            "UPDATE nodes SET parent_id = ?, order_index = ?, indent_level = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')
+             WHERE id = ?"
+        )
+        .bind(placement.parent_id)
+        .bind(placement.order_index)
+        .bind(placement.indent_level)
+        .bind(placement.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        updated += 1;
+    }
+
+    if updated > 0 {
+        record_document_edit(&mut tx, id, editor.as_deref()).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let summary = json!({
+        "document_id": id,
+        "nodes_updated": updated,
+        "orphans_reparented": report.missing_parents.len(),
+        "cycles_broken": report.cycles.len(),
+        "nodes_renumbered": renumbered,
+        "indent_levels_fixed": report.indent_mismatches.len(),
+        "problems": report,
+    });
+
+    if updated > 0 {
+        state.audit.record("repair_tree", "document", id, None::<&()>, Some(&summary));
+    }
+
+    Ok(Json(summary))
+}
+
+/// Renumber order_index to 0, 1, 2, ... within every sibling group of a
+/// document, keeping the current order. Only nodes whose rank changes are written.
+pub async fn normalize_order(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    mut tx: Tx,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    sqlx::query("SELECT id FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let touched = sqlx::query(
+        "UPDATE nodes SET order_index = ranked.position, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')
+         FROM (
+             SELECT id, ROW_NUMBER() OVER (PARTITION BY parent_id ORDER BY order_index, id) - 1 AS position
+             FROM nodes WHERE document_id = ?
+         ) AS ranked
+         WHERE nodes.id = ranked.id AND nodes.order_index != ranked.position"
+    )
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .rows_affected();
+
+    if touched > 0 {
+        record_document_edit(&mut tx, id, editor.as_deref()).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        state.audit.record("normalize_order", "document", id, None::<&()>, Some(&json!({ "nodes_touched": touched })));
+    }
+
+    Ok(Json(json!({
+        "document_id": id,
+        "nodes_touched": touched,
+    })))
+}
+
+const EXPORT_STALE: HeaderName = HeaderName::from_static("x-export-stale");
+
+/// Whether a document may still be mid-edit: a save for one of its nodes is
+/// queued, or it was written within the settle window. Exports flag this with
+/// X-Export-Stale, or refuse with 409 when the client asked for a settled state.
+async fn export_staleness(state: &AppState, document_id: i64, require_settled: bool) -> Result<bool, StatusCode> {
+    let pending = serde_json::to_string(&state.autosave.pending_node_ids())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let stale: bool = sqlx::query_scalar(
+        "SELECT MAX(updated_at, COALESCE(last_edited_at, updated_at)) >= datetime('now', ?)
+             OR EXISTS(SELECT 1 FROM nodes WHERE document_id = documents.id AND id IN (SELECT value FROM json_each(?)))
+         FROM documents WHERE id = ?"
+    )
+    .bind(format!("-{} seconds", state.config.export_settle.as_secs()))
+    .bind(pending)
+    .bind(document_id)
+    .fetch_optional(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if stale && require_settled {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    Ok(stale)
+}
+
+pub async fn export_csv(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<ExportQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let stale = export_staleness(&state, id, params.require_settled).await?;
+
+    let nodes = sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index"
+    )
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["id", "parent_id", "node_type", "title", "order_index", "indent_level"])
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    for node in &nodes {
+        writer.write_record([
+            node.id.to_string(),
+            node.parent_id.map(|p| p.to_string()).unwrap_or_default(),
+            node.node_type.clone(),
+            node.title.clone(),
+            node.order_index.to_string(),
+            node.indent_level.to_string(),
+        ])
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let data = writer.into_inner()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"document-{}-nodes.csv\"", id)),
+            (EXPORT_STALE, stale.to_string()),
+        ],
+        data,
+    ))
+}
+
+// Columns a CSV outline import can't do without
+const CSV_IMPORT_REQUIRED_COLUMNS: &[&str] = &["id", "node_type", "title"];
+
+/// A CSV error without the position the caller already reports, naming the
+/// column rather than its index
+fn csv_problem(error: &csv::Error, headers: &csv::StringRecord) -> String {
+    match error.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => match err.field().and_then(|i| headers.get(i as usize)) {
+            Some(column) => format!("{}: {}", column, err.kind()),
+            None => err.kind().to_string(),
+        },
+        csv::ErrorKind::UnequalLengths { expected_len, len, .. } => {
+            format!("has {} fields, but the header has {}", len, expected_len)
+        }
+        _ => error.to_string(),
+    }
+}
+
+/// Add the nodes of a CSV outline to a document, in one transaction. Rows
+/// may come in any order; problems are reported with their line numbers
+/// and nothing is imported unless every row is valid.
+pub async fn import_csv(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    mut tx: Tx,
+    body: String,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    sqlx::query("SELECT id FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut errors = ValidationErrors::new();
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body.as_bytes());
+
+    let headers = reader.headers().cloned().unwrap_or_default();
+    let missing: Vec<&str> = CSV_IMPORT_REQUIRED_COLUMNS.iter()
+        .copied()
+        .filter(|column| !headers.iter().any(|h| h == *column))
+        .collect();
+    if !missing.is_empty() {
+        errors.add("csv", format!("header is missing column(s): {}", missing.join(", ")));
+        return Err(errors.into());
+    }
+
+    // Line numbers count the header as line 1, matching spreadsheet rows
+    let mut rows: Vec<(u64, CsvNodeRow)> = Vec::new();
+    let mut record = csv::StringRecord::new();
+    let mut any_records = false;
+    loop {
+        match reader.read_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) => {
+                any_records = true;
+                let line = record.position().map(|p| p.line()).unwrap_or_default();
+                match record.deserialize::<CsvNodeRow>(Some(&headers)) {
+                    Ok(row) => rows.push((line, row)),
+                    Err(e) => errors.add("csv", format!("line {}: {}", line, csv_problem(&e, &headers))),
+                }
+            }
+            Err(e) => {
+                any_records = true;
+                let line = e.position().map(|p| p.line()).unwrap_or_default();
+                errors.add("csv", format!("line {}: {}", line, csv_problem(&e, &headers)));
+                // A short or long row doesn't stop the rest being checked
+                if !matches!(e.kind(), csv::ErrorKind::UnequalLengths { .. }) {
+                    break;
+                }
+            }
+        }
+    }
+    if !any_records {
+        errors.add("csv", "must contain at least one row");
+    }
+
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, (line, row)) in rows.iter().enumerate() {
+        if row.id.is_empty() {
+            errors.add("id", format!("line {}: must not be empty", line));
+        } else if let Some(&first) = index.get(row.id.as_str()) {
+            errors.add("id", format!("line {}: duplicates the id on line {}", line, rows[first].0));
+        } else {
+            index.insert(&row.id, i);
+        }
+        if !NODE_TYPES.contains(&row.node_type.as_str()) {
+            errors.add("node_type", format!("line {}: must be one of: {}", line, NODE_TYPES.join(", ")));
+        }
+        if row.title.is_empty() {
+            errors.add("title", format!("line {}: must not be empty", line));
+        } else if row.title.chars().count() > MAX_TITLE_LEN {
+            errors.add("title", format!("line {}: must be at most {} characters", line, MAX_TITLE_LEN));
+        }
+        if row.order_index.is_some_and(|order_index| !order_index.is_finite()) {
+            errors.add("order_index", format!("line {}: must be a finite number", line));
+        }
+    }
+
+    let parent_of = |i: usize| rows[i].1.parent_id.as_deref().filter(|p| !p.is_empty());
+    for (i, (line, _)) in rows.iter().enumerate() {
+        if let Some(parent) = parent_of(i).filter(|p| !index.contains_key(p)) {
+            errors.add("parent_id", format!("line {}: parent {:?} is not in the file", line, parent));
+        }
+    }
+    errors.check()?;
+
+    // Order rows so parents come before their children, working out depths
+    // on the way; whatever can never be placed sits on a parent cycle
+    let max_depth = state.config.max_node_depth;
+    let mut depth: Vec<Option<i64>> = vec![None; rows.len()];
+    let mut order: Vec<usize> = Vec::with_capacity(rows.len());
+    let mut pending: Vec<usize> = (0..rows.len()).collect();
+    let mut errors = ValidationErrors::new();
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<usize>, Vec<usize>) = pending.into_iter().partition(|&i| {
+            parent_of(i).is_none_or(|p| depth[index[p]].is_some())
+        });
+        if ready.is_empty() {
+            for &i in &waiting {
+                errors.add("parent_id", format!("line {}: is part of a parent cycle", rows[i].0));
+            }
+            break;
+        }
+        for i in ready {
+            let d = parent_of(i).map(|p| depth[index[p]].unwrap_or_default() + 1).unwrap_or(0);
+            if d > max_depth {
+                errors.add("parent_id", format!(
+                    "line {}: nesting would exceed the maximum depth of {}", rows[i].0, max_depth
+                ));
+            }
+            depth[i] = Some(d);
+            order.push(i);
+        }
+        pending = waiting;
+    }
+    errors.check()?;
+    check_node_quota(&mut tx, state.config.max_nodes_per_document, id, rows.len()).await?;
+
+    // Rows without an order_index keep their order in the file
+    let mut siblings_seen: HashMap<Option<&str>, usize> = HashMap::new();
+    let positions: Vec<f64> = (0..rows.len()).map(|i| {
+        let seen = siblings_seen.entry(parent_of(i)).or_default();
+        *seen += 1;
+        rows[i].1.order_index.unwrap_or((*seen - 1) as f64)
+    }).collect();
+
+    // Imported roots go after the document's existing ones
+    let existing_max: Option<f64> = sqlx::query_scalar(
+        "SELECT MAX(order_index) FROM nodes WHERE document_id = ? AND parent_id IS NULL"
+    )
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let imported_min = (0..rows.len())
+        .filter(|&i| parent_of(i).is_none())
+        .map(|i| positions[i])
+        .fold(f64::INFINITY, f64::min);
+    let root_offset = existing_max.map(|max| max + 1.0 - imported_min).unwrap_or(0.0);
+
+    let mut id_map: HashMap<&str, i64> = HashMap::new();
+    for i in order {
+        let row = &rows[i].1;
+        let parent_id = parent_of(i).map(|p| id_map[p]);
+        let order_index = if parent_id.is_none() { positions[i] + root_offset } else { positions[i] };
+
+        let new_id = sqlx::query(
+            "INSERT INTO nodes (document_id, parent_id, node_type, title, order_index, indent_level, last_edited_by)
+             VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(id)
+        .bind(parent_id)
+        .bind(&row.node_type)
+        .bind(&row.title)
+        .bind(order_index)
+        .bind(row.indent_level.unwrap_or(depth[i].unwrap_or_default()).clamp(0, max_depth))
+        .bind(&editor)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .last_insert_rowid();
+        id_map.insert(&row.id, new_id);
+    }
+
+    sqlx::query(
+        "UPDATE documents SET updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now'),
+         last_edited_by = ?, last_edited_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?"
+    )
+    .bind(&editor)
+    .bind(id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let nodes = sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index"
+    )
+    .bind(id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("import_csv", "document", id, None::<&()>, Some(&json!({ "nodes_imported": id_map.len() })));
+
+    Ok(Json(json!({
+        "document": document,
+        "tree": build_tree(nodes),
+        "id_map": id_map,
+    })))
+}
+
+pub async fn export_latex(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<LatexExportQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let stale = export_staleness(&state, id, params.require_settled).await?;
+
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let nodes = sqlx::query_as::<_, Node>(&format!(
+        "{} WHERE nodes.document_id = ? ORDER BY nodes.order_index",
+        NODE_WITH_UPLOAD_SELECT
+    ))
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let content = document_content(&state, id, document.encrypted).await?;
+
+    let attachments = document_attachments(&state.read_db, id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let tex = crate::export::render_latex(
+        &document,
+        build_tree(nodes),
+        &content,
+        &attachments,
+        params.template.as_deref().unwrap_or("paper"),
+        params.figure_placement,
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/x-tex; charset=utf-8".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.tex\"", crate::export::slugify(&document.title)),
+            ),
+            (EXPORT_STALE, stale.to_string()),
+        ],
+        tex,
+    ))
+}
+
+/// Every equation node of a document in outline order, with its LaTeX and the
+/// title of its nearest enclosing section. JSON unless ?format=latex or an
+/// Accept header asking for TeX picks a single .tex file.
+pub async fn export_equations(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<EquationExportQuery>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, ApiError> {
+    let wants_tex = headers.get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/x-tex") || accept.contains("application/x-latex"));
+    let latex = match params.format.as_deref().map(str::trim) {
+        Some("latex") | Some("tex") => true,
+        Some("json") => false,
+        None | Some("") => wants_tex,
+        Some(_) => {
+            let mut errors = ValidationErrors::new();
+            errors.add("format", "must be json or latex");
+            return Err(errors.into());
+        }
+    };
+
+    let stale = export_staleness(&state, id, params.require_settled).await?;
+
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let nodes = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE document_id = ? ORDER BY order_index")
+        .bind(id)
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let content = document_content(&state, id, document.encrypted).await?;
+
+    fn walk(
+        trees: &[NodeTree],
+        section: Option<&str>,
+        content: &HashMap<i64, String>,
+        equations: &mut Vec<EquationExport>,
+    ) {
+        for tree in trees {
+            let node = &tree.node;
+            if node.node_type == "equation" {
+                equations.push(EquationExport {
+                    node_id: node.id,
+                    title: node.title.clone(),
+                    latex: content.get(&node.id).map(|json| text::plain_text(json)).unwrap_or_default(),
+                    format: content.get(&node.id)
+                        .map(|json| crate::math::content_format(json))
+                        .unwrap_or(crate::math::EquationFormat::Latex)
+                        .as_str(),
+                    section: section.map(str::to_string),
+                });
+            }
+            let section = if node.node_type == "section" { Some(node.title.as_str()) } else { section };
+            walk(&tree.children, section, content, equations);
+        }
+    }
+    let mut equations = Vec::new();
+    walk(&build_tree(nodes), None, &content, &mut equations);
+
+    // Without a format parameter the representation follows Accept, so
+    // caches must key on it either way
+    if !latex {
+        return Ok((
+            [(EXPORT_STALE, stale.to_string()), (header::VARY, "accept".to_string())],
+            Json(equations),
+        ).into_response());
+    }
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/x-tex; charset=utf-8".to_string()),
+            (header::VARY, "accept".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}-equations.tex\"", crate::export::slugify(&document.title)),
+            ),
+            (EXPORT_STALE, stale.to_string()),
+        ],
+        crate::export::render_equations(&document, &equations),
+    ).into_response())
+}
+
+const READING_PAGE_SIZE: i64 = 50;
+const MAX_READING_PAGE_SIZE: i64 = 200;
+
+/// A document flattened into reading order, depth first with siblings in
+/// display order, one rendered block per node, a page at a time
+pub async fn read_document(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<ReadingQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let page = params.page.unwrap_or(1);
+    let size = params.size.unwrap_or(READING_PAGE_SIZE);
+    let mut errors = ValidationErrors::new();
+    if page < 1 {
+        errors.add("page", "must be at least 1");
+    }
+    if !(1..=MAX_READING_PAGE_SIZE).contains(&size) {
+        errors.add("size", format!("must be between 1 and {}", MAX_READING_PAGE_SIZE));
+    }
+    errors.check()?;
+
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let order = node_order(&state, id).await?.unwrap_or(ChildSort::Manual.order_by());
+
+    let nodes = sqlx::query_as::<_, Node>(&format!(
+        "{} WHERE nodes.document_id = ? ORDER BY {}",
+        NODE_WITH_UPLOAD_SELECT, order
+    ))
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    fn flatten(trees: Vec<NodeTree>, depth: usize, out: &mut Vec<(Node, usize)>) {
+        for tree in trees {
+            out.push((tree.node, depth));
+            flatten(tree.children, depth + 1, out);
+        }
+    }
+    let mut flat = Vec::with_capacity(nodes.len());
+    flatten(build_tree(nodes), 0, &mut flat);
+
+    let total = flat.len() as i64;
+    let start = ((page - 1).saturating_mul(size)).min(total) as usize;
+    let page_nodes: Vec<(Node, usize)> = flat.into_iter().skip(start).take(size as usize).collect();
+
+    let content = document_content(&state, id, document.encrypted).await?;
+    let mut conn = state.read_db.acquire().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut blocks = Vec::with_capacity(page_nodes.len());
+    for (node, depth) in page_nodes {
+        let attachments = match node.node_type.as_str() {
+            "figure" => fetch_attachments(&mut conn, node.id).await?,
+            _ => Vec::new(),
+        };
+        let content_json = content.get(&node.id).map(String::as_str);
+        blocks.push(ReadingBlock {
+            html: crate::export::node_html(&node, content_json, &attachments),
+            text: content_json.map(text::plain_text).unwrap_or_default(),
+            node_id: node.id,
+            node_type: node.node_type,
+            title: node.title,
+            depth,
+        });
+    }
+
+    Ok(Json(json!({
+        "document_id": id,
+        "page": page,
+        "size": size,
+        "total_blocks": total,
+        "total_pages": (total + size - 1) / size,
+        "blocks": blocks,
+    })))
+}
+
+/// Approximate page count of a document's PDF export under a template,
+/// worked out from word, heading, figure and equation counts instead of a render
+pub async fn estimate_export(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<ExportEstimateQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let template = params.template.as_deref().unwrap_or("paper");
+    let Some(geometry) = crate::export::page_geometry(template) else {
+        let mut errors = ValidationErrors::new();
+        errors.add("template", "must be one of paper, report, resume");
+        return Err(errors.into());
+    };
+
+    sqlx::query("SELECT id FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // The cached per-node counts are kept for sealed content too, so nothing
+    // needs opening or parsing here
+    let nodes: Vec<(String, Option<i64>)> = sqlx::query_as(
+        "SELECT nodes.node_type, content.word_count FROM nodes
+         LEFT JOIN content ON content.node_id = nodes.id
+         WHERE nodes.document_id = ?"
+    )
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (mut word_count, mut heading_count, mut figure_count, mut equation_count) = (0, 0, 0, 0);
+    for (node_type, words) in &nodes {
+        match node_type.as_str() {
+            // Figures render their image and caption; their content is never shown
+            "figure" => figure_count += 1,
+            // An equation's text is LaTeX, not prose
+            "equation" => equation_count += 1,
+            _ => {
+                heading_count += 1;
+                word_count += words.unwrap_or(0).max(0) as usize;
+            }
+        }
+    }
+
+    Ok(Json(json!({
+        "template": template,
+        "estimated_pages": crate::export::estimate_pages(&geometry, word_count, heading_count, figure_count, equation_count),
+        "word_count": word_count,
+        "figure_count": figure_count,
+        "equation_count": equation_count,
+    })))
+}
+
+/// Package a document as an EPUB for e-readers
+pub async fn export_epub(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<EpubExportQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let stale = export_staleness(&state, id, params.require_settled).await?;
+
+    let document = sqlx::query_as::<_, Document>("SELECT * FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let mut nodes = sqlx::query_as::<_, Node>(&format!(
+        "{} WHERE nodes.document_id = ? ORDER BY nodes.order_index",
+        NODE_WITH_UPLOAD_SELECT
+    ))
+    .bind(id)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut attachments = document_attachments(&state.read_db, id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    for node in &mut nodes {
+        node.attachments = attachments.remove(&node.id).unwrap_or_default();
+    }
+
+    let content = document_content(&state, id, document.encrypted).await?;
+
+    let epub = crate::export::render_epub(
+        &document,
+        build_tree(nodes),
+        &content,
+        state.storage.as_ref(),
+        params.figure_placement,
+    )
+    .await
+    .map_err(|e| {
+        tracing::warn!("EPUB export of document {} failed: {}", id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/epub+zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.epub\"", crate::export::slugify(&document.title)),
+            ),
+            (EXPORT_STALE, stale.to_string()),
+        ],
+        epub,
+    ))
+}
+
+// Tag handlers
+
+// Longest tag accepted, in characters
+const MAX_TAG_LEN: usize = 100;
+
+fn validate_tag(tag: &str) -> Result<String, ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    let tag = tag.trim();
+    if tag.is_empty() {
+        errors.add("tag", "must not be empty");
+    } else if tag.chars().count() > MAX_TAG_LEN {
+        errors.add("tag", format!("must be at most {} characters", MAX_TAG_LEN));
+    }
+    errors.check().map(|_| tag.to_string())
+}
+
+pub async fn list_tagged_documents(
+    State(state): State<AppState>,
+    Path(tag): Path<String>,
+) -> Result<Json<Vec<Document>>, StatusCode> {
+    let documents = sqlx::query_as::<_, Document>(
+        "SELECT documents.* FROM documents JOIN document_tags ON document_tags.document_id = documents.id
+         WHERE document_tags.tag = ? ORDER BY documents.updated_at DESC"
+    )
+    .bind(tag.trim())
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(documents))
+}
+
+/// Attach (or, when `attach` is false, detach) a tag on many documents in one
+/// transaction. Ids that are not documents are reported rather than failing
+/// the whole request.
+async fn bulk_tag(
+    state: &AppState,
+    tag: &str,
+    document_ids: &[i64],
+    attach: bool,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let tag = validate_tag(tag)?;
+
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut succeeded = Vec::new();
+    let mut invalid = Vec::new();
+    for &document_id in document_ids {
+        let exists: Option<i64> = sqlx::query_scalar("SELECT id FROM documents WHERE id = ?")
+            .bind(document_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if exists.is_none() {
+            invalid.push(document_id);
+            continue;
+        }
+
+        let query = if attach {
+            "INSERT OR IGNORE INTO document_tags (document_id, tag) VALUES (?, ?)"
+        } else {
+            "DELETE FROM document_tags WHERE document_id = ? AND tag = ?"
+        };
+        sqlx::query(query)
+            .bind(document_id)
+            .bind(&tag)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        succeeded.push(document_id);
+    }
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let action = if attach { "tag" } else { "untag" };
+    for &document_id in &succeeded {
+        state.audit.record(action, "document", document_id, None::<&()>, Some(&json!({ "tag": tag })));
+    }
+
+    Ok(Json(json!({
+        "tag": tag,
+        "succeeded": succeeded,
+        "invalid": invalid
+    })))
+}
+
+pub async fn tag_documents(
+    State(state): State<AppState>,
+    Path(tag): Path<String>,
+    Json(payload): Json<TagDocumentsRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    bulk_tag(&state, &tag, &payload.document_ids, true).await
+}
+
+pub async fn untag_documents(
+    State(state): State<AppState>,
+    Path(tag): Path<String>,
+    Json(payload): Json<TagDocumentsRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    bulk_tag(&state, &tag, &payload.document_ids, false).await
+}
+
+// Node handlers
+
+// Node columns plus the alt text and display name of a figure's upload, when known
+const NODE_WITH_UPLOAD_SELECT: &str =
+    "SELECT nodes.*, uploads.alt_text AS image_alt_text, uploads.display_name AS image_display_name
+     FROM nodes LEFT JOIN uploads ON uploads.url = nodes.image_url";
+
+// Binds: path, path, value, path, value. No path matches everything; a path
+// alone matches nodes that have the key; with a value it must be equal.
+const ATTRIBUTE_FILTER: &str =
+    "(? IS NULL OR (json_type(nodes.attributes, ?) IS NOT NULL
+      AND (? IS NULL OR json_extract(nodes.attributes, ?) IS json_extract(?, '$'))))";
+
+// Length of the content preview in node listings, in characters
+const PREVIEW_CHARS: usize = 120;
+
+/// LIKE pattern matching `text` anywhere, with wildcards in it taken literally
+fn like_pattern(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// ORDER BY terms for a document's nodes under its child_sort setting, or
+/// None if the document doesn't exist
+async fn node_order(state: &AppState, document_id: i64) -> Result<Option<&'static str>, StatusCode> {
+    let child_sort: Option<Option<ChildSort>> = sqlx::query_scalar("SELECT child_sort FROM documents WHERE id = ?")
+        .bind(document_id)
+        .fetch_optional(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(child_sort.map(|sort| sort.unwrap_or(state.config.default_child_sort).order_by()))
+}
+
+pub async fn list_nodes(
+    State(state): State<AppState>,
+    Path(doc_id): Path<i64>,
+    Query(page): Query<Pagination>,
+    Query(search): Query<NodeListQuery>,
+    OriginalUri(uri): OriginalUri,
+) -> Result<(HeaderMap, Json<Vec<Node>>), ApiError> {
+    // SQLite's LIKE is case-insensitive for ASCII; the (document_id, order_index)
+    // index keeps the scan to one document, already in display order when
+    // sorting manually
+    let pattern = search.q
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(like_pattern);
+
+    // Attribute filter as a JSON path and a JSON literal; keys are limited to
+    // characters that need no escaping inside the path
+    let attr_path = match search.attr.as_deref().map(str::trim).filter(|key| !key.is_empty()) {
+        Some(key) if key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') => {
+            Some(format!("$.\"{}\"", key))
+        }
+        Some(_) => return Err(StatusCode::BAD_REQUEST.into()),
+        None => None,
+    };
+    let attr_value = search.attr_value.as_deref().map(|value| {
+        serde_json::from_str::<serde_json::Value>(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()))
+            .to_string()
+    });
+
+    let node_type = search.node_type.as_deref().map(str::trim).filter(|t| !t.is_empty());
+    if node_type.is_some_and(|t| !NODE_TYPES.contains(&t)) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    // Unknown documents list as empty, as before. Nodes of one type come
+    // from all over the tree, so they are listed by order_index alone.
+    let order = match node_type {
+        Some(_) => ChildSort::Manual.order_by(),
+        None => node_order(&state, doc_id).await?.unwrap_or(ChildSort::Manual.order_by()),
+    };
+
+    let mut nodes = traced("select", "nodes", sqlx::query_as::<_, Node>(&format!(
+        "{} WHERE nodes.document_id = ? AND (? IS NULL OR nodes.title LIKE ? ESCAPE '\\')
+         AND (? IS NULL OR nodes.node_type = ?) AND {}
+         ORDER BY {} LIMIT ? OFFSET ?",
+        NODE_WITH_UPLOAD_SELECT, ATTRIBUTE_FILTER, order
+    ))
+    .bind(doc_id)
+    .bind(&pattern)
+    .bind(&pattern)
+    .bind(node_type)
+    .bind(node_type)
+    .bind(&attr_path)
+    .bind(&attr_path)
+    .bind(&attr_value)
+    .bind(&attr_path)
+    .bind(&attr_value)
+    .bind(page.sql_limit())
+    .bind(page.sql_offset())
+    .fetch_all(&state.read_db))
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Content is only read for the nodes on this page, and only when asked for
+    if search.preview {
+        let node_ids = serde_json::to_string(&nodes.iter().map(|n| n.id).collect::<Vec<_>>())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let content: HashMap<i64, String> = traced("select", "content", sqlx::query_as::<_, (i64, String)>(
+            "SELECT node_id, content_json FROM content WHERE node_id IN (SELECT value FROM json_each(?))"
+        )
+        .bind(node_ids)
+        .fetch_all(&state.read_db))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .collect();
+
+        for node in &mut nodes {
+            let text = match content.get(&node.id) {
+                Some(stored) => text::plain_text(&crypto::reveal(state.content_cipher.as_deref(), doc_id, stored.clone())?),
+                None => String::new(),
+            };
+            node.preview = Some(text::preview(&text, PREVIEW_CHARS));
+        }
+    }
+
+    let headers = if page.is_paginated() {
+        let total: i64 = traced("count", "nodes", sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM nodes WHERE document_id = ? AND (? IS NULL OR title LIKE ? ESCAPE '\\')
+             AND (? IS NULL OR node_type = ?) AND {}",
+            ATTRIBUTE_FILTER
+        ))
+        .bind(doc_id)
+        .bind(&pattern)
+        .bind(&pattern)
+        .bind(node_type)
+        .bind(node_type)
+        .bind(&attr_path)
+        .bind(&attr_path)
+        .bind(&attr_value)
+        .bind(&attr_path)
+        .bind(&attr_value)
+        .fetch_optional(&state.read_db))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .unwrap_or(0);
+        page.headers(&uri, total)
+    } else {
+        HeaderMap::new()
+    };
+
+    Ok((headers, Json(nodes)))
+}
+
+/// Depth of a node in its tree (0 for roots). The walk is bounded so a parent
+/// cycle in bad data cannot make it recurse forever.
+async fn node_depth(conn: &mut sqlx::SqliteConnection, node_id: i64) -> Result<i64, StatusCode> {
+    sqlx::query_scalar(
+        "WITH RECURSIVE ancestors(id, parent_id, depth) AS (
+             SELECT id, parent_id, 0 FROM nodes WHERE id = ?
+             UNION ALL
+             SELECT nodes.id, nodes.parent_id, ancestors.depth + 1
+             FROM nodes JOIN ancestors ON nodes.id = ancestors.parent_id
+             WHERE ancestors.depth < 1000
+         )
+         SELECT MAX(depth) FROM ancestors"
+    )
+    .bind(node_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Number of levels below a node (0 for a leaf)
+async fn subtree_height(conn: &mut sqlx::SqliteConnection, node_id: i64) -> Result<i64, StatusCode> {
+    sqlx::query_scalar(
+        "WITH RECURSIVE subtree(id, depth) AS (
+             SELECT ?, 0
+             UNION ALL
+             SELECT nodes.id, subtree.depth + 1
+             FROM nodes JOIN subtree ON nodes.parent_id = subtree.id
+             WHERE subtree.depth < 1000
+         )
+         SELECT MAX(depth) FROM subtree"
+    )
+    .bind(node_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Refuse with 403 when adding `adding` nodes would take a document past
+/// MAX_NODES_PER_DOCUMENT
+async fn check_node_quota(
+    conn: &mut sqlx::SqliteConnection,
+    max_nodes: Option<i64>,
+    document_id: i64,
+    adding: usize,
+) -> Result<(), ApiError> {
+    let Some(max_nodes) = max_nodes else {
+        return Ok(());
+    };
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM nodes WHERE document_id = ?")
+        .bind(document_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if count + adding as i64 > max_nodes {
+        return Err(node_quota_reached(max_nodes));
+    }
+
+    Ok(())
+}
+
+fn node_quota_reached(max_nodes: i64) -> ApiError {
+    ApiError::Message(
+        StatusCode::FORBIDDEN,
+        format!("Node quota reached: at most {} nodes allowed per document", max_nodes),
+    )
+}
+
+/// Reject with 422 if putting `node_id`'s subtree (or a new leaf, when None)
+/// under `parent_id` would exceed the configured maximum depth
+async fn check_depth(
+    conn: &mut sqlx::SqliteConnection,
+    max_depth: i64,
+    parent_id: Option<i64>,
+    node_id: Option<i64>,
+) -> Result<(), StatusCode> {
+    let Some(parent_id) = parent_id else {
+        return Ok(());
+    };
+
+    let depth = node_depth(conn, parent_id).await? + 1;
+    let height = match node_id {
+        Some(node_id) => subtree_height(conn, node_id).await?,
+        None => 0,
+    };
+
+    if depth + height > max_depth {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    Ok(())
+}
+
+/// Whether nesting `node_id` under `parent_id` would make it its own
+/// ancestor: the parent is the node itself or one of its descendants.
+/// UNION rather than UNION ALL so a cycle already in the table ends the walk
+/// instead of looping forever.
+async fn creates_cycle(
+    conn: &mut sqlx::SqliteConnection,
+    node_id: i64,
+    parent_id: i64,
+) -> Result<bool, StatusCode> {
+    sqlx::query_scalar(
+        "WITH RECURSIVE subtree(id) AS (
+             SELECT ?
+             UNION
+             SELECT nodes.id FROM nodes JOIN subtree ON nodes.parent_id = subtree.id
+         )
+         SELECT EXISTS(SELECT 1 FROM subtree WHERE id = ?)"
+    )
+    .bind(node_id)
+    .bind(parent_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+const NODE_TYPES: &[&str] = &["section", "reference", "figure", "equation"];
+
+pub async fn create_node(
+    State(state): State<AppState>,
+    mut tx: Tx,
+    Query(params): Query<CreateNodeQuery>,
+    Editor(editor): Editor,
+    Json(payload): Json<CreateNodeRequest>,
+) -> Result<Json<Node>, ApiError> {
+    let mut errors = ValidationErrors::new();
+    validate_title(&mut errors, &payload.title);
+
+    if !NODE_TYPES.contains(&payload.node_type.as_str()) {
+        errors.add("node_type", format!("must be one of: {}", NODE_TYPES.join(", ")));
+    }
+    if payload.order_index.is_some_and(|order_index| !order_index.is_finite()) {
+        errors.add("order_index", "must be a finite number");
+    }
+    let attributes = payload.attributes.as_ref()
+        .and_then(|attributes| validate_attributes(&mut errors, attributes));
+
+    let document_encrypted: Option<bool> = sqlx::query_scalar("SELECT encrypted FROM documents WHERE id = ?")
+        .bind(payload.document_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if document_encrypted.is_none() {
+        errors.add("document_id", "document does not exist");
+    }
+
+    if let Some(parent_id) = payload.parent_id {
+        let parent_document: Option<i64> = sqlx::query_scalar("SELECT document_id FROM nodes WHERE id = ?")
+            .bind(parent_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        match parent_document {
+            None => errors.add("parent_id", "parent node does not exist"),
+            Some(doc_id) if doc_id != payload.document_id => {
+                errors.add("parent_id", "parent node belongs to another document")
+            }
+            Some(_) => match check_depth(&mut tx, state.config.max_node_depth, Some(parent_id), None).await {
+                Err(StatusCode::UNPROCESSABLE_ENTITY) => errors.add(
+                    "parent_id",
+                    format!("nesting would exceed the maximum depth of {}", state.config.max_node_depth),
+                ),
+                other => other?,
+            },
+        }
+    }
+    errors.check()?;
+
+    // Template content for an encrypted document has to be sealed, so make
+    // sure that's possible before the node exists
+    let seal_with = match document_encrypted {
+        Some(true) if params.seed_content => Some(cipher(&state)?),
+        _ => None,
+    };
+
+    // Like the document quota, the node quota is checked in the INSERT itself
+    let result = sqlx::query(
+        "INSERT INTO nodes (document_id, parent_id, node_type, title, order_index, indent_level, image_url, attributes, last_edited_by) 
+         SELECT ?, ?, ?, ?, COALESCE(?, (SELECT COALESCE(MAX(order_index), -1) + 1 FROM nodes WHERE document_id = ? AND parent_id IS ?)), ?, ?, ?, ?
+         WHERE (SELECT COUNT(*) FROM nodes WHERE document_id = ?) < ?"
+    )
+    .bind(payload.document_id)
+    .bind(payload.parent_id)
+    .bind(&payload.node_type)
+    .bind(&payload.title)
+    // Without an order_index the node goes after its last sibling. Working
+    // that out inside the INSERT keeps it atomic, so concurrent appends
+    // can't both pick the same slot.
+    .bind(payload.order_index)
+    .bind(payload.document_id)
+    .bind(payload.parent_id)
+    .bind(payload.indent_level.clamp(0, state.config.max_node_depth))
+    .bind(&payload.image_url)
+    .bind(attributes)
+    .bind(&editor)
+    .bind(payload.document_id)
+    .bind(state.config.max_nodes_per_document.unwrap_or(i64::MAX))
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(node_quota_reached(state.config.max_nodes_per_document.unwrap_or_default()));
+    }
+
+    if params.seed_content {
+        seed_node_content(&mut tx, seal_with, payload.document_id, result.last_insert_rowid(), &payload.node_type).await?;
+    }
+
+    record_document_edit(&mut tx, payload.document_id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(result.last_insert_rowid())
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("create", "node", node.id, None::<&()>, Some(&node));
+
+    Ok(Json(node))
+}
+
+/// Give a new node its type's template content, sealed with `seal_with`
+/// when its document is encrypted. Types without a template start empty as
+/// usual.
+async fn seed_node_content(
+    conn: &mut sqlx::SqliteConnection,
+    seal_with: Option<&crate::crypto::ContentCipher>,
+    document_id: i64,
+    node_id: i64,
+    node_type: &str,
+) -> Result<(), ApiError> {
+    let template: Option<String> = sqlx::query_scalar("SELECT content_json FROM node_content_templates WHERE node_type = ?")
+        .bind(node_type)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(template) = template else {
+        return Ok(());
+    };
+
+    let words = content_words(&template);
+    let content_json = match seal_with {
+        Some(cipher) => cipher.seal(document_id, &template)?,
+        None => template,
+    };
+
+    sqlx::query("INSERT INTO content (node_id, content_json) VALUES (?, ?)")
+        .bind(node_id)
+        .bind(content_json)
+        .execute(&mut *conn)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    set_word_count(conn, node_id, words).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(())
+}
+
+pub async fn get_node(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Node>, StatusCode> {
+    let mut node = traced("select", "nodes", sqlx::query_as::<_, Node>(&format!("{} WHERE nodes.id = ?", NODE_WITH_UPLOAD_SELECT))
+        .bind(id)
+        .fetch_optional(&state.read_db))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    node.attachments = traced("select", "node_attachments", sqlx::query_as::<_, Attachment>(
+        "SELECT * FROM node_attachments WHERE node_id = ? ORDER BY position, id"
+    )
+    .bind(id)
+    .fetch_all(&state.read_db))
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    node.unresolved_comments = Some(
+        sqlx::query_scalar("SELECT COUNT(*) FROM comments WHERE node_id = ? AND resolved = 0")
+            .bind(id)
+            .fetch_one(&state.read_db)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    );
+
+    Ok(Json(node))
+}
+
+pub async fn update_node(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    headers: HeaderMap,
+    mut tx: Tx,
+    Json(payload): Json<UpdateNodeRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let before = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut errors = ValidationErrors::new();
+    let attributes = payload.attributes.as_ref()
+        .and_then(|attributes| validate_attributes(&mut errors, attributes));
+    errors.check()?;
+
+    // A new parent must be another node of the same document, outside this node's subtree
+    if let (Some(parent_id), Some(node)) = (payload.parent_id, &before) {
+        let mut errors = ValidationErrors::new();
+
+        let parent_document: Option<i64> = sqlx::query_scalar("SELECT document_id FROM nodes WHERE id = ?")
+            .bind(parent_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        match parent_document {
+            None => errors.add("parent_id", "parent node does not exist"),
+            Some(doc_id) if doc_id != node.document_id => {
+                errors.add("parent_id", "parent node belongs to another document")
+            }
+            Some(_) => {
+                if creates_cycle(&mut tx, id, parent_id).await? {
+                    errors.add("parent_id", "node cannot be nested under itself or its descendants");
+                }
+            }
+        }
+        errors.check()?;
+    }
+
+    if let Some(title) = &payload.title {
+        sqlx::query("UPDATE nodes SET title = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+            .bind(title)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if let Some(order_index) = payload.order_index {
+        sqlx::query("UPDATE nodes SET order_index = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+            .bind(order_index)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if let Some(indent_level) = payload.indent_level {
+        sqlx::query("UPDATE nodes SET indent_level = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+            .bind(indent_level.clamp(0, state.config.max_node_depth))
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if let Some(parent_id) = payload.parent_id {
+        check_depth(&mut tx, state.config.max_node_depth, Some(parent_id), Some(id)).await?;
+
+        sqlx::query("UPDATE nodes SET parent_id = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+            .bind(parent_id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if let Some(collapsed) = payload.collapsed {
+        sqlx::query("UPDATE nodes SET collapsed = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+            .bind(collapsed)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if let Some(attributes) = attributes {
+        sqlx::query("UPDATE nodes SET attributes = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+            .bind(attributes)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    if before.is_some() {
+        record_node_edit(&mut tx, id, editor.as_deref()).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    state.audit.record("update", "node", id, before.as_ref(), Some(&node));
+
+    Ok(updated_response("node", before.as_ref(), &node, wants_changed_fields(&headers)))
+}
+
+/// Change a node's type, migrating its content to what the new type expects.
+/// Conversions that would discard content or images are refused with a 409
+/// unless `?force=true` is passed.
+pub async fn change_node_type(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<ChangeNodeTypeQuery>,
+    Editor(editor): Editor,
+    mut tx: Tx,
+    Json(payload): Json<ChangeNodeTypeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut errors = ValidationErrors::new();
+    if !NODE_TYPES.contains(&payload.node_type.as_str()) {
+        errors.add("node_type", format!("must be one of: {}", NODE_TYPES.join(", ")));
+    }
+    errors.check()?;
+
+    let before = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let stored: Option<String> = sqlx::query_scalar("SELECT content_json FROM content WHERE node_id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let current = match stored {
+        Some(sealed) if crypto::is_sealed(&sealed) => Some(cipher(&state)?.open(before.document_id, &sealed)?),
+        stored => stored,
+    };
+
+    let mut errors = ValidationErrors::new();
+    let blocks: Vec<serde_json::Value> = match &current {
+        Some(content_json) => serde_json::from_str(content_json).unwrap_or_else(|_| {
+            errors.add("content_json", "stored content is not a block list");
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    errors.check()?;
+
+    let attachment_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM node_attachments WHERE node_id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (blocks, content_lost) = migrate_content(&before.node_type, &payload.node_type, blocks);
+    let drops_images = before.node_type == "figure"
+        && payload.node_type != "figure"
+        && (before.image_url.is_some() || attachment_count > 0);
+
+    let lost: Vec<&str> = content_lost.into_iter()
+        .chain(drops_images.then_some("its images"))
+        .collect();
+    if !lost.is_empty() && !params.force {
+        return Err(ApiError::Message(
+            StatusCode::CONFLICT,
+            format!(
+                "Converting this {} to {} would discard {}; pass ?force=true to convert anyway",
+                before.node_type, payload.node_type, lost.join(" and "),
+            ),
+        ));
+    }
+
+    sqlx::query("UPDATE nodes SET node_type = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+        .bind(&payload.node_type)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if drops_images {
+        sqlx::query("UPDATE nodes SET image_url = NULL WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        sqlx::query("DELETE FROM node_attachments WHERE node_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let content_json = serde_json::Value::Array(blocks).to_string();
+    let content_changed = match &current {
+        Some(current) => serde_json::from_str::<serde_json::Value>(current).ok() != serde_json::from_str(&content_json).ok(),
+        None => content_json != "[]",
+    };
+    if content_changed {
+        let encrypted: bool = sqlx::query_scalar("SELECT encrypted FROM documents WHERE id = ?")
+            .bind(before.document_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let stored_json = if encrypted {
+            cipher(&state)?.seal(before.document_id, &content_json)?
+        } else {
+            content_json.clone()
+        };
+
+        sqlx::query(
+            "INSERT INTO content (node_id, content_json) VALUES (?, ?)
+             ON CONFLICT(node_id) DO UPDATE SET content_json = ?, version = version + 1, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')"
+        )
+        .bind(id)
+        .bind(&stored_json)
+        .bind(&stored_json)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        set_word_count(&mut tx, id, content_words(&content_json)).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    record_node_edit(&mut tx, id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut content = sqlx::query_as::<_, Content>("SELECT * FROM content WHERE node_id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(content) = &mut content {
+        content.content_json = content_json;
+    }
+
+    state.audit.record("change_type", "node", id, Some(&before), Some(&node));
+
+    Ok(Json(json!({
+        "node": node,
+        "content": content,
+    })))
+}
+
+/// Content for a node changing type from `from` to `to`, along with what the
+/// conversion discards, if anything
+fn migrate_content(
+    from: &str,
+    to: &str,
+    blocks: Vec<serde_json::Value>,
+) -> (Vec<serde_json::Value>, Option<&'static str>) {
+    if from == to {
+        return (blocks, None);
+    }
+
+    match (from, to) {
+        // Figures render their image and caption, never content
+        (_, "figure") => {
+            let lost = !text::blocks_text(&blocks).trim().is_empty();
+            (Vec::new(), lost.then_some("its text content"))
+        }
+        // An equation's LaTeX is the plain text of a single paragraph
+        (_, "equation") => {
+            let lost = !blocks.iter().all(is_plain_paragraph);
+            let latex = text::blocks_text(&blocks);
+            (paragraphs([latex.as_str()]), lost.then_some("its formatting"))
+        }
+        // Flattened to one paragraph per line of LaTeX
+        ("equation", _) => (paragraphs(text::blocks_text(&blocks).lines()), None),
+        // Sections and references share the same block content
+        _ => (blocks, None),
+    }
+}
+
+/// A paragraph block of unstyled text with nothing nested under it
+fn is_plain_paragraph(block: &serde_json::Value) -> bool {
+    let is_empty = |value: Option<&serde_json::Value>| match value {
+        None | Some(serde_json::Value::Null) => true,
+        Some(serde_json::Value::Array(items)) => items.is_empty(),
+        Some(serde_json::Value::Object(fields)) => fields.is_empty(),
+        Some(_) => false,
+    };
+
+    block.get("type").and_then(|t| t.as_str()) == Some("paragraph")
+        && is_empty(block.get("children"))
+        && block.get("content")
+            .and_then(|c| c.as_array())
+            .is_none_or(|runs| runs.iter().all(|run| {
+                run.get("type").and_then(|t| t.as_str()) == Some("text") && is_empty(run.get("styles"))
+            }))
+}
+
+/// BlockNote paragraph blocks for the non-blank lines
+fn paragraphs<'a>(lines: impl IntoIterator<Item = &'a str>) -> Vec<serde_json::Value> {
+    lines.into_iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| json!({
+            "type": "paragraph",
+            "props": {},
+            "content": [{ "type": "text", "text": line, "styles": {} }],
+            "children": []
+        }))
+        .collect()
+}
+
+pub async fn delete_node(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(params): Query<DeleteQuery>,
+    Editor(editor): Editor,
+    mut tx: Tx,
+) -> Result<StatusCode, StatusCode> {
+    let before = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let deleted = sqlx::query("DELETE FROM nodes WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .rows_affected();
+
+    // As with documents, only the request that removed the row records it
+    let Some(before) = before.filter(|_| deleted > 0) else {
+        return if params.ignore_missing { Ok(StatusCode::NO_CONTENT) } else { Err(StatusCode::NOT_FOUND) };
+    };
+
+    record_document_edit(&mut tx, before.document_id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Descendants' content went with the subtree
+    refresh_word_count(&mut tx, before.document_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let audit = state.audit.clone();
+    tx.after_commit(move || audit.record("delete", "node", id, Some(&before), None::<&()>));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Smallest gap between two sibling ranks before they get renumbered
+const MIN_RANK_GAP: f64 = 1e-9;
+
+/// Compute a rank strictly between two neighbours, or None when they are too
+/// close together and the sibling group needs rebalancing first.
+fn rank_between(prev: Option<f64>, next: Option<f64>) -> Option<f64> {
+    match (prev, next) {
+        (None, None) => Some(0.0),
+        (Some(prev), None) => Some(prev.floor() + 1.0),
+        (None, Some(next)) => Some(next.ceil() - 1.0),
+        (Some(prev), Some(next)) if next - prev > MIN_RANK_GAP => Some(prev + (next - prev) / 2.0),
+        _ => None,
+    }
+}
+
+/// Move a node into the sibling group under `parent_id`, positioned after
+/// `after_id` or before `before_id` (or at the end when neither is given).
+/// Only the moved node is written unless the group needs rebalancing.
+async fn place_among_siblings(
+    tx: &mut sqlx::SqliteConnection,
+    node: &Node,
+    parent_id: Option<i64>,
+    after_id: Option<i64>,
+    before_id: Option<i64>,
+) -> Result<(), StatusCode> {
+    let siblings = sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? AND parent_id IS ? AND id != ? ORDER BY order_index, id"
+    )
+    .bind(node.document_id)
+    .bind(parent_id)
+    .bind(node.id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let position_of = |sibling_id: i64| siblings.iter().position(|n| n.id == sibling_id);
+    let insert_at = match (after_id, before_id) {
+        (Some(after_id), _) => position_of(after_id).ok_or(StatusCode::UNPROCESSABLE_ENTITY)? + 1,
+        (None, Some(before_id)) => position_of(before_id).ok_or(StatusCode::UNPROCESSABLE_ENTITY)?,
+        (None, None) => siblings.len(),
+    };
+
+    let prev = insert_at.checked_sub(1).map(|i| siblings[i].order_index);
+    let next = siblings.get(insert_at).map(|n| n.order_index);
+
+    match rank_between(prev, next) {
+        Some(rank) => {
+            sqlx::query("UPDATE nodes SET parent_id = ?, order_index = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+                .bind(parent_id)
+                .bind(rank)
+                .bind(node.id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+        None => {
+            // Ranks are too dense to split further: renumber the whole group
+            sqlx::query("UPDATE nodes SET parent_id = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+                .bind(parent_id)
+                .bind(node.id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            let mut ordered: Vec<i64> = siblings.iter().map(|n| n.id).collect();
+            ordered.insert(insert_at, node.id);
+
+            for (index, node_id) in ordered.iter().enumerate() {
+                sqlx::query("UPDATE nodes SET order_index = ? WHERE id = ?")
+                    .bind(index as f64)
+                    .bind(node_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn reorder_node(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    mut tx: Tx,
+    Json(payload): Json<ReorderNodeRequest>,
+) -> Result<Json<Node>, StatusCode> {
+    let before = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    place_among_siblings(&mut tx, &before, before.parent_id, payload.after_id, payload.before_id).await?;
+    record_node_edit(&mut tx, id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("reorder", "node", id, Some(&before), Some(&node));
+
+    Ok(Json(node))
+}
+
+/// The nodes sharing a node's parent (the document's root nodes for a root
+/// node), in (order_index, id) order like move-up and move-down, with the
+/// node's own position among them
+pub async fn list_siblings(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let siblings = traced("select", "nodes", sqlx::query_as::<_, Node>(
+        "SELECT * FROM nodes WHERE document_id = ? AND parent_id IS ? ORDER BY order_index, id"
+    )
+    .bind(node.document_id)
+    .bind(node.parent_id)
+    .fetch_all(&state.read_db))
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let index = siblings.iter().position(|sibling| sibling.id == id);
+    let siblings: Vec<SiblingNode> = siblings.into_iter()
+        .map(|sibling| SiblingNode { current: sibling.id == id, node: sibling })
+        .collect();
+
+    Ok(Json(json!({
+        "node_id": id,
+        "index": index,
+        "count": siblings.len(),
+        "siblings": siblings,
+    })))
+}
+
+/// Swap a node with the sibling just above it. At the top of its group
+/// nothing changes and `sibling` is null.
+pub async fn move_node_up(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    tx: Tx,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    step_node(&state, tx, id, editor.as_deref(), true).await
+}
+
+/// Swap a node with the sibling just below it. At the bottom of its group
+/// nothing changes and `sibling` is null.
+pub async fn move_node_down(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    tx: Tx,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    step_node(&state, tx, id, editor.as_deref(), false).await
+}
+
+async fn step_node(
+    state: &AppState,
+    mut tx: Tx,
+    id: i64,
+    editor: Option<&str>,
+    up: bool,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let before = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // Adjacent in (order_index, id) order, the same tiebreak listings use
+    let query = if up {
+        "SELECT * FROM nodes WHERE document_id = ? AND parent_id IS ? AND id != ?
+           AND (order_index < ? OR (order_index = ? AND id < ?))
+         ORDER BY order_index DESC, id DESC LIMIT 1"
+    } else {
+        "SELECT * FROM nodes WHERE document_id = ? AND parent_id IS ? AND id != ?
+           AND (order_index > ? OR (order_index = ? AND id > ?))
+         ORDER BY order_index, id LIMIT 1"
+    };
+    let sibling = sqlx::query_as::<_, Node>(query)
+        .bind(before.document_id)
+        .bind(before.parent_id)
+        .bind(id)
+        .bind(before.order_index)
+        .bind(before.order_index)
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let Some(sibling) = sibling else {
+        return Ok(Json(json!({ "node": before, "sibling": null })));
+    };
+
+    // Swapping equal ranks would change nothing, so spread the group out
+    // first, keeping its (order_index, id) order
+    let (mine, theirs) = if sibling.order_index == before.order_index {
+        sqlx::query(
+            "UPDATE nodes SET order_index = ranked.position, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')
+             FROM (
+                 SELECT id, ROW_NUMBER() OVER (ORDER BY order_index, id) - 1 AS position
+                 FROM nodes WHERE document_id = ? AND parent_id IS ?
+             ) AS ranked
+             WHERE nodes.id = ranked.id AND nodes.order_index != ranked.position"
+        )
+        .bind(before.document_id)
+        .bind(before.parent_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let mut ranks = Vec::with_capacity(2);
+        for node_id in [id, sibling.id] {
+            ranks.push(
+                sqlx::query_scalar::<_, f64>("SELECT order_index FROM nodes WHERE id = ?")
+                    .bind(node_id)
+                    .fetch_one(&mut *tx)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            );
+        }
+        (ranks[0], ranks[1])
+    } else {
+        (before.order_index, sibling.order_index)
+    };
+
+    for (node_id, order_index) in [(id, theirs), (sibling.id, mine)] {
+        sqlx::query("UPDATE nodes SET order_index = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+            .bind(order_index)
+            .bind(node_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    record_node_edit(&mut tx, id, editor).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut moved = Vec::with_capacity(2);
+    for node_id in [id, sibling.id] {
+        moved.push(
+            sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+                .bind(node_id)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        );
+    }
+
+    state.audit.record("reorder", "node", id, Some(&before), Some(&moved[0]));
+
+    Ok(Json(json!({ "node": moved[0], "sibling": moved[1] })))
+}
+
+pub async fn place_node(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<PlaceNodeRequest>,
+) -> Result<Json<Node>, StatusCode> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let before = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let indent_level = match payload.parent_id {
+        Some(parent_id) => {
+            let parent = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+                .bind(parent_id)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .filter(|parent| parent.document_id == before.document_id)
+                .ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+
+            if creates_cycle(&mut tx, id, parent_id).await? {
+                return Err(StatusCode::UNPROCESSABLE_ENTITY);
+            }
+
+            check_depth(&mut tx, state.config.max_node_depth, Some(parent_id), Some(id)).await?;
+
+            parent.indent_level + 1
+        }
+        None => 0,
+    };
+
+    place_among_siblings(&mut tx, &before, payload.parent_id, payload.after_id, payload.before_id).await?;
+
+    // Shift the whole subtree so descendants keep their depth relative to the moved node
+    let delta = indent_level - before.indent_level;
+    if delta != 0 {
+        sqlx::query(
+            "WITH RECURSIVE subtree(id) AS (
+                 SELECT ?
+                 UNION ALL
+                 SELECT nodes.id FROM nodes JOIN subtree ON nodes.parent_id = subtree.id
+             )
+             UPDATE nodes SET indent_level = indent_level + ? WHERE id IN (SELECT id FROM subtree)"
+        )
+        .bind(id)
+        .bind(delta)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    record_node_edit(&mut tx, id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let node = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("place", "node", id, Some(&before), Some(&node));
+
+    Ok(Json(node))
+}
+
+// A node's subtree, parents before children
+const SUBTREE_SELECT: &str =
+    "WITH RECURSIVE subtree(id, depth) AS (
+         SELECT ?, 0
+         UNION ALL
+         SELECT nodes.id, subtree.depth + 1
+         FROM nodes JOIN subtree ON nodes.parent_id = subtree.id
+         WHERE subtree.depth < 1000
+     )
+     SELECT nodes.* FROM nodes JOIN subtree ON nodes.id = subtree.id
+     ORDER BY subtree.depth, nodes.order_index";
+
+/// Deep-copy a node and its descendants, placing the copy right after the original
+pub async fn duplicate_node(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+) -> Result<Json<NodeTree>, ApiError> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let subtree = sqlx::query_as::<_, Node>(SUBTREE_SELECT)
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let original = subtree.first().ok_or(StatusCode::NOT_FOUND)?;
+    check_node_quota(&mut tx, state.config.max_nodes_per_document, original.document_id, subtree.len()).await?;
+
+    let root_id = copy_node(&mut tx, original, original.document_id, original.parent_id, original.order_index).await?;
+    let root = sqlx::query_as::<_, Node>("SELECT * FROM nodes WHERE id = ?")
+        .bind(root_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    place_among_siblings(&mut tx, &root, original.parent_id, Some(original.id), None).await?;
+
+    let mut id_map: HashMap<i64, i64> = HashMap::from([(original.id, root_id)]);
+    for node in &subtree[1..] {
+        let parent_id = node.parent_id.and_then(|p| id_map.get(&p).copied());
+        let new_id = copy_node(&mut tx, node, node.document_id, parent_id, node.order_index).await?;
+        id_map.insert(node.id, new_id);
+    }
+
+    record_document_edit(&mut tx, original.document_id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    refresh_word_count(&mut tx, original.document_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let copies = sqlx::query_as::<_, Node>(SUBTREE_SELECT)
+        .bind(root_id)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("duplicate", "node", root_id, None::<&()>, Some(&json!({
+        "source_id": id,
+        "nodes_copied": id_map.len()
+    })));
+
+    Ok(build_tree(copies).into_iter().next()
+        .map(Json)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?)
+}
+
+// Attachment handlers
+
+/// Attachments of every node in a document, keyed by node id
+pub(crate) async fn document_attachments(
+    db: &sqlx::SqlitePool,
+    document_id: i64,
+) -> sqlx::Result<HashMap<i64, Vec<Attachment>>> {
+    let attachments = sqlx::query_as::<_, Attachment>(
+        "SELECT node_attachments.* FROM node_attachments
+         JOIN nodes ON nodes.id = node_attachments.node_id
+         WHERE nodes.document_id = ?
+         ORDER BY node_attachments.position, node_attachments.id"
+    )
+    .bind(document_id)
+    .fetch_all(db)
+    .await?;
+
+    let mut by_node: HashMap<i64, Vec<Attachment>> = HashMap::new();
+    for attachment in attachments {
+        by_node.entry(attachment.node_id).or_default().push(attachment);
+    }
+
+    Ok(by_node)
+}
+
+async fn fetch_attachments(
+    conn: &mut sqlx::SqliteConnection,
+    node_id: i64,
+) -> Result<Vec<Attachment>, StatusCode> {
+    sqlx::query_as::<_, Attachment>("SELECT * FROM node_attachments WHERE node_id = ? ORDER BY position, id")
+        .bind(node_id)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn list_attachments(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+) -> Result<Json<Vec<Attachment>>, StatusCode> {
+    let mut conn = state.read_db.acquire().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query("SELECT id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(Json(fetch_attachments(&mut conn, node_id).await?))
+}
+
+pub async fn add_attachment(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<CreateAttachmentRequest>,
+) -> Result<Json<Attachment>, ApiError> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query("SELECT id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let mut errors = ValidationErrors::new();
+    let uploaded: Option<i64> = sqlx::query_scalar("SELECT id FROM uploads WHERE url = ?")
+        .bind(&payload.url)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if uploaded.is_none() {
+        errors.add("url", "must be the url of an upload");
+    }
+    if payload.caption.as_ref().is_some_and(|c| c.chars().count() > MAX_TITLE_LEN) {
+        errors.add("caption", format!("must be at most {} characters", MAX_TITLE_LEN));
+    }
+    errors.check()?;
+
+    let result = sqlx::query(
+        "INSERT INTO node_attachments (node_id, url, position, caption)
+         VALUES (?, ?, (SELECT COALESCE(MAX(position) + 1, 0) FROM node_attachments WHERE node_id = ?), ?)"
+    )
+    .bind(node_id)
+    .bind(&payload.url)
+    .bind(node_id)
+    .bind(&payload.caption)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let attachment = sqlx::query_as::<_, Attachment>("SELECT * FROM node_attachments WHERE id = ?")
+        .bind(result.last_insert_rowid())
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    record_node_edit(&mut tx, node_id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("create", "attachment", attachment.id, None::<&()>, Some(&attachment));
+
+    Ok(Json(attachment))
+}
+
+pub async fn delete_attachment(
+    State(state): State<AppState>,
+    Path((node_id, attachment_id)): Path<(i64, i64)>,
+    Editor(editor): Editor,
+) -> Result<StatusCode, StatusCode> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let before = sqlx::query_as::<_, Attachment>("SELECT * FROM node_attachments WHERE id = ? AND node_id = ?")
+        .bind(attachment_id)
+        .bind(node_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Some(before) = &before {
+        sqlx::query("DELETE FROM node_attachments WHERE id = ?")
+            .bind(attachment_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        record_node_edit(&mut tx, node_id, editor.as_deref()).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        tx.commit().await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        state.audit.record("delete", "attachment", attachment_id, Some(before), None::<&()>);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reorder a node's attachments; the ids must list every attachment exactly once
+pub async fn reorder_attachments(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<ReorderAttachmentsRequest>,
+) -> Result<Json<Vec<Attachment>>, StatusCode> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query("SELECT id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let current = fetch_attachments(&mut tx, node_id).await?;
+    let mut current_ids: Vec<i64> = current.iter().map(|a| a.id).collect();
+    let mut requested_ids = payload.attachment_ids.clone();
+    current_ids.sort_unstable();
+    requested_ids.sort_unstable();
+    if current_ids != requested_ids {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    for (position, attachment_id) in payload.attachment_ids.iter().enumerate() {
+        sqlx::query("UPDATE node_attachments SET position = ? WHERE id = ?")
+            .bind(position as i64)
+            .bind(attachment_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    record_node_edit(&mut tx, node_id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let attachments = fetch_attachments(&mut tx, node_id).await?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("reorder", "attachment", node_id, Some(&current), Some(&attachments));
+
+    Ok(Json(attachments))
+}
+
+const MAX_COMMENT_LEN: usize = 10_000;
+
+/// A node's review comments, oldest first
+pub async fn list_comments(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Query(params): Query<CommentListQuery>,
+) -> Result<Json<Vec<Comment>>, StatusCode> {
+    sqlx::query("SELECT id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_optional(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let comments = sqlx::query_as::<_, Comment>(
+        "SELECT * FROM comments WHERE node_id = ? AND (? IS NULL OR resolved = ?) ORDER BY id"
+    )
+    .bind(node_id)
+    .bind(params.resolved)
+    .bind(params.resolved)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(comments))
+}
+
+/// Comment on a node. Comments don't touch the node itself, so they don't
+/// count as edits to it or its document.
+pub async fn add_comment(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<CreateCommentRequest>,
+) -> Result<(StatusCode, Json<Comment>), ApiError> {
+    let body = payload.body.trim();
+    let mut errors = ValidationErrors::new();
+    if body.is_empty() {
+        errors.add("body", "must not be empty");
+    } else if body.chars().count() > MAX_COMMENT_LEN {
+        errors.add("body", format!("must be at most {} characters", MAX_COMMENT_LEN));
+    }
+    errors.check()?;
+
+    // Checked in the INSERT so a node deleted meanwhile can't gain a comment
+    let result = sqlx::query(
+        "INSERT INTO comments (node_id, author, body) SELECT id, ?, ? FROM nodes WHERE id = ?"
+    )
+    .bind(&editor)
+    .bind(body)
+    .bind(node_id)
+    .execute(&state.db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+
+    let comment = sqlx::query_as::<_, Comment>("SELECT * FROM comments WHERE id = ?")
+        .bind(result.last_insert_rowid())
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("create", "comment", comment.id, None::<&()>, Some(&comment));
+
+    Ok((StatusCode::CREATED, Json(comment)))
+}
+
+/// Resolve or reopen a comment
+pub async fn update_comment(
+    State(state): State<AppState>,
+    Path((node_id, comment_id)): Path<(i64, i64)>,
+    Json(payload): Json<UpdateCommentRequest>,
+) -> Result<Json<Comment>, StatusCode> {
+    let before = sqlx::query_as::<_, Comment>("SELECT * FROM comments WHERE id = ? AND node_id = ?")
+        .bind(comment_id)
+        .bind(node_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    sqlx::query("UPDATE comments SET resolved = ?, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?")
+        .bind(payload.resolved)
+        .bind(comment_id)
+        .execute(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let comment = sqlx::query_as::<_, Comment>("SELECT * FROM comments WHERE id = ?")
+        .bind(comment_id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record(if payload.resolved { "resolve" } else { "reopen" }, "comment", comment_id, Some(&before), Some(&comment));
+
+    Ok(Json(comment))
+}
+
+pub async fn delete_comment(
+    State(state): State<AppState>,
+    Path((node_id, comment_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, StatusCode> {
+    let before = sqlx::query_as::<_, Comment>("SELECT * FROM comments WHERE id = ? AND node_id = ?")
+        .bind(comment_id)
+        .bind(node_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    sqlx::query("DELETE FROM comments WHERE id = ?")
+        .bind(comment_id)
+        .execute(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("delete", "comment", comment_id, Some(&before), None::<&()>);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Content handlers
+pub async fn get_content(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Query(range): Query<ContentRangeQuery>,
+    Editor(editor): Editor,
+) -> Result<Json<Content>, ApiError> {
+    let mut content = traced("select", "content", sqlx::query_as::<_, Content>("SELECT * FROM content WHERE node_id = ?")
+        .bind(node_id)
+        .fetch_optional(&state.read_db))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    content.content_json = reveal_content(&state, node_id, content.content_json).await?;
+    content.editor_state = load_editor_state(&state, node_id, editor.as_deref()).await?
+        .map(|saved| saved.state.0);
+
+    if range.offset.is_some() || range.length.is_some() {
+        let (blocks, total) = content_range(&content.content_json, &range)?;
+        content.content_json = blocks;
+        content.total_blocks = Some(total);
+    }
+
+    Ok(Json(content))
+}
+
+/// The requested slice of a content_json array of blocks, and the array's length
+fn content_range(content_json: &str, range: &ContentRangeQuery) -> Result<(String, usize), ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    let Ok(blocks) = serde_json::from_str::<Vec<serde_json::Value>>(content_json) else {
+        errors.add("offset", "ranges need content that is an array of blocks");
+        return Err(errors);
+    };
+
+    let total = blocks.len();
+    let offset = range.offset.unwrap_or(0);
+    if offset > total {
+        errors.add("offset", format!("must be at most the block count, {}", total));
+    }
+    if range.length == Some(0) {
+        errors.add("length", "must be at least 1");
+    }
+    errors.check()?;
+
+    let end = range.length.map_or(total, |length| offset.saturating_add(length).min(total));
+    let slice = serde_json::to_string(&blocks[offset..end]).unwrap_or_default();
+    Ok((slice, total))
+}
+
+pub async fn get_content_text(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    sqlx::query("SELECT id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let content_json: Option<String> = sqlx::query_scalar("SELECT content_json FROM content WHERE node_id = ?")
+        .bind(node_id)
+        .fetch_optional(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let text = match content_json {
+        Some(stored) => text::plain_text(&reveal_content(&state, node_id, stored).await?),
+        None => String::new(),
+    };
+
+    Ok(Json(json!({
+        "node_id": node_id,
+        "word_count": text::word_count(&text),
+        "text": text,
+    })))
+}
+
+/// Where the requesting editor left off in a node
+pub async fn get_editor_state(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Editor(editor): Editor,
+) -> Result<Json<EditorState>, StatusCode> {
+    load_editor_state(&state, node_id, editor.as_deref()).await?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Remember the requesting editor's cursor/selection in a node. Clients send
+/// this often and don't wait on it, so the write happens after the response.
+pub async fn save_editor_state(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<serde_json::Value>,
+) -> Result<StatusCode, ApiError> {
+    let payload = payload.to_string();
+    let mut errors = ValidationErrors::new();
+    if payload.len() > state.config.editor_state_max_bytes {
+        errors.add("state", format!("must be at most {} bytes", state.config.editor_state_max_bytes));
+    }
+    errors.check()?;
+
+    sqlx::query("SELECT id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let db = state.db.clone();
+    tokio::spawn(async move {
+        // The node may be deleted in the meantime, hence the SELECT
+        let result = sqlx::query(
+            "INSERT INTO editor_state (editor, node_id, state)
+             SELECT ?, id, ? FROM nodes WHERE id = ?
+             ON CONFLICT (editor, node_id) DO UPDATE SET state = excluded.state, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')"
+        )
+        .bind(editor.unwrap_or_default())
+        .bind(payload)
+        .bind(node_id)
+        .execute(&db)
+        .await;
+
+        if let Err(e) = result {
+            tracing::warn!("Failed to save editor state for node {}: {}", node_id, e);
+        }
+    });
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Saved state that hasn't expired yet; editors without a name share ''
+async fn load_editor_state(
+    state: &AppState,
+    node_id: i64,
+    editor: Option<&str>,
+) -> Result<Option<EditorState>, StatusCode> {
+    sqlx::query_as::<_, EditorState>(
+        "SELECT node_id, state, updated_at FROM editor_state
+         WHERE editor = ? AND node_id = ? AND updated_at >= datetime('now', ?)"
+    )
+    .bind(editor.unwrap_or_default())
+    .bind(node_id)
+    .bind(format!("-{} seconds", state.config.editor_state_ttl.as_secs()))
+    .fetch_optional(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn save_content(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Query(params): Query<SaveContentQuery>,
+    Editor(editor): Editor,
+    Json(payload): Json<SaveContentRequest>,
+) -> Result<Json<Content>, ApiError> {
+    check_content_depth(&payload.content_json, state.config.max_content_depth)?;
+    check_equations(&payload.content_json)?;
+
+    // Before sealing, so equal content also seals from equal plaintext
+    let content_json = match state.config.normalize_content {
+        true => text::normalize_json(&payload.content_json),
+        false => payload.content_json,
+    };
+    let words = content_words(&content_json);
+    let content_json = match encrypted_document(&state.db, node_id).await? {
+        Some(document_id) => cipher(&state)?.seal(document_id, &content_json)?,
+        None => content_json,
+    };
+
+    let content = if params.coalesce {
+        state.autosave.save(node_id, content_json, words, editor).await
+    } else {
+        state.autosave.save_now(node_id, content_json, words, editor).await
+    };
+
+    let mut content = content.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    content.content_json = reveal_content(&state, node_id, content.content_json).await?;
+
+    Ok(Json(content))
+}
+
+/// The id of the node's document when that document is encrypted at rest
+async fn encrypted_document(conn: impl sqlx::SqliteExecutor<'_>, node_id: i64) -> Result<Option<i64>, StatusCode> {
+    traced("select", "documents", sqlx::query_scalar(
+        "SELECT d.id FROM nodes n JOIN documents d ON d.id = n.document_id WHERE n.id = ? AND d.encrypted = 1"
+    )
+    .bind(node_id)
+    .fetch_optional(conn))
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn cipher(state: &AppState) -> Result<&crate::crypto::ContentCipher, CryptoError> {
+    state.content_cipher.as_deref().ok_or(CryptoError::NotConfigured)
+}
+
+/// What restoring a node's content from a snapshot would change, without
+/// changing anything: the current and snapshotted content, and a word diff
+/// of their text. Snapshots are the content history this server keeps, so
+/// the version is a snapshot of the node's document.
+pub async fn restore_preview(
+    State(state): State<AppState>,
+    Path((node_id, version_id)): Path<(i64, i64)>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (document_id, encrypted): (i64, bool) = sqlx::query_as(
+        "SELECT documents.id, documents.encrypted FROM nodes
+         JOIN documents ON documents.id = nodes.document_id WHERE nodes.id = ?"
+    )
+    .bind(node_id)
+    .fetch_optional(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let snapshot = sqlx::query_as::<_, DocumentSnapshot>(
+        "SELECT id, document_id, reason, node_count, created_at FROM document_snapshots WHERE id = ? AND document_id = ?"
+    )
+    .bind(version_id)
+    .bind(document_id)
+    .fetch_optional(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+    let snapshot_json: String = sqlx::query_scalar("SELECT snapshot_json FROM document_snapshots WHERE id = ?")
+        .bind(version_id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut data: SnapshotData = serde_json::from_str(&snapshot_json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The node may have been created after the snapshot was taken
+    if !data.nodes.iter().any(|node| node.id == node_id) {
+        return Err(ApiError::Message(
+            StatusCode::NOT_FOUND,
+            format!("Node {} is not in snapshot {}", node_id, version_id),
+        ));
+    }
+
+    let current: Option<(String, i64)> = sqlx::query_as("SELECT content_json, version FROM content WHERE node_id = ?")
+        .bind(node_id)
+        .fetch_optional(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (current_json, current_version) = current.unwrap_or_else(|| ("[]".to_string(), 0));
+    let target_json = data.content.remove(&node_id).unwrap_or_else(|| "[]".to_string());
+
+    let reveal = |stored: String| -> Result<String, ApiError> {
+        if encrypted && crypto::is_sealed(&stored) {
+            Ok(cipher(&state)?.open(document_id, &stored)?)
+        } else {
+            Ok(stored)
+        }
+    };
+    let current_json = reveal(current_json)?;
+    let target_json = reveal(target_json)?;
+
+    let current_text = text::plain_text(&current_json);
+    let target_text = text::plain_text(&target_json);
+    let changes = crate::diff::word_diff(&current_text, &target_text);
+
+    Ok(Json(json!({
+        "node_id": node_id,
+        "snapshot": snapshot,
+        "current": {
+            "content_json": current_json,
+            "text": current_text,
+            "version": current_version,
+        },
+        "target": {
+            "content_json": target_json,
+            "text": target_text,
+        },
+        "changed": current_json != target_json,
+        "diff": changes,
+    })))
+}
+
+/// Stored content_json as plain JSON, decrypting it if it was sealed
+async fn reveal_content(state: &AppState, node_id: i64, stored: String) -> Result<String, ApiError> {
+    if !crypto::is_sealed(&stored) {
+        return Ok(stored);
+    }
+    let document_id: i64 = sqlx::query_scalar("SELECT document_id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(cipher(state)?.open(document_id, &stored)?)
+}
+
+/// Reject content nested deeper than `max_depth` with a 422
+fn check_content_depth(content_json: &str, max_depth: usize) -> Result<(), ValidationErrors> {
+    let mut errors = ValidationErrors::new();
+    if text::json_depth(content_json) > max_depth {
+        errors.add("content_json", format!("must not be nested more than {} levels deep", max_depth));
+    }
+    errors.check()
+}
+
+/// Equation blocks must hold valid source for their format (latex or mathml)
+fn check_equations(content_json: &str) -> Result<(), ValidationErrors> {
+    let blocks: Vec<serde_json::Value> = serde_json::from_str(content_json).unwrap_or_default();
+    let mut errors = ValidationErrors::new();
+    if let Err(message) = crate::math::validate_blocks(&blocks) {
+        errors.add("content_json", message);
+    }
+    errors.check()
+}
+
+/// Apply a batch of incremental edits to a node's content in one transaction
+pub async fn apply_content_ops(
+    State(state): State<AppState>,
+    Path(node_id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<ContentOpsRequest>,
+) -> Result<Json<Content>, ApiError> {
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let document_id: i64 = sqlx::query_scalar("SELECT document_id FROM nodes WHERE id = ?")
+        .bind(node_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let encrypted = encrypted_document(&mut *tx, node_id).await?.is_some();
+
+    let current = sqlx::query_as::<_, Content>("SELECT * FROM content WHERE node_id = ?")
+        .bind(node_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let version = current.as_ref().map(|c| c.version).unwrap_or(0);
+    if payload.base_version.is_some_and(|base| base != version) {
+        return Err(StatusCode::CONFLICT.into());
+    }
+
+    let stored = match current {
+        Some(content) if crypto::is_sealed(&content.content_json) => {
+            Some(cipher(&state)?.open(document_id, &content.content_json)?)
+        }
+        current => current.map(|content| content.content_json),
+    };
+
+    let mut errors = ValidationErrors::new();
+    let mut blocks: Vec<serde_json::Value> = match &stored {
+        Some(content_json) => serde_json::from_str(content_json).unwrap_or_else(|_| {
+            errors.add("content_json", "stored content is not a block list");
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    if let Err(message) = crate::ops::apply(&mut blocks, &payload.ops) {
+        errors.add("ops", message);
+    }
+    errors.check()?;
+
+    // Inserted blocks can push already-deep content past the limit
+    let content_json = serde_json::Value::Array(blocks).to_string();
+    check_content_depth(&content_json, state.config.max_content_depth)?;
+    check_equations(&content_json)?;
+    let stored_json = if encrypted {
+        cipher(&state)?.seal(document_id, &content_json)?
+    } else {
+        content_json.clone()
+    };
+
+    sqlx::query(
+        "INSERT INTO content (node_id, content_json) VALUES (?, ?)
+         ON CONFLICT(node_id) DO UPDATE SET content_json = ?, version = version + 1, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now')"
+    )
+    .bind(node_id)
+    .bind(&stored_json)
+    .bind(&stored_json)
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    set_word_count(&mut tx, node_id, content_words(&content_json)).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    record_node_edit(&mut tx, node_id, editor.as_deref()).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut content = sqlx::query_as::<_, Content>("SELECT * FROM content WHERE node_id = ?")
+        .bind(node_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    content.content_json = content_json;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("ops", "content", node_id, None::<&()>, Some(&json!({
+        "operations": payload.ops.len(),
+        "version": content.version
+    })));
+
+    Ok(Json(content))
+}
+
+/// Replace every occurrence of `find` in `haystack`, returning the new string and match count
+fn replace_matches(haystack: &str, find: &str, replace: &str, case_sensitive: bool) -> (String, usize) {
+    if case_sensitive {
+        let count = haystack.matches(find).count();
+        return (haystack.replace(find, replace), count);
+    }
+
+    let needle: Vec<char> = find.chars().flat_map(char::to_lowercase).collect();
+    let mut out = String::with_capacity(haystack.len());
+    let mut count = 0;
+    let mut rest = haystack;
+
+    while !rest.is_empty() {
+        // Walk chars from the current position, lowercasing as we go, until the needle is consumed
+        let mut matched = 0;
+        let mut end = None;
+        for (offset, c) in rest.char_indices() {
+            for lower in c.to_lowercase() {
+                if needle.get(matched) != Some(&lower) {
+                    matched = usize::MAX;
+                    break;
+                }
+                matched += 1;
+            }
+            if matched == usize::MAX {
+                break;
+            }
+            if matched == needle.len() {
+                end = Some(offset + c.len_utf8());
+                break;
+            }
+        }
+
+        match end {
+            Some(end) => {
+                out.push_str(replace);
+                rest = &rest[end..];
+                count += 1;
+            }
+            None => {
+                let c = rest.chars().next().expect("rest is not empty");
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+
+    (out, count)
+}
+
+/// Apply a replacement to the "text" strings of a content tree, leaving keys and other values alone
+fn replace_in_text_fields(value: &mut serde_json::Value, payload: &ReplaceTextRequest) -> usize {
+    match value {
+        serde_json::Value::Object(map) => map
+            .iter_mut()
+            .map(|(key, child)| match child {
+                serde_json::Value::String(text) if key == "text" => {
+                    let (replaced, count) = replace_matches(text, &payload.find, &payload.replace, payload.case_sensitive);
+                    if count > 0 {
+                        *text = replaced;
+                    }
+                    count
+                }
+                _ => replace_in_text_fields(child, payload),
+            })
+            .sum(),
+        serde_json::Value::Array(items) => items
+            .iter_mut()
+            .map(|item| replace_in_text_fields(item, payload))
+            .sum(),
+        _ => 0,
+    }
+}
+
+pub async fn replace_text(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Editor(editor): Editor,
+    Json(payload): Json<ReplaceTextRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if payload.find.is_empty() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let encrypted: bool = sqlx::query_scalar("SELECT encrypted FROM documents WHERE id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let rows = sqlx::query_as::<_, Content>(
+        "SELECT content.* FROM content JOIN nodes ON content.node_id = nodes.id
+         WHERE nodes.document_id = ? ORDER BY nodes.order_index"
+    )
+    .bind(id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut per_node = Vec::new();
+    let mut total = 0;
+
+    for row in rows {
+        let plain = crypto::reveal(state.content_cipher.as_deref(), id, row.content_json)?;
+        // Content that is not valid JSON has no text fields we can safely touch
+        let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&plain) else {
+            continue;
+        };
+
+        let count = replace_in_text_fields(&mut value, &payload);
+        if count == 0 {
+            continue;
+        }
+
+        if !payload.dry_run {
+            let content_json = value.to_string();
+            let stored_json = if encrypted {
+                cipher(&state)?.seal(id, &content_json)?
+            } else {
+                content_json.clone()
+            };
+            sqlx::query("UPDATE content SET content_json = ?, version = version + 1, updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE node_id = ?")
+                .bind(&stored_json)
+                .bind(row.node_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            set_word_count(&mut tx, row.node_id, content_words(&content_json)).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            record_node_edit(&mut tx, row.node_id, editor.as_deref()).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+
+        total += count;
+        per_node.push(json!({ "node_id": row.node_id, "replacements": count }));
+    }
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !payload.dry_run {
+        for entry in &per_node {
+            if let Some(node_id) = entry["node_id"].as_i64() {
+                state.audit.record("replace", "content", node_id, None::<&()>, Some(entry));
+            }
+        }
+    }
+
+    Ok(Json(json!({
+        "dry_run": payload.dry_run,
+        "total_replacements": total,
+        "nodes": per_node
+    })))
+}
+
+// File validation constants
+const ALLOWED_EXTENSIONS: &[&str] = &[".jpg", ".jpeg", ".png", ".gif", ".webp", ".svg"];
+
+// Magic number signatures for image files
+fn verify_image_magic_number(data: &[u8], extension: &str) -> bool {
+    if data.len() < 4 {
+        return false;
+    }
+    
+    match extension {
         ".jpg" | ".jpeg" => {
             // JPEG: FF D8 FF
             data.len() >= 3 && data[0] == 0xFF && data[1] == 0xD8 && data[2] == 0xFF
@@ -283,132 +4269,1032 @@ fn verify_image_magic_number(data: &[u8], extension: &str) -> bool {
                 && data[2] == 0x46
                 && data[3] == 0x38
         }
-        ".webp" => {
-            // WebP: RIFF header (52 49 46 46) followed by WEBP
-            data.len() >= 12
-                && data[0] == 0x52
-                && data[1] == 0x49
-                && data[2] == 0x46
-                && data[3] == 0x46
-                && &data[8..12] == b"WEBP"
+        ".webp" => {
+            // WebP: RIFF header (52 49 46 46) followed by WEBP
+            data.len() >= 12
+                && data[0] == 0x52
+                && data[1] == 0x49
+                && data[2] == 0x46
+                && data[3] == 0x46
+                && &data[8..12] == b"WEBP"
+        }
+        _ => false,
+    }
+}
+
+/// Sanitize filename to prevent path traversal attacks
+fn sanitize_filename(filename: &str) -> String {
+    use std::path::Path;
+    
+    // Get only the basename (remove any path components)
+    let basename = Path::new(filename)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown");
+    
+    // Remove any non-alphanumeric characters except dots, hyphens, and underscores
+    let sanitized: String = basename
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    
+    // Limit filename length
+    const MAX_LENGTH: usize = 255;
+    if sanitized.len() > MAX_LENGTH {
+        if let Some(dot_pos) = sanitized.rfind('.') {
+            let ext = &sanitized[dot_pos..];
+            let name = &sanitized[..dot_pos.min(MAX_LENGTH - ext.len())];
+            format!("{}{}", name, ext)
+        } else {
+            sanitized.chars().take(MAX_LENGTH).collect()
+        }
+    } else {
+        sanitized
+    }
+}
+
+// File upload handler. The first field with a filename is the file; text
+// fields alongside it can carry the upload's alt text and display name.
+pub async fn upload_file(
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut file = None;
+    let mut alt_text = None;
+    let mut display_name = None;
+
+    while let Some(field) = multipart.next_field().await
+        .map_err(|e| e.status())?
+    {
+        match (field.file_name().map(str::to_string), field.name()) {
+            (Some(original_name), _) if file.is_none() => {
+                let data = read_field(field, state.config.max_upload_bytes).await?;
+                file = Some((original_name, data));
+            }
+            (None, Some("alt_text" | "alt")) => {
+                alt_text = Some(field.text().await.map_err(|e| e.status())?);
+            }
+            (None, Some("display_name" | "caption")) => {
+                display_name = Some(field.text().await.map_err(|e| e.status())?);
+            }
+            // Extra files and unrelated form fields are ignored
+            _ => {}
+        }
+    }
+
+    let Some((original_name, data)) = file else {
+        return Err(ApiError::Message(StatusCode::BAD_REQUEST, "No file field found in upload".to_string()));
+    };
+
+    let mut errors = ValidationErrors::new();
+    for (field, value) in [("alt_text", &alt_text), ("display_name", &display_name)] {
+        if value.as_ref().is_some_and(|v| v.chars().count() > MAX_TITLE_LEN) {
+            errors.add(field, format!("must be at most {} characters", MAX_TITLE_LEN));
+        }
+    }
+    errors.check()?;
+    let alt_text = alt_text.filter(|v| !v.trim().is_empty());
+    let display_name = display_name.filter(|v| !v.trim().is_empty());
+
+    // Sanitize filename
+    let sanitized_name = sanitize_filename(&original_name);
+    
+    // Check file extension
+    let extension = std::path::Path::new(&sanitized_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| format!(".{}", ext.to_lowercase()))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    
+    if !ALLOWED_EXTENSIONS.contains(&extension.as_str()) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    store_upload(&state, &original_name, &sanitized_name, &extension, data, alt_text, display_name).await
+}
+
+/// A multipart field's data, refused with 413 as soon as it passes
+/// `max_bytes` instead of after the whole field has been buffered
+async fn read_field(mut field: axum::extract::multipart::Field<'_>, max_bytes: usize) -> Result<axum::body::Bytes, ApiError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(|e| e.status())? {
+        if data.len() + chunk.len() > max_bytes {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data.into())
+}
+
+/// Check an image's content and store it as an upload: SVG is sanitized,
+/// raster formats must match their magic number and declare a sane size
+async fn store_upload(
+    state: &AppState,
+    original_name: &str,
+    sanitized_name: &str,
+    extension: &str,
+    data: axum::body::Bytes,
+    alt_text: Option<String>,
+    display_name: Option<String>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    // SVG is text and gets sanitized; raster formats are checked by magic number
+    let data = if extension == ".svg" {
+        axum::body::Bytes::from(crate::svg::sanitize(&data).ok_or(StatusCode::BAD_REQUEST)?)
+    } else if verify_image_magic_number(&data, extension) {
+        data
+    } else {
+        return Err(StatusCode::BAD_REQUEST.into());
+    };
+    
+    // A tiny file can declare enormous dimensions; check the header before
+    // anything decodes it
+    if extension != ".svg" {
+        let (width, height) = crate::image::declared_dimensions(&data, extension)
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        if u64::from(width) * u64::from(height) > state.config.max_image_pixels {
+            tracing::warn!("Rejected upload {} declaring {}x{} pixels", original_name, width, height);
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+        }
+    }
+    
+    // Generate timestamp-based filename
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .as_secs();
+    
+    let filename = format!("{}_{}", timestamp, sanitized_name);
+    let size_bytes = data.len() as i64;
+
+    state.storage.put(&filename, data, image_content_type(extension)).await
+        .map_err(|e| {
+            if crate::storage::is_storage_full(&e) {
+                tracing::error!("Upload storage is full; could not store {} ({} bytes)", filename, size_bytes);
+                return ApiError::Message(
+                    StatusCode::INSUFFICIENT_STORAGE,
+                    "The server has run out of storage space for uploads".to_string(),
+                );
+            }
+            tracing::warn!("Failed to store upload {}: {}", filename, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into()
+        })?;
+
+    let url = state.storage.public_url(&filename);
+    let result = sqlx::query(
+        "INSERT INTO uploads (filename, url, original_name, size_bytes, alt_text, display_name) VALUES (?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&filename)
+    .bind(&url)
+    .bind(original_name)
+    .bind(size_bytes)
+    .bind(&alt_text)
+    .bind(&display_name)
+    .execute(&state.db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "id": result.last_insert_rowid(),
+        "url": url,
+        "filename": filename,
+        "alt_text": alt_text,
+        "display_name": display_name
+    })))
+}
+
+/// Fetch an image from a URL on the server and store it as an upload, so
+/// figures don't hotlink external images that can vanish or track readers
+pub async fn import_upload_url(
+    State(state): State<AppState>,
+    Json(payload): Json<ImportUrlRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut errors = ValidationErrors::new();
+    for (field, value) in [("alt_text", &payload.alt_text), ("display_name", &payload.display_name)] {
+        if value.as_ref().is_some_and(|v| v.chars().count() > MAX_TITLE_LEN) {
+            errors.add(field, format!("must be at most {} characters", MAX_TITLE_LEN));
+        }
+    }
+    errors.check()?;
+
+    let fetched = crate::fetch::fetch(
+        payload.url.trim(),
+        state.config.max_upload_bytes,
+        state.config.import_url_timeout,
+    )
+    .await?;
+
+    // The content decides the type; the server's Content-Type or the URL
+    // only matter for SVG, which has no magic number
+    let is_svg = fetched.content_type.as_deref().is_some_and(|t| t.starts_with("image/svg+xml"))
+        || fetched.url.path().to_ascii_lowercase().ends_with(".svg");
+    let extension = [".png", ".jpg", ".gif", ".webp"]
+        .into_iter()
+        .find(|extension| verify_image_magic_number(&fetched.data, extension))
+        .or(is_svg.then_some(".svg"))
+        .ok_or_else(|| ApiError::Message(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "The URL does not point to a supported image".to_string(),
+        ))?;
+
+    // Named after the last path segment, with the extension matching the content
+    let original_name = fetched.url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("image")
+        .to_string();
+    let stem = std::path::Path::new(&original_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("image");
+    let sanitized_name = sanitize_filename(&format!("{}{}", stem, extension));
+
+    let alt_text = payload.alt_text.filter(|v| !v.trim().is_empty());
+    let display_name = payload.display_name.filter(|v| !v.trim().is_empty());
+    let Json(mut upload) = store_upload(
+        &state, &original_name, &sanitized_name, extension, fetched.data, alt_text, display_name,
+    )
+    .await?;
+
+    upload["source_url"] = json!(fetched.url.as_str());
+    Ok(Json(upload))
+}
+
+pub(crate) fn image_content_type(extension: &str) -> &'static str {
+    match extension {
+        ".jpg" | ".jpeg" => "image/jpeg",
+        ".png" => "image/png",
+        ".gif" => "image/gif",
+        ".webp" => "image/webp",
+        ".svg" => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+// Serve an upload for storage backends without a local directory: redirect
+// to a presigned URL when the backend has one, otherwise proxy the bytes
+pub async fn serve_upload(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, StatusCode> {
+    if let Some(url) = state.storage.presigned_url(&key) {
+        return Ok(axum::response::Redirect::temporary(&url).into_response());
+    }
+
+    let data = state.storage.get(&key).await
+        .map_err(|_| StatusCode::BAD_GATEWAY)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let extension = std::path::Path::new(&key)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| format!(".{}", ext.to_lowercase()))
+        .unwrap_or_default();
+    let content_type = image_content_type(&extension);
+    let len = data.len() as u64;
+
+    // Same Range handling ServeDir gives locally stored uploads
+    let range = headers.get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| byte_range(value, len));
+
+    Ok(match range {
+        None => (
+            [(header::CONTENT_TYPE, content_type), (header::ACCEPT_RANGES, "bytes")],
+            data,
+        ).into_response(),
+        Some(Some((start, end))) => (
+            StatusCode::PARTIAL_CONTENT,
+            [
+                (header::CONTENT_TYPE, content_type.to_string()),
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len)),
+            ],
+            data.slice(start as usize..=end as usize),
+        ).into_response(),
+        Some(None) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (header::CONTENT_RANGE, format!("bytes */{}", len)),
+            ],
+        ).into_response(),
+    })
+}
+
+/// Resolve a `Range: bytes=...` header against a body of `len` bytes: None
+/// to send the whole body, Some(None) if the range is malformed or
+/// unsatisfiable (416), otherwise the inclusive byte span. Only single ranges
+/// are served; a multi-range request gets the whole body.
+fn byte_range(value: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Some(None);
+    };
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => suffix.parse::<u64>().ok()
+            .filter(|&suffix| suffix > 0 && len > 0)
+            .map(|suffix| (len.saturating_sub(suffix), len - 1)),
+        (start, "") => start.parse::<u64>().ok()
+            .filter(|&start| start < len)
+            .map(|start| (start, len - 1)),
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end && start < len => Some((start, end.min(len - 1))),
+            _ => None,
+        },
+    };
+
+    Some(range)
+}
+
+pub async fn update_upload(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateUploadRequest>,
+) -> Result<Json<Upload>, StatusCode> {
+    let before = sqlx::query_as::<_, Upload>("SELECT * FROM uploads WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    sqlx::query(
+        "UPDATE uploads SET alt_text = COALESCE(?, alt_text), display_name = COALESCE(?, display_name),
+         updated_at = strftime('%Y-%m-%d %H:%M:%f', 'now') WHERE id = ?"
+    )
+    .bind(&payload.alt_text)
+    .bind(&payload.display_name)
+    .bind(id)
+    .execute(&state.db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let upload = sqlx::query_as::<_, Upload>("SELECT * FROM uploads WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("update", "upload", id, Some(&before), Some(&upload));
+
+    Ok(Json(upload))
+}
+
+// Stylesheet handlers
+pub async fn list_stylesheets(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Stylesheet>>, StatusCode> {
+    let stylesheets = sqlx::query_as::<_, Stylesheet>("SELECT * FROM stylesheets ORDER BY name")
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(stylesheets))
+}
+
+pub async fn get_stylesheet(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Stylesheet>, StatusCode> {
+    let stylesheet = sqlx::query_as::<_, Stylesheet>("SELECT * FROM stylesheets WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok(Json(stylesheet))
+}
+
+pub async fn create_stylesheet(
+    State(state): State<AppState>,
+    Json(payload): Json<CreateStylesheetRequest>,
+) -> Result<Json<Stylesheet>, ApiError> {
+    let mut errors = ValidationErrors::new();
+    if payload.name.trim().is_empty() {
+        errors.add("name", "must not be empty");
+    } else if payload.name.chars().count() > MAX_TITLE_LEN {
+        errors.add("name", format!("must be at most {} characters", MAX_TITLE_LEN));
+    }
+    let css = crate::export::sanitize_css(&payload.css).unwrap_or_else(|message| {
+        errors.add("css", message);
+        String::new()
+    });
+    errors.check()?;
+
+    let stylesheet = sqlx::query_as::<_, Stylesheet>(
+        "INSERT INTO stylesheets (name, css) VALUES (?, ?) RETURNING *"
+    )
+    .bind(payload.name.trim())
+    .bind(&css)
+    .fetch_one(&state.db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("create", "stylesheet", stylesheet.id, None::<&()>, Some(&stylesheet));
+
+    Ok(Json(stylesheet))
+}
+
+pub async fn delete_stylesheet(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let before = sqlx::query_as::<_, Stylesheet>("SELECT * FROM stylesheets WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query("DELETE FROM stylesheets WHERE id = ?")
+        .bind(id)
+        .execute(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Some(before) = &before {
+        state.audit.record("delete", "stylesheet", id, Some(before), None::<&()>);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// PDF export handler: renders on a background job and returns its id immediately
+pub async fn export_pdf(
+    State(state): State<AppState>,
+    Json(payload): Json<ExportPdfRequest>,
+) -> Result<(StatusCode, [(HeaderName, String); 1], Json<serde_json::Value>), ApiError> {
+    // A stored stylesheet first, then the request's own CSS on top of it
+    let mut errors = ValidationErrors::new();
+    let mut css = Vec::new();
+    if let Some(stylesheet_id) = payload.stylesheet_id {
+        let stored: Option<String> = sqlx::query_scalar("SELECT css FROM stylesheets WHERE id = ?")
+            .bind(stylesheet_id)
+            .fetch_optional(&state.db)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        match stored {
+            Some(stored) => css.push(stored),
+            None => errors.add("stylesheet_id", "no such stylesheet"),
+        }
+    }
+    if let Some(custom_css) = &payload.custom_css {
+        match crate::export::sanitize_css(custom_css) {
+            Ok(custom_css) => css.push(custom_css),
+            Err(message) => errors.add("custom_css", message),
         }
-        _ => false,
     }
+    errors.check()?;
+    let css = (!css.is_empty()).then(|| css.join("\n"));
+
+    let stale = export_staleness(&state, payload.document_id, payload.require_settled).await?;
+
+    let job_id = state.export_jobs.start(
+        state.db.clone(),
+        state.content_cipher.clone(),
+        payload.document_id,
+        crate::export::PdfOptions {
+            template: payload.template,
+            bookmarks: payload.bookmarks,
+            css,
+            figure_placement: payload.figure_placement,
+        },
+    );
+
+    Ok((StatusCode::ACCEPTED, [(EXPORT_STALE, stale.to_string())], Json(json!({
+        "job_id": job_id,
+        "status": "pending",
+        "status_url": format!("/api/export/jobs/{}", job_id)
+    }))))
 }
 
-/// Sanitize filename to prevent path traversal attacks
-fn sanitize_filename(filename: &str) -> String {
-    use std::path::Path;
-    
-    // Get only the basename (remove any path components)
-    let basename = Path::new(filename)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("unknown");
-    
-    // Remove any non-alphanumeric characters except dots, hyphens, and underscores
-    let sanitized: String = basename
-        .chars()
-        .map(|c| {
-            if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' {
-                c
-            } else {
-                '_'
-            }
-        })
-        .collect();
-    
-    // Limit filename length
-    const MAX_LENGTH: usize = 255;
-    if sanitized.len() > MAX_LENGTH {
-        if let Some(dot_pos) = sanitized.rfind('.') {
-            let ext = &sanitized[dot_pos..];
-            let name = &sanitized[..dot_pos.min(MAX_LENGTH - ext.len())];
-            format!("{}{}", name, ext)
-        } else {
-            sanitized.chars().take(MAX_LENGTH).collect()
-        }
+pub async fn get_export_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    state.export_jobs.status(&id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+pub async fn cancel_export_job(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    if state.export_jobs.cancel(&id) {
+        Ok(StatusCode::NO_CONTENT)
     } else {
-        sanitized
+        Err(StatusCode::NOT_FOUND)
     }
 }
 
-// File upload handler
-pub async fn upload_file(
-    mut multipart: Multipart,
+pub async fn download_export(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let path = state.export_jobs.output_path(&id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let data = tokio::fs::read(&path).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"export-{}.html\"", id)),
+        ],
+        data,
+    ))
+}
+
+pub async fn export_all(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let path = crate::export::write_backup_archive(&state.read_db, state.storage.as_ref())
+        .await
+        .map_err(|e| {
+            tracing::warn!("Backup export failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let file = tokio::fs::File::open(&path).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // The open handle keeps the archive readable while it streams out
+    let _ = tokio::fs::remove_file(&path).await;
+
+    let filename = format!("type-editor-backup-{}.zip", chrono::Utc::now().format("%Y-%m-%d"));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ))
+}
+
+// Admin handlers
+pub async fn list_audit_log(
+    _admin: AdminToken,
+    State(state): State<AppState>,
+    Query(params): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, StatusCode> {
+    let limit = params.limit.unwrap_or(100).clamp(1, 1000);
+
+    let entries = sqlx::query_as::<_, AuditEntry>(
+        "SELECT * FROM audit_log
+         WHERE (? IS NULL OR entity_type = ?)
+           AND (? IS NULL OR entity_id = ?)
+           AND (? IS NULL OR timestamp >= datetime(?))
+           AND (? IS NULL OR timestamp <= datetime(?))
+         ORDER BY id DESC
+         LIMIT ?"
+    )
+    .bind(&params.entity_type)
+    .bind(&params.entity_type)
+    .bind(params.entity_id)
+    .bind(params.entity_id)
+    .bind(&params.from)
+    .bind(&params.from)
+    .bind(&params.to)
+    .bind(&params.to)
+    .bind(limit)
+    .fetch_all(&state.read_db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(entries))
+}
+
+// Rows left behind when foreign keys were not enforced
+const ORPHAN_CONTENT_WHERE: &str = "node_id NOT IN (SELECT id FROM nodes)";
+// A node is orphaned by a missing document or a missing parent; deleting it
+// cascades to its own subtree
+const ORPHAN_NODES_WHERE: &str = "document_id NOT IN (SELECT id FROM documents)
+    OR (parent_id IS NOT NULL AND parent_id NOT IN (SELECT id FROM nodes))";
+
+// Every orphan node paired with each node in its subtree, itself included.
+// UNION keeps a cycle among orphans from recursing forever.
+fn orphan_subtrees_cte() -> String {
+    format!(
+        "WITH RECURSIVE subtree(root, id) AS (
+             SELECT id, id FROM nodes WHERE {}
+             UNION
+             SELECT subtree.root, nodes.id FROM nodes JOIN subtree ON nodes.parent_id = subtree.id
+         )",
+        ORPHAN_NODES_WHERE
+    )
+}
+
+/// Per orphan node, how many descendants and content rows deleting it would
+/// cascade to
+async fn orphan_cascades(
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<HashMap<i64, (i64, i64)>, StatusCode> {
+    let rows: Vec<(i64, i64, i64)> = sqlx::query_as(&format!(
+        "{} SELECT subtree.root, COUNT(DISTINCT subtree.id) - 1, COUNT(DISTINCT content.id)
+         FROM subtree LEFT JOIN content ON content.node_id = subtree.id
+         GROUP BY subtree.root",
+        orphan_subtrees_cte()
+    ))
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(rows.into_iter().map(|(root, descendants, content)| (root, (descendants, content))).collect())
+}
+
+/// Nodes below the orphans and content rows of the orphans and their
+/// subtrees, each counted once even where orphan subtrees overlap
+async fn orphan_cascade_totals(conn: &mut sqlx::SqliteConnection) -> Result<(i64, i64), StatusCode> {
+    sqlx::query_as(&format!(
+        "{} SELECT
+             (SELECT COUNT(DISTINCT id) FROM subtree WHERE id NOT IN (SELECT id FROM nodes WHERE {})),
+             (SELECT COUNT(*) FROM content WHERE node_id IN (SELECT id FROM subtree))",
+        orphan_subtrees_cte(),
+        ORPHAN_NODES_WHERE
+    ))
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+pub async fn list_orphans(
+    _admin: AdminToken,
+    State(state): State<AppState>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    while let Some(field) = multipart.next_field().await
-        .map_err(|_| StatusCode::BAD_REQUEST)? 
-    {
-        let original_name = field.file_name()
-            .ok_or(StatusCode::BAD_REQUEST)?;
-        
-        let data = field.bytes().await
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
-        
-        // Check file size
-        if data.len() > MAX_FILE_SIZE {
-            return Err(StatusCode::PAYLOAD_TOO_LARGE);
-        }
-        
-        // Sanitize filename
-        let sanitized_name = sanitize_filename(original_name);
-        
-        // Check file extension
-        let extension = std::path::Path::new(&sanitized_name)
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .map(|ext| format!(".{}", ext.to_lowercase()))
-            .ok_or(StatusCode::BAD_REQUEST)?;
-        
-        if !ALLOWED_EXTENSIONS.contains(&extension.as_str()) {
-            return Err(StatusCode::BAD_REQUEST);
-        }
-        
-        // Verify file content matches extension using magic numbers
-        if !verify_image_magic_number(&data, &extension) {
-            return Err(StatusCode::BAD_REQUEST);
-        }
-        
-        // Generate timestamp-based filename
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .as_secs();
-        
-        let filename = format!("{}_{}", timestamp, sanitized_name);
-        let filepath = format!("../uploads/{}", filename);
-        
-        // Create uploads directory if it doesn't exist
-        std::fs::create_dir_all("../uploads")
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        
-        // Write file
-        let mut file = std::fs::File::create(&filepath)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        file.write_all(&data)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let content = sqlx::query_as::<_, Content>(&format!("SELECT * FROM content WHERE {}", ORPHAN_CONTENT_WHERE))
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-        return Ok(Json(json!({
-            "url": format!("/uploads/{}", filename),
-            "filename": filename
-        })));
-    }
+    let nodes = sqlx::query_as::<_, Node>(&format!("SELECT * FROM nodes WHERE {}", ORPHAN_NODES_WHERE))
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Cleanup deletes whole subtrees, so show what each orphan takes with it
+    let mut conn = state.read_db.acquire().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let cascades = orphan_cascades(&mut conn).await?;
+    let (descendant_nodes, cascaded_content) = orphan_cascade_totals(&mut conn).await?;
 
-    Err(StatusCode::BAD_REQUEST)
+    Ok(Json(json!({
+        "content": content.iter()
+            .map(|c| json!({ "id": c.id, "node_id": c.node_id, "updated_at": c.updated_at }))
+            .collect::<Vec<_>>(),
+        "nodes": nodes.iter()
+            .map(|n| {
+                let (descendants, content) = cascades.get(&n.id).copied().unwrap_or_default();
+                json!({
+                    "id": n.id,
+                    "document_id": n.document_id,
+                    "parent_id": n.parent_id,
+                    "title": n.title,
+                    "descendants": descendants,
+                    "content": content
+                })
+            })
+            .collect::<Vec<_>>(),
+        "counts": {
+            "content": content.len(),
+            "nodes": nodes.len(),
+            "descendant_nodes": descendant_nodes,
+            "cascaded_content": cascaded_content
+        }
+    })))
 }
 
-// PDF export handler (placeholder - full implementation requires headless_chrome setup)
-pub async fn export_pdf(
+pub async fn cleanup_orphans(
+    _admin: AdminToken,
     State(state): State<AppState>,
-    Json(payload): Json<ExportPdfRequest>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    // TODO: Implement full PDF generation with headless_chrome
-    // For now, return a placeholder response
-    
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The cascade from deleting a node isn't in rows_affected, so count it
+    // beforehand in the same transaction
+    let (descendants_deleted, cascaded_content_deleted) = orphan_cascade_totals(&mut tx).await?;
+
+    // Nodes go first so content belonging to them is caught by the second pass
+    let nodes_deleted = sqlx::query(&format!("DELETE FROM nodes WHERE {}", ORPHAN_NODES_WHERE))
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .rows_affected();
+
+    let content_deleted = sqlx::query(&format!("DELETE FROM content WHERE {}", ORPHAN_CONTENT_WHERE))
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .rows_affected();
+
+    // Nodes under a missing parent may have belonged to a live document
+    sqlx::query(REFRESH_WORD_COUNT)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tracing::info!(
+        "Orphan cleanup removed {} nodes with {} descendants, {} content rows and {} content rows in their subtrees",
+        nodes_deleted,
+        descendants_deleted,
+        content_deleted,
+        cascaded_content_deleted
+    );
+
     Ok(Json(json!({
-        "message": "PDF export not yet implemented",
-        "document_id": payload.document_id,
-        "template": payload.template
+        "deleted": {
+            "content": content_deleted,
+            "nodes": nodes_deleted,
+            "descendant_nodes": descendants_deleted,
+            "cascaded_content": cascaded_content_deleted
+        }
+    })))
+}
+
+/// Recount every content row and document total from scratch, for installs
+/// that predate the cached counts or after editing the database by hand
+pub async fn recompute_word_counts(
+    _admin: AdminToken,
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let rows: Vec<(i64, i64, String)> = sqlx::query_as(
+        "SELECT content.node_id, nodes.document_id, content.content_json FROM content
+         JOIN nodes ON nodes.id = content.node_id"
+    )
+    .fetch_all(&state.db)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut tx = state.db.begin().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    for (node_id, document_id, content_json) in &rows {
+        sqlx::query("UPDATE content SET word_count = ? WHERE node_id = ?")
+            .bind(stored_words(&state, *document_id, content_json))
+            .bind(node_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let documents = sqlx::query(REFRESH_WORD_COUNT)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .rows_affected();
+
+    tx.commit().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tracing::info!("Recomputed word counts for {} content rows across {} documents", rows.len(), documents);
+
+    Ok(Json(json!({
+        "content": rows.len(),
+        "documents": documents
     })))
 }
+
+pub async fn list_webhooks(
+    _admin: AdminToken,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Webhook>>, StatusCode> {
+    let hooks = sqlx::query_as::<_, Webhook>("SELECT id, url, events, created_at FROM webhooks ORDER BY id")
+        .fetch_all(&state.read_db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(hooks))
+}
+
+/// Register a hook. The response is the only time its secret is shown.
+pub async fn create_webhook(
+    _admin: AdminToken,
+    State(state): State<AppState>,
+    Json(payload): Json<CreateWebhookRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let mut errors = ValidationErrors::new();
+    match reqwest::Url::parse(&payload.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => errors.add("url", "must be an absolute http or https URL"),
+    }
+    if payload.events.is_empty() {
+        errors.add("events", "must name at least one event");
+    }
+    for event in &payload.events {
+        if !webhooks::EVENTS.contains(&event.as_str()) {
+            errors.add("events", format!("unknown event {}; expected one of {}", event, webhooks::EVENTS.join(", ")));
+        }
+    }
+    if payload.secret.as_deref().is_some_and(|secret| secret.len() < 16) {
+        errors.add("secret", "must be at least 16 characters");
+    }
+    errors.check()?;
+
+    let mut events = payload.events;
+    events.sort();
+    events.dedup();
+    let secret = payload.secret.unwrap_or_else(webhooks::generate_secret);
+
+    let id = sqlx::query("INSERT INTO webhooks (url, events, secret) VALUES (?, ?, ?)")
+        .bind(&payload.url)
+        .bind(sqlx::types::Json(&events))
+        .bind(&secret)
+        .execute(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .last_insert_rowid();
+
+    let hook = sqlx::query_as::<_, Webhook>("SELECT id, url, events, created_at FROM webhooks WHERE id = ?")
+        .bind(id)
+        .fetch_one(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("create", "webhook", id, None::<&()>, Some(&hook));
+
+    let mut body = serde_json::to_value(&hook).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    body["secret"] = json!(secret);
+    Ok((StatusCode::CREATED, Json(body)))
+}
+
+pub async fn delete_webhook(
+    _admin: AdminToken,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let before = sqlx::query_as::<_, Webhook>("SELECT id, url, events, created_at FROM webhooks WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    sqlx::query("DELETE FROM webhooks WHERE id = ?")
+        .bind(id)
+        .execute(&state.db)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    state.audit.record("delete", "webhook", id, Some(&before), None::<&()>);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit, autosave, config::Config, db, export};
+    use std::sync::Arc;
+
+    async fn test_state(configure: impl FnOnce(&mut Config)) -> AppState {
+        let mut config = Config::from_env().expect("default configuration");
+        config.db_path = std::env::temp_dir()
+            .join(format!(
+                "type_editor_test_{}_{}.db",
+                std::process::id(),
+                std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos()
+            ))
+            .to_string_lossy()
+            .into_owned();
+        config.read_pool_size = None;
+        configure(&mut config);
+
+        let db = db::init_db(&config).await.expect("test database");
+        let audit = audit::AuditLog::spawn(db.clone());
+        let content_cipher = config.content_encryption_key.as_deref()
+            .map(|key| crypto::ContentCipher::new(key, None))
+            .map(Arc::new);
+        AppState {
+            autosave: autosave::SaveCoalescer::new(db.clone(), audit.clone(), config.autosave_window),
+            audit,
+            webhooks: webhooks::Webhooks::spawn(db.clone(), config.webhook_timeout),
+            storage: Arc::new(crate::storage::LocalStorage::new(std::env::temp_dir())),
+            content_cipher,
+            read_db: db.clone(),
+            db,
+            export_jobs: export::ExportJobs::default(),
+            config: Arc::new(config),
+            ready: Default::default(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_creates_stop_at_the_document_quota() {
+        const LIMIT: i64 = 5;
+        let state = test_state(|config| config.max_documents = Some(LIMIT)).await;
+        let existing: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM documents")
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+
+        let attempts: Vec<_> = (0..20)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let request = CreateDocumentRequest { title: format!("Doc {}", i), child_sort: None, encrypted: false };
+                    create_document(State(state), Editor(None), Json(request)).await
+                })
+            })
+            .collect();
+
+        let mut created = 0;
+        for attempt in attempts {
+            match attempt.await.unwrap() {
+                Ok(_) => created += 1,
+                Err(ApiError::Message(status, _)) => assert_eq!(status, StatusCode::FORBIDDEN),
+                Err(other) => panic!("unexpected error: {:?}", other.into_response().status()),
+            }
+        }
+
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM documents")
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(created, LIMIT - existing);
+        assert_eq!(total, LIMIT);
+
+        state.db.close().await;
+        let _ = std::fs::remove_file(&state.config.db_path);
+    }
+
+    /// An encrypted document with one section whose sealed content mentions `word`
+    async fn encrypted_document_with(state: &AppState, word: &str) -> i64 {
+        let request = CreateDocumentRequest { title: "Sealed".to_string(), child_sort: None, encrypted: true };
+        let Ok(Json(document)) = create_document(State(state.clone()), Editor(None), Json(request)).await else {
+            panic!("encrypted document not created");
+        };
+
+        let node_id = sqlx::query("INSERT INTO nodes (document_id, node_type, title, order_index) VALUES (?, 'section', 'Intro', 0)")
+            .bind(document.id)
+            .execute(&state.db)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+        let plain = json!([{
+            "type": "paragraph",
+            "content": [{ "type": "text", "text": format!("{} facts", word), "styles": {} }],
+            "children": [],
+        }])
+        .to_string();
+        let sealed = cipher(state).unwrap().seal(document.id, &plain).unwrap();
+        sqlx::query("INSERT INTO content (node_id, content_json) VALUES (?, ?)")
+            .bind(node_id)
+            .bind(sealed)
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        document.id
+    }
+
+    async fn body_bytes(response: Response) -> Vec<u8> {
+        assert_eq!(response.status(), StatusCode::OK);
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn exports_of_encrypted_documents_include_their_content() {
+        let state = test_state(|config| {
+            config.content_encryption_key = Some("test content key".to_string());
+        })
+        .await;
+        let id = encrypted_document_with(&state, "Quokka").await;
+
+        let query = LatexExportQuery { template: None, require_settled: false, figure_placement: FigurePlacement::default() };
+        let Ok(response) = export_latex(State(state.clone()), Path(id), Query(query)).await else {
+            panic!("LaTeX export failed");
+        };
+        let tex = String::from_utf8(body_bytes(response.into_response()).await).unwrap();
+        assert!(tex.contains("Quokka facts"), "LaTeX export is missing the content:\n{}", tex);
+
+        let query = EpubExportQuery { require_settled: false, figure_placement: FigurePlacement::default() };
+        let Ok(response) = export_epub(State(state.clone()), Path(id), Query(query)).await else {
+            panic!("EPUB export failed");
+        };
+        let epub = body_bytes(response.into_response()).await;
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(epub)).unwrap();
+        let mut text = String::new();
+        for i in 0..archive.len() {
+            std::io::Read::read_to_string(&mut archive.by_index(i).unwrap(), &mut text).unwrap();
+        }
+        assert!(text.contains("Quokka facts"), "EPUB export is missing the content");
+
+        state.db.close().await;
+        let _ = std::fs::remove_file(&state.config.db_path);
+    }
+}
@@ -0,0 +1,194 @@
+//! Image header inspection, so oversized images can be turned away before
+//! anything tries to decode them
+
+fn be16(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 2).map(|b| u32::from(u16::from_be_bytes([b[0], b[1]])))
+}
+
+fn le16(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 2).map(|b| u32::from(u16::from_le_bytes([b[0], b[1]])))
+}
+
+fn le24(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 3).map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]))
+}
+
+/// Width and height declared in a raster image's header, without decoding
+/// any pixel data. None if the header is truncated, not understood, or
+/// declares an empty image.
+pub fn declared_dimensions(data: &[u8], extension: &str) -> Option<(u32, u32)> {
+    let dimensions = match extension {
+        // IHDR is always the first chunk
+        ".png" => {
+            let width = data.get(16..20)?;
+            let height = data.get(20..24)?;
+            Some((
+                u32::from_be_bytes(width.try_into().ok()?),
+                u32::from_be_bytes(height.try_into().ok()?),
+            ))
+        }
+        // Logical screen descriptor follows the 6-byte signature
+        ".gif" => Some((le16(data, 6)?, le16(data, 8)?)),
+        ".jpg" | ".jpeg" => jpeg_dimensions(data),
+        ".webp" => webp_dimensions(data),
+        _ => None,
+    };
+    dimensions.filter(|&(width, height)| width > 0 && height > 0)
+}
+
+/// Walk JPEG marker segments up to the first start-of-frame
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        // Any number of 0xFF fill bytes may precede a marker
+        while *data.get(pos)? == 0xFF {
+            pos += 1;
+        }
+        let marker = *data.get(pos)?;
+        pos += 1;
+
+        match marker {
+            // Standalone markers carry no length
+            0x01 | 0xD0..=0xD7 => continue,
+            // SOF0-SOF15, except DHT, JPG and DAC which share the range
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Some((be16(data, pos + 5)?, be16(data, pos + 3)?));
+            }
+            // Image data started without a frame header
+            0xD9 | 0xDA => return None,
+            _ => pos += be16(data, pos)? as usize,
+        }
+    }
+}
+
+fn webp_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    match data.get(12..16)? {
+        // Lossy: the key frame header follows a 3-byte frame tag and start code
+        b"VP8 " => {
+            if data.get(23..26)? != [0x9D, 0x01, 0x2A] {
+                return None;
+            }
+            Some((le16(data, 26)? & 0x3FFF, le16(data, 28)? & 0x3FFF))
+        }
+        // Lossless: 14-bit width-1 and height-1 packed after the signature byte
+        b"VP8L" => {
+            if *data.get(20)? != 0x2F {
+                return None;
+            }
+            let bits = u32::from_le_bytes(data.get(21..25)?.try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        // Extended: 24-bit canvas width-1 and height-1
+        b"VP8X" => Some((le24(data, 24)? + 1, le24(data, 27)? + 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        data.extend(width.to_be_bytes());
+        data.extend(height.to_be_bytes());
+        data.extend([8, 6, 0, 0, 0]);
+        data
+    }
+
+    fn gif(width: u16, height: u16) -> Vec<u8> {
+        let mut data = b"GIF89a".to_vec();
+        data.extend(width.to_le_bytes());
+        data.extend(height.to_le_bytes());
+        data
+    }
+
+    fn webp(chunk: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+        data.extend(chunk);
+        data.extend((payload.len() as u32).to_le_bytes());
+        data.extend(payload);
+        data
+    }
+
+    #[test]
+    fn png_reads_ihdr() {
+        assert_eq!(declared_dimensions(&png(640, 480), ".png"), Some((640, 480)));
+    }
+
+    #[test]
+    fn truncated_png_ihdr_is_refused() {
+        let data = png(640, 480);
+        for len in [8, 16, 20, 23] {
+            assert_eq!(declared_dimensions(&data[..len], ".png"), None, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn jpeg_frame_after_app_segments() {
+        let mut data = vec![0xFF, 0xD8];
+        // APP0 (JFIF) and APP1 (Exif) with arbitrary payloads
+        data.extend([0xFF, 0xE0, 0x00, 0x10]);
+        data.extend(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        data.extend([0xFF, 0xE1, 0x00, 0x08]);
+        data.extend(b"Exif\0\0");
+        // Fill bytes before SOF2: length, precision, height 300, width 400
+        data.extend([0xFF, 0xFF, 0xC2, 0x00, 0x11, 0x08, 0x01, 0x2C, 0x01, 0x90, 0x03]);
+        assert_eq!(declared_dimensions(&data, ".jpg"), Some((400, 300)));
+
+        // DHT shares the SOF range but is skipped like any other segment
+        let mut with_dht = vec![0xFF, 0xD8, 0xFF, 0xC4, 0x00, 0x04, 0x00, 0x00];
+        with_dht.extend(&data[2..]);
+        assert_eq!(declared_dimensions(&with_dht, ".jpeg"), Some((400, 300)));
+    }
+
+    #[test]
+    fn jpeg_without_frame_is_refused() {
+        let data = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xDA, 0x00, 0x02];
+        assert_eq!(declared_dimensions(&data, ".jpg"), None);
+        // Segment length runs past the end
+        assert_eq!(declared_dimensions(&[0xFF, 0xD8, 0xFF, 0xE0, 0x40, 0x00], ".jpg"), None);
+    }
+
+    #[test]
+    fn gif_dimensions() {
+        assert_eq!(declared_dimensions(&gif(65535, 65535), ".gif"), Some((65535, 65535)));
+        assert_eq!(declared_dimensions(&gif(0, 10), ".gif"), None);
+        assert_eq!(declared_dimensions(&gif(10, 0), ".gif"), None);
+        assert_eq!(declared_dimensions(&gif(10, 10)[..9], ".gif"), None);
+    }
+
+    #[test]
+    fn webp_dimensions() {
+        // Lossy: frame tag, start code, then 14-bit width and height
+        let lossy = |width: u16, height: u16| {
+            let mut payload = vec![0, 0, 0, 0x9D, 0x01, 0x2A];
+            payload.extend(width.to_le_bytes());
+            payload.extend(height.to_le_bytes());
+            webp(b"VP8 ", &payload)
+        };
+        assert_eq!(declared_dimensions(&lossy(16383, 16383), ".webp"), Some((16383, 16383)));
+        // The top two bits are the scale, not part of the size
+        assert_eq!(declared_dimensions(&lossy(0xC000 | 100, 50), ".webp"), Some((100, 50)));
+        assert_eq!(declared_dimensions(&lossy(0, 50), ".webp"), None);
+        assert_eq!(declared_dimensions(&lossy(0xC000, 50), ".webp"), None);
+
+        // Lossless: 14-bit width-1 and height-1, so the largest is 16384
+        let lossless = |bits: u32| {
+            let mut payload = vec![0x2F];
+            payload.extend(bits.to_le_bytes());
+            webp(b"VP8L", &payload)
+        };
+        assert_eq!(declared_dimensions(&lossless(u32::MAX), ".webp"), Some((16384, 16384)));
+        assert_eq!(declared_dimensions(&lossless(0), ".webp"), Some((1, 1)));
+
+        // Extended: 24-bit canvas width-1 and height-1
+        let mut payload = vec![0; 4];
+        payload.extend([0xFF; 6]);
+        assert_eq!(declared_dimensions(&webp(b"VP8X", &payload), ".webp"), Some((1 << 24, 1 << 24)));
+        assert_eq!(declared_dimensions(&webp(b"VP8X", &payload[..8]), ".webp"), None);
+    }
+}
@@ -1,33 +1,74 @@
+mod audit;
+mod auth;
+mod autosave;
+mod backup;
+mod config;
+mod crypto;
 mod db;
+mod diff;
+mod error;
+mod export;
+mod fetch;
 mod handlers;
+mod image;
+mod math;
+mod middleware;
 mod models;
+mod ops;
+mod pagination;
+mod query;
+mod storage;
+mod svg;
+mod text;
+mod tree;
+mod tx;
+#[cfg(unix)]
+mod uds;
+mod webhooks;
 
 use axum::{
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
     extract::State,
-    routing::{get, post, put, delete},
+    routing::{any, get, post, put, patch, delete},
     Router,
 };
 use sqlx::sqlite::SqlitePool;
-use std::sync::Arc;
+use tower::ServiceBuilder;
 use tower_http::cors::{CorsLayer, AllowOrigin};
-use tower_http::services::ServeDir;
+use tower_http::timeout::TimeoutLayer;
+use tower_http::trace::TraceLayer;
+use tower_http::services::{ServeDir, ServeFile};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: SqlitePool,
+    /// For handlers that only read; the same pool as `db` unless
+    /// READ_POOL_SIZE is set
+    pub read_db: SqlitePool,
+    pub config: std::sync::Arc<config::Config>,
+    pub export_jobs: export::ExportJobs,
+    pub audit: audit::AuditLog,
+    pub storage: std::sync::Arc<dyn storage::Storage>,
+    pub autosave: autosave::SaveCoalescer,
+    pub webhooks: webhooks::Webhooks,
+    pub content_cipher: Option<std::sync::Arc<crypto::ContentCipher>>,
+    /// Set once startup has finished, cleared when shutdown begins
+    pub ready: std::sync::Arc<std::sync::atomic::AtomicBool>,
 }
 
-// Health check handler
-async fn health_check(State(state): State<AppState>) -> axum::response::Json<serde_json::Value> {
-    // Quick database health check
-    let db_healthy = sqlx::query("SELECT 1")
-        .execute(&state.db)
-        .await
-        .is_ok();
-    
+impl AppState {
+    pub fn flag_enabled(&self, name: &str) -> bool {
+        self.config.features.contains(name)
+    }
+}
+
+// Liveness: answers as long as the process is serving. It touches nothing
+// else, so a slow database doesn't get a healthy process restarted.
+async fn health_check() -> axum::response::Json<serde_json::Value> {
     axum::response::Json(serde_json::json!({
-        "status": if db_healthy { "healthy" } else { "unhealthy" },
+        "status": "healthy",
         "timestamp": chrono::Utc::now().to_rfc3339(),
         "uptime": std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -36,15 +77,63 @@ async fn health_check(State(state): State<AppState>) -> axum::response::Json<ser
     }))
 }
 
+// How long the readiness probe waits for a database connection
+const READY_ACQUIRE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Readiness: 200 only once startup (including migrations) has finished and the
+// pool can hand out a working connection; 503 before that and while shutting down
+async fn readiness_check(
+    State(state): State<AppState>,
+) -> (axum::http::StatusCode, axum::response::Json<serde_json::Value>) {
+    let started = state.ready.load(std::sync::atomic::Ordering::Acquire);
+    let database = started && tokio::time::timeout(READY_ACQUIRE_TIMEOUT, async {
+        let mut conn = state.db.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *conn).await
+    })
+    .await
+    .is_ok_and(|result| result.is_ok());
+
+    let status = if database {
+        axum::http::StatusCode::OK
+    } else {
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, axum::response::Json(serde_json::json!({
+        "ready": database,
+        "started": started,
+        "database": { "connected": database },
+    })))
+}
+
+// Check that upload storage accepts writes by creating and removing a probe object
+async fn uploads_writable(storage: &dyn storage::Storage) -> bool {
+    let probe = format!(".health_probe_{}", std::process::id());
+    if storage.put(&probe, axum::body::Bytes::from_static(b"ok"), "text/plain").await.is_err() {
+        return false;
+    }
+
+    storage.delete(&probe).await.is_ok()
+}
+
 // Detailed health check handler
 async fn detailed_health_check(State(state): State<AppState>) -> axum::response::Json<serde_json::Value> {
     let db_healthy = sqlx::query("SELECT 1")
         .execute(&state.db)
         .await
         .is_ok();
+    let uploads_healthy = uploads_writable(state.storage.as_ref()).await;
+    
+    let status = if !db_healthy {
+        "unhealthy"
+    } else if !uploads_healthy {
+        "degraded"
+    } else {
+        "healthy"
+    };
     
     axum::response::Json(serde_json::json!({
-        "status": if db_healthy { "healthy" } else { "unhealthy" },
+        "status": status,
         "timestamp": chrono::Utc::now().to_rfc3339(),
         "uptime": std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -52,10 +141,53 @@ async fn detailed_health_check(State(state): State<AppState>) -> axum::response:
             .as_secs(),
         "database": {
             "connected": db_healthy
-        }
+        },
+        "uploads": {
+            "writable": uploads_healthy
+        },
+        "features": &state.config.features
     }))
 }
 
+// Matches http://localhost[:port] and http://127.0.0.1[:port] for CORS dev mode
+fn is_local_dev_origin(origin: &axum::http::HeaderValue) -> bool {
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+
+    ["http://localhost", "http://127.0.0.1"].iter().any(|host| {
+        origin.strip_prefix(host).is_some_and(|rest| {
+            rest.is_empty()
+                || rest
+                    .strip_prefix(':')
+                    .is_some_and(|port| port.parse::<u16>().is_ok())
+        })
+    })
+}
+
+/// Resolves on Ctrl-C, or SIGTERM where there are signals
+pub(crate) async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::registry()
@@ -65,95 +197,325 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let config = config::Config::from_env()?;
+    query::set_slow_threshold(config.slow_query_threshold);
+    
     // Initialize database
-    let db_pool = db::init_db().await?;
+    let db_pool = db::init_db(&config).await?;
+    let read_pool = db::open_read_pool(&config, &db_pool).await?;
     
-    let state = AppState { db: db_pool };
-
-    // CORS configuration
-    let allowed_origins_str = std::env::var("ALLOWED_ORIGINS")
-        .unwrap_or_else(|_| "http://localhost:5000,http://localhost:3000".to_string());
+    if config.admin_token.is_none() {
+        tracing::info!("ADMIN_TOKEN not set, admin endpoints are disabled");
+    }
+    if config.log_bodies {
+        tracing::warn!("DEBUG_LOG_BODIES is set: request and response bodies will be logged");
+    }
     
-    let mut allowed_origins: Vec<axum::http::HeaderValue> = allowed_origins_str
-        .split(',')
-        .filter_map(|s| {
-            s.trim().parse().map_err(|e| {
-                tracing::warn!("Invalid CORS origin '{}': {}", s.trim(), e);
-            }).ok()
-        })
-        .collect();
+    let storage = storage::open(&config)?;
     
-    if allowed_origins.is_empty() {
-        tracing::warn!("No valid CORS origins configured, using defaults");
-        // Fallback to default origins if parsing failed
-        allowed_origins = vec![
-            "http://localhost:5000".parse().expect("Hardcoded origin should be valid"),
-            "http://localhost:3000".parse().expect("Hardcoded origin should be valid"),
-        ];
+    let content_cipher = config.content_encryption_key.as_deref()
+        .map(|key| crypto::ContentCipher::new(key, config.content_encryption_key_previous.as_deref()))
+        .map(std::sync::Arc::new);
+    if let Some(cipher) = &content_cipher {
+        tracing::info!("Content encryption enabled (key {})", cipher.key_id());
     }
+    
+    let audit = audit::AuditLog::spawn(db_pool.clone());
+    let state = AppState {
+        autosave: autosave::SaveCoalescer::new(db_pool.clone(), audit.clone(), config.autosave_window),
+        audit,
+        webhooks: webhooks::Webhooks::spawn(db_pool.clone(), config.webhook_timeout),
+        storage,
+        content_cipher,
+        db: db_pool,
+        read_db: read_pool,
+        export_jobs: export::ExportJobs::default(),
+        config: std::sync::Arc::new(config),
+        ready: Default::default(),
+    };
+    let config = state.config.clone();
+    let db = state.db.clone();
+    let ready = state.ready.clone();
 
-    // Build our application with routes
-    let app = Router::new()
-        // Health check routes (before API routes)
-        .route("/health", get(health_check))
-        .route("/health/detailed", get(detailed_health_check))
-        
+    // Periodically drop finished export jobs and their files
+    let export_job_ttl = config.export_job_ttl;
+    let export_jobs = state.export_jobs.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            export_jobs.prune(export_job_ttl);
+        }
+    });
+
+    // Expired editor cursor/selection state is hidden on read; clear it out hourly
+    let editor_state_ttl = format!("-{} seconds", config.editor_state_ttl.as_secs());
+    let editor_state_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = sqlx::query("DELETE FROM editor_state WHERE updated_at < datetime('now', ?)")
+                .bind(&editor_state_ttl)
+                .execute(&editor_state_db)
+                .await
+            {
+                tracing::warn!("Failed to expire editor state: {}", e);
+            }
+        }
+    });
+
+    // In dev mode any local port is accepted. The predicate echoes the request's
+    // own origin back, so this stays compatible with allow_credentials.
+    let allow_origin = if config.cors_dev_mode {
+        tracing::warn!("CORS_DEV_MODE enabled: allowing any localhost origin");
+        AllowOrigin::predicate(|origin, _| is_local_dev_origin(origin))
+    } else {
+        AllowOrigin::list(config.allowed_origins.clone())
+    };
+    tracing::info!(
+        "CORS: origins [{}], methods [{}], headers [{}]",
+        if config.cors_dev_mode {
+            "any localhost".to_string()
+        } else {
+            config.allowed_origins.iter().filter_map(|o| o.to_str().ok()).collect::<Vec<_>>().join(", ")
+        },
+        config.cors_allowed_methods.iter().map(|m| m.as_str()).collect::<Vec<_>>().join(", "),
+        config.cors_allowed_headers.iter().map(|h| h.as_str()).collect::<Vec<_>>().join(", "),
+    );
+
+    // Serve uploaded files straight from disk for local storage, using
+    // precompressed variants when present (only text formats such as SVG get
+    // one; raster images are served as-is). Other backends go through the
+    // storage trait.
+    let uploads = match state.storage.local_dir() {
+        Some(dir) => Router::new().nest_service(
+            "/uploads",
+            ServeDir::new(dir)
+                .precompressed_br()
+                .precompressed_gzip(),
+        ),
+        None => Router::new().route("/uploads/:key", get(handlers::serve_upload)),
+    };
+
+    let query_timeout = axum::middleware::from_fn_with_state(state.clone(), middleware::query_timeout);
+    
+    // Routes still being rolled out only exist when their feature flag is on
+    let mut flagged = Router::new();
+    if state.flag_enabled("epub_export") {
+        flagged = flagged.route("/api/documents/:id/export/epub", get(handlers::export_epub));
+    }
+
+    // Browser-facing API routes, the only ones wrapped in CORS
+    let api = Router::new()
         // Document routes
         .route("/api/documents", get(handlers::list_documents))
         .route("/api/documents", post(handlers::create_document))
+        .route("/api/documents/changes", get(handlers::list_document_changes))
+        .route("/api/documents/batch", post(handlers::batch_documents))
+        .route("/api/documents/diff", get(handlers::diff_documents))
         .route("/api/documents/:id", get(handlers::get_document))
         .route("/api/documents/:id", put(handlers::update_document))
         .route("/api/documents/:id", delete(handlers::delete_document))
+        .route("/api/documents/:id/stats", get(handlers::document_stats))
+        .route("/api/documents/:id/last-edit", get(handlers::get_document_last_edit))
+        .route("/api/documents/:id/cover", put(handlers::set_document_cover))
+        .route("/api/documents/:id/publish", post(handlers::publish_document))
+        .route("/api/documents/:id/unpublish", post(handlers::unpublish_document))
+        .route("/api/documents/:id/full", get(handlers::get_document_full))
+        .route("/api/documents/:id/content", get(handlers::get_document_content))
+        .route("/api/documents/:id/replace", post(handlers::replace_text))
+        .route("/api/documents/:id/toc", get(handlers::get_document_toc))
+        .route("/api/documents/:id/validate-tree", get(handlers::validate_tree))
+        .route("/api/documents/:id/repair-tree", post(handlers::repair_tree))
+        .route("/api/documents/:id/normalize-order", post(handlers::normalize_order))
+        .route("/api/documents/:id/export/csv", get(handlers::export_csv))
+        .route("/api/documents/:id/import/csv", post(handlers::import_csv))
+        .route("/api/documents/:id/export/latex", get(handlers::export_latex))
+        .route("/api/documents/:id/export/equations", get(handlers::export_equations))
+        .route("/api/documents/:id/read", get(handlers::read_document))
+        .route("/api/documents/:id/export/estimate", get(handlers::estimate_export))
+        .route("/api/documents/:id/merge", post(handlers::merge_documents))
+        .route("/api/documents/:id/snapshot", post(handlers::create_snapshot))
+        .route("/api/documents/:id/snapshots", get(handlers::list_snapshots))
+        .route("/api/documents/:id/restore/:snapshot_id", post(handlers::restore_snapshot))
+        
+        // Dashboard counters
+        .route("/api/stats/summary", get(handlers::stats_summary))
+        
+        // Tag routes
+        .route("/api/tags/:tag/documents", get(handlers::list_tagged_documents))
+        .route("/api/tags/:tag/documents", post(handlers::tag_documents))
+        .route("/api/tags/:tag/documents", delete(handlers::untag_documents))
         
         // Node routes
         .route("/api/nodes", post(handlers::create_node))
         .route("/api/nodes/:id", get(handlers::get_node))
         .route("/api/nodes/:id", put(handlers::update_node))
         .route("/api/nodes/:id", delete(handlers::delete_node))
+        .route("/api/nodes/:id/type", patch(handlers::change_node_type))
+        .route("/api/nodes/:id/reorder", post(handlers::reorder_node))
+        .route("/api/nodes/:id/move-up", post(handlers::move_node_up))
+        .route("/api/nodes/:id/move-down", post(handlers::move_node_down))
+        .route("/api/nodes/:id/siblings", get(handlers::list_siblings))
+        .route("/api/nodes/:id/place", post(handlers::place_node))
+        .route("/api/nodes/:id/duplicate", post(handlers::duplicate_node))
+        .route("/api/nodes/:id/editor-state", get(handlers::get_editor_state))
+        .route("/api/nodes/:id/editor-state", put(handlers::save_editor_state))
+        .route("/api/nodes/:id/attachments", get(handlers::list_attachments))
+        .route("/api/nodes/:id/attachments", post(handlers::add_attachment))
+        .route("/api/nodes/:id/attachments/reorder", post(handlers::reorder_attachments))
+        .route("/api/nodes/:id/attachments/:attachment_id", delete(handlers::delete_attachment))
+        .route("/api/nodes/:id/comments", get(handlers::list_comments).post(handlers::add_comment))
+        .route("/api/nodes/:id/comments/:comment_id", patch(handlers::update_comment).delete(handlers::delete_comment))
         .route("/api/documents/:doc_id/nodes", get(handlers::list_nodes))
         
         // Content routes
         .route("/api/content/:node_id", get(handlers::get_content))
         .route("/api/content/:node_id", put(handlers::save_content))
+        .route("/api/content/:node_id/text", get(handlers::get_content_text))
+        .route("/api/content/:node_id/restore-preview/:version_id", get(handlers::restore_preview))
+        .route("/api/content/:node_id/ops", post(handlers::apply_content_ops))
+        
+        // Upload metadata
+        .route("/api/uploads/:id", put(handlers::update_upload))
+        
+        // Export stylesheets
+        .route("/api/stylesheets", get(handlers::list_stylesheets))
+        .route("/api/stylesheets", post(handlers::create_stylesheet))
+        .route("/api/stylesheets/:id", get(handlers::get_stylesheet))
+        .route("/api/stylesheets/:id", delete(handlers::delete_stylesheet))
+        
+        // Everything above is database-bound; uploads and exports are not
+        .route_layer(query_timeout.clone())
         
         // File upload
-        .route("/api/upload", post(handlers::upload_file))
+        .route(
+            "/api/upload",
+            post(handlers::upload_file)
+                // Room for the multipart framing and text fields around the file
+                .layer(DefaultBodyLimit::max(config.max_upload_bytes + 64 * 1024)),
+        )
+        .route(
+            "/api/uploads/import-url",
+            post(handlers::import_upload_url).layer(axum::middleware::from_fn_with_state(
+                middleware::RateLimit::per_minute(config.import_url_per_minute),
+                middleware::rate_limit,
+            )),
+        )
         
-        // PDF export
+        // Exports
         .route("/api/export/pdf", post(handlers::export_pdf))
+        .route("/api/export/all", get(handlers::export_all))
+        .route("/api/export/jobs/:id", get(handlers::get_export_job))
+        .route("/api/export/jobs/:id", delete(handlers::cancel_export_job))
+        .route("/api/export/jobs/:id/download", get(handlers::download_export))
         
-        // Serve uploaded files
-        .nest_service("/uploads", ServeDir::new("../uploads"))
+        .merge(flagged)
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::log_bodies))
+        .merge(uploads)
         
+        // Router::layer only wraps routes added so far, so the internal
+        // routes merged below stay outside CORS
         .layer(
             CorsLayer::new()
-                .allow_origin(AllowOrigin::list(allowed_origins))
-                .allow_methods([
-                    axum::http::Method::GET,
-                    axum::http::Method::POST,
-                    axum::http::Method::PUT,
-                    axum::http::Method::DELETE,
-                    axum::http::Method::OPTIONS,
-                ])
-                .allow_headers([
-                    axum::http::header::CONTENT_TYPE,
-                    axum::http::header::AUTHORIZATION,
+                .allow_origin(allow_origin)
+                .allow_methods(config.cors_allowed_methods.clone())
+                .allow_headers(config.cors_allowed_headers.clone())
+                .expose_headers([
+                    axum::http::header::LINK,
+                    axum::http::HeaderName::from_static("x-total-count"),
+                    axum::http::HeaderName::from_static("x-export-stale"),
                 ])
                 .allow_credentials(true),
+        );
+
+    // Health probes and admin endpoints are for operators and monitoring, not
+    // browsers: without CORS headers a web page on another origin cannot read them
+    let internal = Router::new()
+        .route("/health", get(health_check))
+        .route("/health/ready", get(readiness_check))
+        .route("/health/detailed", get(detailed_health_check))
+        
+        // Admin routes
+        .route("/api/admin/audit", get(handlers::list_audit_log))
+        .route("/api/admin/orphans", get(handlers::list_orphans))
+        .route("/api/admin/orphans/cleanup", post(handlers::cleanup_orphans))
+        .route("/api/admin/recompute-word-counts", post(handlers::recompute_word_counts))
+        .route("/api/admin/webhooks", get(handlers::list_webhooks).post(handlers::create_webhook))
+        .route("/api/admin/webhooks/:id", delete(handlers::delete_webhook))
+        
+        .route_layer(query_timeout);
+
+    // The built frontend answers everything else, with index.html standing
+    // in for client-side routes. Unknown /api paths still get a plain 404.
+    let mut frontend = Router::new();
+    if config.serve_frontend {
+        let index = config.frontend_dir.join("index.html");
+        if !index.is_file() {
+            tracing::warn!("SERVE_FRONTEND is set but {} does not exist; build the frontend first", index.display());
+        }
+        tracing::info!("Serving the frontend from {}", config.frontend_dir.display());
+        frontend = frontend
+            .route("/api/*path", any(|| async { axum::http::StatusCode::NOT_FOUND }))
+            .fallback_service(ServeDir::new(&config.frontend_dir).fallback(ServeFile::new(index)));
+    }
+
+    // Backpressure bounds request duration and the number of requests in
+    // flight. It and the optional response envelope and pretty printing
+    // apply to both groups. Pretty printing is outermost so it sees the envelope;
+    // request transactions are innermost so they settle on the handler's own status.
+    let app = Router::new()
+        .merge(internal)
+        .merge(api)
+        .merge(frontend)
+        .layer(axum::middleware::from_fn(tx::transactions))
+        .layer(axum::middleware::from_fn(middleware::envelope))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), middleware::pretty_json))
+        .layer(TimeoutLayer::new(config.request_timeout))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(middleware::handle_overload))
+                .load_shed()
+                .concurrency_limit(config.max_in_flight),
         )
+        // One span per request, so query spans and logs can be tied to it
+        .layer(TraceLayer::new_for_http())
         .with_state(state);
 
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| "3001".to_string())
-        .parse::<u16>()
-        .unwrap_or(3001);
-    
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port))
-        .await?;
-    
-    tracing::info!("Backend server listening on {}", listener.local_addr()?);
-    
-    axum::serve(listener, app).await?;
+    // Report ready from here on, and not ready again as soon as a shutdown
+    // signal arrives so load balancers stop routing while requests drain
+    ready.store(true, std::sync::atomic::Ordering::Release);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        ready.store(false, std::sync::atomic::Ordering::Release);
+    });
+
+    // A Unix socket replaces TCP when LISTEN_UDS is set
+    if let Some(path) = config.listen_uds.as_deref() {
+        #[cfg(unix)]
+        uds::serve(path, config.listen_uds_mode, app).await?;
+        #[cfg(not(unix))]
+        anyhow::bail!("LISTEN_UDS={:?} is set, but Unix sockets are not supported on this platform", path);
+    } else {
+        let listener = tokio::net::TcpListener::bind((config.host, config.port))
+            .await
+            .map_err(|e| anyhow::anyhow!("Cannot listen on {}:{} (BIND_ADDR/PORT): {}", config.host, config.port, e))?;
+        
+        tracing::info!("Backend server listening on {}", listener.local_addr()?);
+        
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal())
+            .await?;
+    }
+
+    // In-flight requests have finished, so the copy has every acknowledged write
+    if config.backup_on_shutdown {
+        match backup::write_copy(&db, &config.backup_dir).await {
+            Ok((path, size)) => tracing::info!("Shutdown backup written to {} ({} bytes)", path.display(), size),
+            Err(e) => tracing::error!("Shutdown backup to {} failed: {}", config.backup_dir.display(), e),
+        }
+    }
 
     Ok(())
 }
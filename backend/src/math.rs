@@ -0,0 +1,535 @@
+//! Equation blocks in either LaTeX or MathML.
+//!
+//! An equation (or math) block keeps its source in props: `latex` by
+//! default, or `mathml` when `props.format` is "mathml". Saves check the
+//! source for its format. HTML exports embed MathML as-is (after stripping
+//! it down to presentation markup), and everything that wants LaTeX (the
+//! LaTeX export, plain text, search) gets MathML converted.
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EquationFormat {
+    Latex,
+    Mathml,
+}
+
+impl EquationFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EquationFormat::Latex => "latex",
+            EquationFormat::Mathml => "mathml",
+        }
+    }
+}
+
+/// Whether a block is an equation block, whose source is in props
+pub fn is_equation_block(block: &Value) -> bool {
+    matches!(block.get("type").and_then(|t| t.as_str()), Some("equation") | Some("math"))
+}
+
+fn block_format(block: &Value) -> Result<EquationFormat, String> {
+    match block.pointer("/props/format").and_then(|f| f.as_str()) {
+        None | Some("latex") => Ok(EquationFormat::Latex),
+        Some("mathml") => Ok(EquationFormat::Mathml),
+        Some(other) => Err(format!("unknown equation format {:?}; expected latex or mathml", other)),
+    }
+}
+
+/// LaTeX of an equation block, converted from MathML when that's what it
+/// holds; None when the block keeps no source in props
+pub fn block_latex(block: &Value) -> Option<String> {
+    match block_format(block).ok()? {
+        EquationFormat::Latex => block.pointer("/props/latex").and_then(|l| l.as_str()).map(str::to_string),
+        EquationFormat::Mathml => {
+            let source = block.pointer("/props/mathml").and_then(|m| m.as_str())?;
+            parse(source).ok().map(|root| to_latex(&root))
+        }
+    }
+}
+
+/// Sanitized MathML of the first MathML equation block among `blocks`
+pub fn blocks_mathml(blocks: &[Value]) -> Option<String> {
+    blocks.iter().find_map(|block| {
+        if is_equation_block(block) {
+            if let Some(markup) = block_mathml(block) {
+                return Some(markup);
+            }
+        }
+        block.get("children")
+            .and_then(|c| c.as_array())
+            .and_then(|children| blocks_mathml(children))
+    })
+}
+
+/// Sanitized MathML of one block, if it is a MathML equation block
+pub fn block_mathml(block: &Value) -> Option<String> {
+    if block_format(block).ok()? != EquationFormat::Mathml {
+        return None;
+    }
+    let source = block.pointer("/props/mathml").and_then(|m| m.as_str())?;
+    let root = parse(source).ok()?;
+    let mut out = String::new();
+    write_mathml(&mut out, &root);
+    Some(out)
+}
+
+/// The format of the first equation block in a node's content_json; LaTeX
+/// when there is none, as equation nodes keep LaTeX as plain text
+pub fn content_format(content_json: &str) -> EquationFormat {
+    fn find(blocks: &[Value]) -> Option<EquationFormat> {
+        blocks.iter().find_map(|block| {
+            if is_equation_block(block) {
+                return block_format(block).ok();
+            }
+            block.get("children").and_then(|c| c.as_array()).and_then(|children| find(children))
+        })
+    }
+
+    let blocks: Vec<Value> = serde_json::from_str(content_json).unwrap_or_default();
+    find(&blocks).unwrap_or(EquationFormat::Latex)
+}
+
+/// Check every equation block in `blocks` against its format, returning
+/// the first problem found
+pub fn validate_blocks(blocks: &[Value]) -> Result<(), String> {
+    for block in blocks {
+        if is_equation_block(block) {
+            validate_block(block)?;
+        }
+        if let Some(children) = block.get("children").and_then(|c| c.as_array()) {
+            validate_blocks(children)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_block(block: &Value) -> Result<(), String> {
+    match block_format(block)? {
+        EquationFormat::Latex => match block.pointer("/props/latex") {
+            None => Ok(()),
+            Some(Value::String(latex)) => check_latex(latex),
+            Some(_) => Err("equation props.latex must be a string".to_string()),
+        },
+        EquationFormat::Mathml => match block.pointer("/props/mathml") {
+            Some(Value::String(mathml)) => parse(mathml).map(|_| ()),
+            Some(_) => Err("equation props.mathml must be a string".to_string()),
+            None => Err("an equation with format mathml needs props.mathml".to_string()),
+        },
+    }
+}
+
+/// Braces must balance, not counting escaped ones
+fn check_latex(latex: &str) -> Result<(), String> {
+    let mut depth = 0usize;
+    let mut chars = latex.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '{' => depth += 1,
+            '}' => {
+                depth = depth.checked_sub(1).ok_or("equation LaTeX has an unmatched }")?;
+            }
+            _ => {}
+        }
+    }
+    match depth {
+        0 => Ok(()),
+        _ => Err("equation LaTeX has an unclosed {".to_string()),
+    }
+}
+
+// Presentation MathML. Anything else, including annotation-xml (which can
+// carry HTML), is refused on save.
+const ELEMENTS: &[&str] = &[
+    "math", "mrow", "mi", "mn", "mo", "ms", "mtext", "mspace", "msup", "msub", "msubsup",
+    "mfrac", "msqrt", "mroot", "mover", "munder", "munderover", "mfenced", "mtable", "mtr",
+    "mtd", "mstyle", "mpadded", "mphantom", "menclose", "merror", "semantics", "annotation",
+];
+
+// Attributes kept when MathML is embedded in HTML
+const ATTRIBUTES: &[&str] = &[
+    "display", "mathvariant", "stretchy", "fence", "separator", "accent", "accentunder",
+    "linethickness", "encoding", "open", "close", "separators", "columnalign", "rowalign",
+    "displaystyle", "scriptlevel", "width", "height", "depth", "lspace", "rspace", "notation",
+];
+
+// Nesting limit, so hostile input can't recurse without bound
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug)]
+enum MathNode {
+    Element { name: String, attributes: Vec<(String, String)>, children: Vec<MathNode> },
+    Text(String),
+}
+
+impl MathNode {
+    fn attribute(&self, key: &str) -> Option<&str> {
+        match self {
+            MathNode::Element { attributes, .. } => attributes.iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.as_str()),
+            MathNode::Text(_) => None,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            MathNode::Text(text) => text.clone(),
+            MathNode::Element { children, .. } => children.iter().map(MathNode::text).collect(),
+        }
+    }
+}
+
+/// Parse MathML with a <math> root into a tree
+fn parse(source: &str) -> Result<MathNode, String> {
+    let mut parser = Parser { input: source, pos: 0 };
+    parser.skip_prolog()?;
+    let root = parser.element(0)?;
+    parser.skip_misc()?;
+    if parser.pos < parser.input.len() {
+        return Err("unexpected content after the closing </math>".to_string());
+    }
+    match &root {
+        MathNode::Element { name, .. } if name == "math" => Ok(root),
+        _ => Err("MathML must have a <math> root element".to_string()),
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    // Whitespace and comments between elements
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<!--") {
+                let end = self.rest().find("-->").ok_or("unterminated comment in MathML")?;
+                self.pos += end + 3;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_prolog(&mut self) -> Result<(), String> {
+        self.skip_misc()?;
+        if self.rest().starts_with("<?xml") {
+            let end = self.rest().find("?>").ok_or("unterminated XML declaration")?;
+            self.pos += end + 2;
+        }
+        self.skip_misc()?;
+        if self.rest().starts_with("<!") {
+            return Err("DOCTYPE and CDATA sections are not allowed in MathML".to_string());
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.')))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err("expected a name in MathML".to_string());
+        }
+        let name = &self.rest()[..len];
+        self.pos += len;
+        // Namespace prefixes, as in <m:math>, are dropped
+        Ok(name.rsplit(':').next().unwrap_or(name).to_string())
+    }
+
+    fn element(&mut self, depth: usize) -> Result<MathNode, String> {
+        if depth > MAX_DEPTH {
+            return Err("MathML is nested too deeply".to_string());
+        }
+        if !self.rest().starts_with('<') {
+            return Err("expected a MathML element".to_string());
+        }
+        self.pos += 1;
+        let name = self.name()?;
+        if !ELEMENTS.contains(&name.as_str()) {
+            return Err(format!("<{}> is not a supported MathML element", name));
+        }
+
+        let mut attributes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(MathNode::Element { name, attributes, children: Vec::new() });
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let key = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(format!("attribute {} in <{}> has no value", key, name));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = self.rest().chars().next().filter(|c| *c == '"' || *c == '\'')
+                .ok_or_else(|| format!("attribute {} in <{}> must be quoted", key, name))?;
+            self.pos += 1;
+            let end = self.rest().find(quote).ok_or("unterminated attribute value in MathML")?;
+            let value = decode_entities(&self.rest()[..end])?;
+            self.pos += end + 1;
+            attributes.push((key, value));
+        }
+
+        let mut children = Vec::new();
+        loop {
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                let closing = self.name()?;
+                self.skip_whitespace();
+                if closing != name || !self.rest().starts_with('>') {
+                    return Err(format!("<{}> is closed by </{}>", name, closing));
+                }
+                self.pos += 1;
+                return Ok(MathNode::Element { name, attributes, children });
+            }
+            if self.rest().starts_with("<!--") {
+                self.skip_misc()?;
+            } else if self.rest().starts_with("<!") || self.rest().starts_with("<?") {
+                return Err("CDATA sections and processing instructions are not allowed in MathML".to_string());
+            } else if self.rest().starts_with('<') {
+                children.push(self.element(depth + 1)?);
+            } else if self.rest().is_empty() {
+                return Err(format!("<{}> is never closed", name));
+            } else {
+                let end = self.rest().find('<').unwrap_or(self.rest().len());
+                let text = decode_entities(&self.rest()[..end])?;
+                self.pos += end;
+                if !text.trim().is_empty() {
+                    children.push(MathNode::Text(text.trim().to_string()));
+                }
+            }
+        }
+    }
+}
+
+fn decode_entities(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or("unterminated entity in MathML")? + start;
+        let entity = &rest[start + 1..end];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse::<u32>))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        out.push(decoded.ok_or_else(|| format!("unknown entity &{}; in MathML", entity))?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Re-serialize a parsed tree, keeping only the allowed attributes
+fn write_mathml(out: &mut String, node: &MathNode) {
+    match node {
+        MathNode::Text(text) => out.push_str(&escape_xml(text)),
+        MathNode::Element { name, attributes, children } => {
+            out.push('<');
+            out.push_str(name);
+            if name == "math" {
+                out.push_str(" xmlns=\"http://www.w3.org/1998/Math/MathML\"");
+            }
+            for (key, value) in attributes.iter().filter(|(key, _)| ATTRIBUTES.contains(&key.as_str())) {
+                out.push_str(&format!(" {}=\"{}\"", key, escape_xml(value)));
+            }
+            out.push('>');
+            for child in children {
+                write_mathml(out, child);
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+    }
+}
+
+/// LaTeX for a parsed MathML tree. Covers the common presentation elements;
+/// a TeX annotation, when the MathML carries one, is used as it is.
+fn to_latex(root: &MathNode) -> String {
+    let latex = latex_of(root);
+    latex.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn latex_of(node: &MathNode) -> String {
+    let (name, children) = match node {
+        MathNode::Text(text) => return symbols(text),
+        MathNode::Element { name, children, .. } => (name.as_str(), children),
+    };
+    let arg = |i: usize| children.get(i).map(latex_of).unwrap_or_default();
+    let all = || children.iter().map(latex_of).collect::<Vec<_>>().join(" ");
+
+    match name {
+        "semantics" => children.iter()
+            .find(|child| {
+                matches!(child, MathNode::Element { name, .. } if name == "annotation")
+                    && matches!(child.attribute("encoding"), Some("application/x-tex") | Some("TeX") | Some("text/x-latex"))
+            })
+            .map(MathNode::text)
+            .unwrap_or_else(|| arg(0)),
+        "annotation" => String::new(),
+        "mi" => {
+            let text = node.text();
+            match text.chars().count() {
+                0 | 1 => symbols(&text),
+                _ if FUNCTIONS.contains(&text.as_str()) => format!("\\{} ", text),
+                _ => format!("\\mathrm{{{}}}", text),
+            }
+        }
+        "mn" | "mo" => symbols(&node.text()),
+        "mtext" | "ms" => format!("\\text{{{}}}", escape_text(&node.text())),
+        "mspace" => "\\, ".to_string(),
+        "msup" => format!("{{{}}}^{{{}}}", arg(0), arg(1)),
+        "msub" => format!("{{{}}}_{{{}}}", arg(0), arg(1)),
+        "msubsup" => format!("{{{}}}_{{{}}}^{{{}}}", arg(0), arg(1), arg(2)),
+        "mfrac" => format!("\\frac{{{}}}{{{}}}", arg(0), arg(1)),
+        "msqrt" => format!("\\sqrt{{{}}}", all()),
+        "mroot" => format!("\\sqrt[{}]{{{}}}", arg(1), arg(0)),
+        "mover" => {
+            let accent = children.get(1).map(MathNode::text).unwrap_or_default();
+            match accent.trim() {
+                "¯" | "‾" | "_" => format!("\\overline{{{}}}", arg(0)),
+                "^" | "ˆ" => format!("\\hat{{{}}}", arg(0)),
+                "~" | "˜" => format!("\\tilde{{{}}}", arg(0)),
+                "→" | "⃗" => format!("\\vec{{{}}}", arg(0)),
+                "˙" | "." => format!("\\dot{{{}}}", arg(0)),
+                _ if is_big_operator(children.first()) => format!("{}^{{{}}}", arg(0), arg(1)),
+                _ => format!("\\overset{{{}}}{{{}}}", arg(1), arg(0)),
+            }
+        }
+        "munder" if is_big_operator(children.first()) => format!("{}_{{{}}}", arg(0), arg(1)),
+        "munder" => format!("\\underset{{{}}}{{{}}}", arg(1), arg(0)),
+        "munderover" if is_big_operator(children.first()) => format!("{}_{{{}}}^{{{}}}", arg(0), arg(1), arg(2)),
+        "munderover" => format!("\\overset{{{}}}{{\\underset{{{}}}{{{}}}}}", arg(2), arg(1), arg(0)),
+        "mfenced" => {
+            let open = node.attribute("open").unwrap_or("(");
+            let close = node.attribute("close").unwrap_or(")");
+            let separator = node.attribute("separators").and_then(|s| s.trim().chars().next()).unwrap_or(',');
+            let inner = children.iter().map(latex_of).collect::<Vec<_>>().join(&format!("{} ", separator));
+            format!("\\left{} {} \\right{}", delimiter(open), inner, delimiter(close))
+        }
+        "mtable" => {
+            let rows: Vec<String> = children.iter()
+                .map(|row| match row {
+                    MathNode::Element { children: cells, .. } => cells.iter().map(latex_of).collect::<Vec<_>>().join(" & "),
+                    MathNode::Text(text) => symbols(text),
+                })
+                .collect();
+            format!("\\begin{{matrix}} {} \\end{{matrix}}", rows.join(" \\\\ "))
+        }
+        "mphantom" => format!("\\phantom{{{}}}", all()),
+        "menclose" => format!("\\boxed{{{}}}", all()),
+        // math, mrow, mstyle, mpadded, merror, mtr, mtd
+        _ => all(),
+    }
+}
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh",
+    "tanh", "log", "ln", "exp", "lim", "max", "min", "sup", "inf", "det", "gcd", "deg", "dim",
+];
+
+fn is_big_operator(node: Option<&MathNode>) -> bool {
+    let text = node.map(MathNode::text).unwrap_or_default();
+    matches!(text.trim(), "∑" | "∏" | "∐" | "∫" | "∮" | "⋃" | "⋂" | "lim" | "max" | "min")
+}
+
+fn delimiter(fence: &str) -> String {
+    match fence {
+        "" => ".".to_string(),
+        "{" => "\\{".to_string(),
+        "}" => "\\}".to_string(),
+        "‖" => "\\|".to_string(),
+        "⟨" => "\\langle".to_string(),
+        "⟩" => "\\rangle".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn escape_text(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\\' => "\\textbackslash{}".to_string(),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => format!("\\{}", c),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+/// Unicode symbols as LaTeX commands, everything else as it is
+fn symbols(text: &str) -> String {
+    text.chars()
+        .map(|c| match symbol(c) {
+            Some(command) => format!("{} ", command),
+            None => match c {
+                '{' => "\\{".to_string(),
+                '}' => "\\}".to_string(),
+                '−' => "-".to_string(),
+                '\u{2061}' | '\u{2062}' | '\u{2063}' => String::new(),
+                _ => c.to_string(),
+            },
+        })
+        .collect()
+}
+
+fn symbol(c: char) -> Option<&'static str> {
+    Some(match c {
+        'α' => "\\alpha", 'β' => "\\beta", 'γ' => "\\gamma", 'δ' => "\\delta",
+        'ε' => "\\epsilon", 'ζ' => "\\zeta", 'η' => "\\eta", 'θ' => "\\theta",
+        'ι' => "\\iota", 'κ' => "\\kappa", 'λ' => "\\lambda", 'μ' => "\\mu",
+        'ν' => "\\nu", 'ξ' => "\\xi", 'π' => "\\pi", 'ρ' => "\\rho",
+        'σ' => "\\sigma", 'τ' => "\\tau", 'υ' => "\\upsilon", 'φ' => "\\phi",
+        'χ' => "\\chi", 'ψ' => "\\psi", 'ω' => "\\omega",
+        'Γ' => "\\Gamma", 'Δ' => "\\Delta", 'Θ' => "\\Theta", 'Λ' => "\\Lambda",
+        'Ξ' => "\\Xi", 'Π' => "\\Pi", 'Σ' => "\\Sigma", 'Φ' => "\\Phi",
+        'Ψ' => "\\Psi", 'Ω' => "\\Omega",
+        '∑' => "\\sum", '∏' => "\\prod", '∐' => "\\coprod", '∫' => "\\int", '∮' => "\\oint",
+        '×' => "\\times", '·' | '⋅' => "\\cdot", '÷' => "\\div", '±' => "\\pm", '∓' => "\\mp",
+        '≤' => "\\leq", '≥' => "\\geq", '≠' => "\\neq", '≈' => "\\approx", '≡' => "\\equiv",
+        '∼' => "\\sim", '∝' => "\\propto", '∞' => "\\infty", '∂' => "\\partial", '∇' => "\\nabla",
+        '∈' => "\\in", '∉' => "\\notin", '⊂' => "\\subset", '⊆' => "\\subseteq",
+        '⊃' => "\\supset", '⊇' => "\\supseteq", '∪' => "\\cup", '∩' => "\\cap",
+        '⋃' => "\\bigcup", '⋂' => "\\bigcap", '∅' => "\\emptyset",
+        '∀' => "\\forall", '∃' => "\\exists", '¬' => "\\neg", '∧' => "\\wedge", '∨' => "\\vee",
+        '→' => "\\to", '←' => "\\leftarrow", '↔' => "\\leftrightarrow",
+        '⇒' => "\\Rightarrow", '⇐' => "\\Leftarrow", '⇔' => "\\Leftrightarrow",
+        '…' => "\\ldots", '⋯' => "\\cdots", '°' => "^\\circ", '′' => "'",
+        '⟨' => "\\langle", '⟩' => "\\rangle", '‖' => "\\|", 'ℏ' => "\\hbar", 'ℓ' => "\\ell",
+        _ => return None,
+    })
+}
@@ -0,0 +1,295 @@
+use crate::AppState;
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Bound the time a request may spend on database work.
+///
+/// Handlers run their queries inline, so timing out the handler future drops
+/// any in-flight query and releases the pooled connection instead of letting
+/// a hung statement hold it indefinitely.
+pub async fn query_timeout(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let method = request.method().clone();
+
+    match tokio::time::timeout(state.config.query_timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!(
+                "Query timed out after {:?} on {} {}",
+                state.config.query_timeout,
+                method,
+                route
+            );
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+/// Fixed-window limit on how often a route may be called. There are no user
+/// accounts to count against, so the limit is for the whole instance.
+#[derive(Clone)]
+pub struct RateLimit {
+    per_minute: u32,
+    // Start of the current window and requests let through in it
+    window: std::sync::Arc<std::sync::Mutex<(std::time::Instant, u32)>>,
+}
+
+impl RateLimit {
+    pub fn per_minute(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            window: std::sync::Arc::new(std::sync::Mutex::new((std::time::Instant::now(), 0))),
+        }
+    }
+}
+
+/// Answer 429 with Retry-After once the route's limit for the minute is used up
+pub async fn rate_limit(State(limit): State<RateLimit>, request: Request, next: Next) -> Response {
+    const WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+    let retry_after = {
+        let mut window = limit.window.lock().unwrap();
+        if window.0.elapsed() >= WINDOW {
+            *window = (std::time::Instant::now(), 0);
+        }
+        if window.1 < limit.per_minute {
+            window.1 += 1;
+            None
+        } else {
+            Some(WINDOW.saturating_sub(window.0.elapsed()).as_secs().max(1))
+        }
+    };
+
+    match retry_after {
+        None => next.run(request).await,
+        Some(seconds) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, seconds.to_string())],
+            "Too many requests, please retry later",
+        )
+            .into_response(),
+    }
+}
+
+/// Map errors from the load-shedding stack into responses
+pub async fn handle_overload(err: tower::BoxError) -> (StatusCode, String) {
+    if err.is::<tower::load_shed::error::Overloaded>() {
+        (StatusCode::SERVICE_UNAVAILABLE, "Server is busy, please retry".to_string())
+    } else {
+        tracing::error!("Unhandled middleware error: {}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error".to_string())
+    }
+}
+
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"))
+}
+
+/// Wrap JSON responses in `{ "data": ... }` (or `{ "error": ... }`) for
+/// clients that send `X-Envelope: true`. Everyone else gets the bare body,
+/// and non-JSON responses (files, exports) are never touched.
+pub async fn envelope(request: Request, next: Next) -> Response {
+    let wants_envelope = request
+        .headers()
+        .get("x-envelope")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+
+    let response = next.run(request).await;
+    if !wants_envelope {
+        return response;
+    }
+
+    let status = response.status();
+    let is_json = is_json(&response);
+
+    // Successful non-JSON bodies and empty successes pass through as they are
+    if status.is_success() && !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Failed to buffer response for envelope: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let body: Option<serde_json::Value> = if is_json {
+        serde_json::from_slice(&bytes).ok()
+    } else {
+        None
+    };
+
+    let wrapped = if status.is_success() {
+        let mut wrapped = serde_json::json!({ "data": body });
+        if let Some(total) = parts
+            .headers
+            .get("x-total-count")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<i64>().ok())
+        {
+            wrapped["meta"] = serde_json::json!({ "total": total });
+        }
+        wrapped
+    } else {
+        let message = match (&body, bytes.is_empty()) {
+            (None, false) => String::from_utf8_lossy(&bytes).into_owned(),
+            _ => status.canonical_reason().unwrap_or("Error").to_string(),
+        };
+        let mut error = serde_json::json!({ "status": status.as_u16(), "message": message });
+        if let Some(details) = body {
+            error["details"] = details;
+        }
+        serde_json::json!({ "error": error })
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    Response::from_parts(parts, Body::from(wrapped.to_string()))
+}
+
+/// Re-serialize JSON responses with indentation when DEBUG_PRETTY_JSON is set
+/// or the request asks for `?pretty=true`. Compact output stays the default.
+pub async fn pretty_json(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let wants_pretty = state.config.pretty_json
+        || request.uri().query().is_some_and(|query| {
+            query
+                .split('&')
+                .any(|param| matches!(param, "pretty" | "pretty=1" | "pretty=true"))
+        });
+
+    let response = next.run(request).await;
+    if !wants_pretty || !is_json(&response) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Failed to buffer response for pretty printing: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let pretty = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .and_then(|value| serde_json::to_vec_pretty(&value));
+    let body = match pretty {
+        Ok(pretty) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Body::from(pretty)
+        }
+        Err(_) => Body::from(bytes),
+    };
+
+    Response::from_parts(parts, body)
+}
+
+// Longest body logged by log_bodies, in bytes; the rest is elided
+const MAX_LOGGED_BODY: usize = 2048;
+
+// Only text bodies are logged; uploads and other binary payloads never are
+fn is_text(headers: &axum::http::HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json") || value.starts_with("text/"))
+}
+
+fn loggable_body(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_LOGGED_BODY)]);
+    if bytes.len() > MAX_LOGGED_BODY {
+        format!("{}... ({} bytes total)", text, bytes.len())
+    } else {
+        text.into_owned()
+    }
+}
+
+/// Log request and response bodies at debug level when DEBUG_LOG_BODIES is
+/// set, as a support aid. Credentials in headers are redacted and non-text
+/// bodies (file uploads, images, exports) are only logged by type.
+pub async fn log_bodies(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !state.config.log_bodies {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let headers: Vec<String> = parts
+        .headers
+        .iter()
+        .map(|(name, value)| match *name {
+            header::AUTHORIZATION | header::COOKIE => format!("{}: [redacted]", name),
+            _ => format!("{}: {}", name, value.to_str().unwrap_or("[binary]")),
+        })
+        .collect();
+
+    let body = if is_text(&parts.headers) {
+        let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("Failed to buffer request for body logging: {}", e);
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        };
+        tracing::debug!(
+            "Request {} {} [{}] body: {}",
+            parts.method,
+            parts.uri,
+            headers.join(", "),
+            loggable_body(&bytes)
+        );
+        Body::from(bytes)
+    } else {
+        let content_type = parts.headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+        tracing::debug!(
+            "Request {} {} [{}] body: [{} not logged]",
+            parts.method,
+            parts.uri,
+            headers.join(", "),
+            content_type.unwrap_or("no content type")
+        );
+        body
+    };
+
+    let method = parts.method.clone();
+    let uri = parts.uri.clone();
+    let response = next.run(Request::from_parts(parts, body)).await;
+    if !is_text(response.headers()) {
+        tracing::debug!("Response {} {} {}: [body not logged]", method, uri, response.status());
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Failed to buffer response for body logging: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    tracing::debug!("Response {} {} {}: {}", method, uri, parts.status, loggable_body(&bytes));
+
+    Response::from_parts(parts, Body::from(bytes))
+}
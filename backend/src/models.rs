@@ -1,17 +1,177 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use sqlx::FromRow;
+use std::collections::HashMap;
+
+/// Serialize a SQLite timestamp ("YYYY-MM-DD HH:MM:SS", always UTC) as RFC 3339.
+/// Values that don't parse are passed through unchanged.
+pub fn serialize_timestamp<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    match chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f") {
+        Ok(naive) => serializer.serialize_str(
+            &naive.and_utc().to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+        ),
+        Err(_) => serializer.serialize_str(value),
+    }
+}
+
+/// Like `serialize_timestamp`, for nullable columns
+pub fn serialize_optional_timestamp<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serialize_timestamp(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct Document {
     pub id: i64,
     pub title: String,
+    #[serde(serialize_with = "serialize_timestamp")]
     pub created_at: String,
+    #[serde(serialize_with = "serialize_timestamp")]
     pub updated_at: String,
+    /// Display name of whoever last changed the document or anything in it
+    pub last_edited_by: Option<String>,
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    pub last_edited_at: Option<String>,
+    /// How sibling nodes are ordered for display; the server default when unset
+    pub child_sort: Option<ChildSort>,
+    pub status: DocumentStatus,
+    /// Content is encrypted at rest; fixed when the document is created
+    pub encrypted: bool,
+    /// Image for the document's card: the one set explicitly, or in listings
+    /// the first figure's image when none is
+    pub cover_image_url: Option<String>,
+    /// Words across all of the document's content, maintained on save
+    #[sqlx(default)]
+    #[serde(default)]
+    pub word_count: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum DocumentStatus {
+    Draft,
+    Published,
+}
+
+impl DocumentStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DocumentStatus::Draft => "draft",
+            DocumentStatus::Published => "published",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetCoverRequest {
+    /// URL of an uploaded image, or null to go back to the automatic cover
+    pub image_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DocumentListQuery {
+    pub status: Option<DocumentStatus>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateDocumentRequest {
     pub title: String,
+    pub child_sort: Option<ChildSort>,
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ChildSort {
+    /// By order_index, as arranged by the user
+    Manual,
+    TitleAsc,
+    TitleDesc,
+    Created,
+}
+
+impl ChildSort {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "manual" => Some(ChildSort::Manual),
+            "title_asc" => Some(ChildSort::TitleAsc),
+            "title_desc" => Some(ChildSort::TitleDesc),
+            "created" => Some(ChildSort::Created),
+            _ => None,
+        }
+    }
+
+    /// ORDER BY terms for the `nodes` table; anything but manual ignores order_index
+    pub fn order_by(self) -> &'static str {
+        match self {
+            ChildSort::Manual => "nodes.order_index, nodes.id",
+            ChildSort::TitleAsc => "nodes.title COLLATE NOCASE, nodes.id",
+            ChildSort::TitleDesc => "nodes.title COLLATE NOCASE DESC, nodes.id",
+            ChildSort::Created => "nodes.created_at, nodes.id",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergePosition {
+    Append,
+    Prepend,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeDocumentRequest {
+    pub source_id: i64,
+    pub position: MergePosition,
+    #[serde(default)]
+    pub delete_source: bool,
+}
+
+/// Listing entry for a stored document snapshot (the captured data is not included)
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DocumentSnapshot {
+    pub id: i64,
+    pub document_id: i64,
+    pub reason: String, // manual, pre-restore
+    pub node_count: i64,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub created_at: String,
+}
+
+/// Captured state of a document: its nodes (with attachments) and their content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotData {
+    pub nodes: Vec<Node>,
+    pub content: HashMap<i64, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagDocumentsRequest {
+    pub document_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangesQuery {
+    pub since: String,
+    #[serde(default)]
+    pub include_nodes: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeleteQuery {
+    /// Answer 204 rather than 404 when there was nothing to delete, so a
+    /// retried delete still looks successful
+    #[serde(default)]
+    pub ignore_missing: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DocumentBatchRequest {
+    pub ids: Vec<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -21,11 +181,128 @@ pub struct Node {
     pub parent_id: Option<i64>,
     pub node_type: String, // section, equation, figure
     pub title: String,
-    pub order_index: i64,
+    pub order_index: f64,
     pub indent_level: i64,
     pub image_url: Option<String>,
+    pub collapsed: bool,
+    pub last_edited_by: Option<String>,
+    /// Type-specific fields that don't warrant a column; always an object
+    #[serde(default)]
+    pub attributes: Option<sqlx::types::Json<serde_json::Map<String, serde_json::Value>>>,
+    #[serde(serialize_with = "serialize_timestamp")]
     pub created_at: String,
+    #[serde(serialize_with = "serialize_timestamp")]
     pub updated_at: String,
+    // Joined from the uploads table for figure nodes
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub image_alt_text: Option<String>,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub image_display_name: Option<String>,
+    // Only filled in when a listing asks for previews
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub preview: Option<String>,
+    // Filled in separately where a full node is returned
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub attachments: Vec<Attachment>,
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub unresolved_comments: Option<i64>,
+}
+
+/// One extra image of a multi-panel figure, ordered by position
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Attachment {
+    pub id: i64,
+    pub node_id: i64,
+    pub url: String,
+    pub position: i64,
+    pub caption: Option<String>,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAttachmentRequest {
+    pub url: String,
+    pub caption: Option<String>,
+}
+
+/// A reviewer's note on a node, kept apart from its content
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Comment {
+    pub id: i64,
+    pub node_id: i64,
+    pub author: Option<String>,
+    pub body: String,
+    pub resolved: bool,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub created_at: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateCommentRequest {
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateCommentRequest {
+    pub resolved: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CommentListQuery {
+    /// Only resolved (true) or unresolved (false) comments; all when left out
+    pub resolved: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReorderAttachmentsRequest {
+    pub attachment_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct NodeListQuery {
+    /// Case-insensitive substring to match against node titles
+    pub q: Option<String>,
+    /// Include a one-line plain-text preview of each node's content
+    #[serde(default)]
+    pub preview: bool,
+    /// Only nodes whose attributes have this key...
+    pub attr: Option<String>,
+    /// ...set to this value, parsed as JSON when it is valid JSON and
+    /// matched as a string otherwise
+    pub attr_value: Option<String>,
+    /// Only nodes of this type, e.g. every figure for a list of figures
+    pub node_type: Option<String>,
+}
+
+/// A node with its children nested, for tree-shaped responses
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeTree {
+    #[serde(flatten)]
+    pub node: Node,
+    pub children: Vec<NodeTree>,
+}
+
+/// A node in its sibling group, flagged when it is the one asked about
+#[derive(Debug, Clone, Serialize)]
+pub struct SiblingNode {
+    #[serde(flatten)]
+    pub node: Node,
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CreateNodeQuery {
+    /// Start the node's content from its type's template, when there is one
+    #[serde(default)]
+    pub seed_content: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,18 +311,69 @@ pub struct CreateNodeRequest {
     pub parent_id: Option<i64>,
     pub node_type: String,
     pub title: String,
-    pub order_index: i64,
+    /// Omit to append after the last sibling
+    pub order_index: Option<f64>,
     pub indent_level: i64,
     pub image_url: Option<String>,
+    pub attributes: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateNodeRequest {
     pub title: Option<String>,
-    pub order_index: Option<i64>,
+    pub order_index: Option<f64>,
     pub indent_level: Option<i64>,
     pub parent_id: Option<i64>,
     pub image_url: Option<String>,
+    pub collapsed: Option<bool>,
+    /// Replaces the node's attributes as a whole
+    pub attributes: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeNodeTypeRequest {
+    pub node_type: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ChangeNodeTypeQuery {
+    /// Convert even when content or images would be discarded
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReorderNodeRequest {
+    pub after_id: Option<i64>,
+    pub before_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaceNodeRequest {
+    pub parent_id: Option<i64>,
+    pub after_id: Option<i64>,
+    pub before_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Upload {
+    pub id: i64,
+    pub filename: String,
+    pub url: String,
+    pub original_name: String,
+    pub size_bytes: i64,
+    pub alt_text: Option<String>,
+    pub display_name: Option<String>,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub created_at: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateUploadRequest {
+    pub alt_text: Option<String>,
+    pub display_name: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -53,6 +381,26 @@ pub struct Content {
     pub id: i64,
     pub node_id: i64,
     pub content_json: String,
+    pub version: i64,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub updated_at: String,
+    // The requesting editor's saved cursor/selection, when opening a node
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub editor_state: Option<serde_json::Value>,
+    // Block count of the whole node, when content_json holds only a range of it
+    #[sqlx(skip)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub total_blocks: Option<usize>,
+}
+
+/// Where an editor left off in a node, as stored per editor and node
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct EditorState {
+    pub node_id: i64,
+    /// Opaque to the server: whatever cursor/selection shape the client uses
+    pub state: sqlx::types::Json<serde_json::Value>,
+    #[serde(serialize_with = "serialize_timestamp")]
     pub updated_at: String,
 }
 
@@ -61,8 +409,213 @@ pub struct SaveContentRequest {
     pub content_json: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct ContentOpsRequest {
+    /// Version the ops were made against; a mismatch is rejected with 409
+    pub base_version: Option<i64>,
+    pub ops: Vec<crate::ops::Operation>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportUrlRequest {
+    pub url: String,
+    pub alt_text: Option<String>,
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DocumentDiffQuery {
+    pub left: i64,
+    pub right: i64,
+}
+
+/// A contiguous range of a node's top-level blocks, for virtualized editors
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ContentRangeQuery {
+    /// First block to return; 0 when only length is given
+    pub offset: Option<usize>,
+    /// Number of blocks; up to the end when only offset is given
+    pub length: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SaveContentQuery {
+    /// Let a burst of saves for the same node collapse into one write
+    #[serde(default)]
+    pub coalesce: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplaceTextRequest {
+    pub find: String,
+    pub replace: String,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Where figure nodes go in an export: where they are in the outline, or
+/// collected into a closing List of Figures. `end` shows the figures there in
+/// full; `separate-list` lists their captions first and gives each figure a
+/// page of its own, as journal submissions often ask.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FigurePlacement {
+    #[default]
+    Inline,
+    End,
+    SeparateList,
+}
+
+impl FigurePlacement {
+    pub fn collects(self) -> bool {
+        self != FigurePlacement::Inline
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExportPdfRequest {
     pub document_id: i64,
     pub template: String, // paper, report, resume
+    /// Emit a PDF outline (bookmarks) following the section hierarchy
+    #[serde(default = "default_true")]
+    pub bookmarks: bool,
+    /// Refuse with 409 instead of exporting a document that is still being edited
+    #[serde(default)]
+    pub require_settled: bool,
+    /// Stored stylesheet applied on top of the template
+    pub stylesheet_id: Option<i64>,
+    /// Extra CSS applied last, after any stored stylesheet
+    pub custom_css: Option<String>,
+    #[serde(default)]
+    pub figure_placement: FigurePlacement,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Stylesheet {
+    pub id: i64,
+    pub name: String,
+    pub css: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub created_at: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateStylesheetRequest {
+    pub name: String,
+    pub css: String,
+}
+
+/// One row of a CSV outline import, in the columns the CSV export writes.
+/// Ids only link rows within the file; imported nodes get new ones.
+#[derive(Debug, Deserialize)]
+pub struct CsvNodeRow {
+    pub id: String,
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    pub node_type: String,
+    pub title: String,
+    /// Defaults to the row's position among its siblings in the file
+    #[serde(default)]
+    pub order_index: Option<f64>,
+    /// Defaults to the node's depth in the imported tree
+    #[serde(default)]
+    pub indent_level: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub require_settled: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LatexExportQuery {
+    /// paper, report or resume; picks the document class
+    pub template: Option<String>,
+    #[serde(default)]
+    pub require_settled: bool,
+    #[serde(default)]
+    pub figure_placement: FigurePlacement,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EpubExportQuery {
+    #[serde(default)]
+    pub require_settled: bool,
+    #[serde(default)]
+    pub figure_placement: FigurePlacement,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EquationExportQuery {
+    /// json or latex; without it the Accept header decides, then JSON
+    pub format: Option<String>,
+    #[serde(default)]
+    pub require_settled: bool,
+}
+
+/// One equation node of a document, as listed by the equations export
+#[derive(Debug, Clone, Serialize)]
+pub struct EquationExport {
+    pub node_id: i64,
+    pub title: String,
+    /// Converted to LaTeX when the equation is stored as MathML
+    pub latex: String,
+    /// Format the equation is stored in, latex or mathml
+    pub format: &'static str,
+    /// Title of the nearest ancestor section, if any
+    pub section: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ReadingQuery {
+    /// 1-based
+    pub page: Option<i64>,
+    /// Blocks per page, 50 by default
+    pub size: Option<i64>,
+}
+
+/// One node of the reading view, rendered for display
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadingBlock {
+    pub node_id: i64,
+    pub node_type: String,
+    pub title: String,
+    /// 0 for top-level nodes
+    pub depth: usize,
+    pub text: String,
+    pub html: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExportEstimateQuery {
+    /// paper (default), report or resume
+    pub template: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// One section in a document's table of contents / PDF outline
+#[derive(Debug, Clone, Serialize)]
+pub struct OutlineEntry {
+    pub node_id: i64,
+    pub title: String,
+    pub level: usize, // 1 for top-level sections
+    pub anchor: String,
+    pub children: Vec<OutlineEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditQuery {
+    pub entity_type: Option<String>,
+    pub entity_id: Option<i64>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<i64>,
 }
@@ -0,0 +1,195 @@
+//! Incremental edits to BlockNote content_json, applied server-side so
+//! clients can send small deltas instead of the whole document
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// One edit. Blocks are addressed by a path of indexes: `[2]` is the third
+/// top-level block, `[2, 0]` its first child. Text offsets and lengths count
+/// characters within a block's inline content.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    InsertBlock { path: Vec<usize>, block: Value },
+    DeleteBlock { path: Vec<usize> },
+    InsertText { path: Vec<usize>, offset: usize, text: String },
+    DeleteText { path: Vec<usize>, offset: usize, length: usize },
+    /// Set styles on a range; a null style value removes that style
+    Format { path: Vec<usize>, offset: usize, length: usize, styles: Map<String, Value> },
+}
+
+/// Apply operations in order. On error nothing should be persisted; the
+/// message names the first operation that failed.
+pub fn apply(blocks: &mut Vec<Value>, ops: &[Operation]) -> Result<(), String> {
+    for (i, op) in ops.iter().enumerate() {
+        apply_one(blocks, op).map_err(|e| format!("operation {}: {}", i, e))?;
+    }
+    Ok(())
+}
+
+fn apply_one(blocks: &mut Vec<Value>, op: &Operation) -> Result<(), String> {
+    match op {
+        Operation::InsertBlock { path, block } => {
+            if !block.is_object() {
+                return Err("block must be an object".to_string());
+            }
+            let (siblings, index) = parent_list(blocks, path)?;
+            if index > siblings.len() {
+                return Err(format!("index {} is past the end", index));
+            }
+            siblings.insert(index, block.clone());
+        }
+        Operation::DeleteBlock { path } => {
+            let (siblings, index) = parent_list(blocks, path)?;
+            if index >= siblings.len() {
+                return Err(format!("no block at index {}", index));
+            }
+            siblings.remove(index);
+        }
+        Operation::InsertText { path, offset, text } => {
+            let block = block_at(blocks, path)?;
+            let mut chars = styled_chars(block)?;
+            if *offset > chars.len() {
+                return Err(format!("offset {} is past the end of the text", offset));
+            }
+            // Inserted text takes the styles of the character before it
+            let styles = offset
+                .checked_sub(1)
+                .and_then(|i| chars.get(i))
+                .map(|(_, styles)| styles.clone())
+                .unwrap_or_default();
+            let inserted: Vec<_> = text.chars().map(|c| (c, styles.clone())).collect();
+            chars.splice(*offset..*offset, inserted);
+            set_styled_chars(block, chars);
+        }
+        Operation::DeleteText { path, offset, length } => {
+            let block = block_at(blocks, path)?;
+            let mut chars = styled_chars(block)?;
+            let end = range_end(&chars, *offset, *length)?;
+            chars.drain(*offset..end);
+            set_styled_chars(block, chars);
+        }
+        Operation::Format { path, offset, length, styles } => {
+            let block = block_at(blocks, path)?;
+            let mut chars = styled_chars(block)?;
+            let end = range_end(&chars, *offset, *length)?;
+            for (_, current) in &mut chars[*offset..end] {
+                for (name, value) in styles {
+                    if value.is_null() {
+                        current.remove(name);
+                    } else {
+                        current.insert(name.clone(), value.clone());
+                    }
+                }
+            }
+            set_styled_chars(block, chars);
+        }
+    }
+    Ok(())
+}
+
+// A character of inline text with the styles of the run it came from
+type StyledChar = (char, Map<String, Value>);
+
+fn range_end<T>(chars: &[T], offset: usize, length: usize) -> Result<usize, String> {
+    offset
+        .checked_add(length)
+        .filter(|end| *end <= chars.len())
+        .ok_or_else(|| format!("range {}..{} is past the end of the text", offset, offset.saturating_add(length)))
+}
+
+/// The list holding the block at `path`, and the block's index within it
+fn parent_list<'a>(blocks: &'a mut Vec<Value>, path: &[usize]) -> Result<(&'a mut Vec<Value>, usize), String> {
+    let (&index, parents) = path.split_last().ok_or("path must not be empty")?;
+
+    let mut list = blocks;
+    for &i in parents {
+        list = list
+            .get_mut(i)
+            .ok_or_else(|| format!("no block at index {}", i))?
+            .as_object_mut()
+            .ok_or("block is not an object")?
+            .entry("children")
+            .or_insert_with(|| Value::Array(Vec::new()))
+            .as_array_mut()
+            .ok_or("block children are not an array")?;
+    }
+
+    Ok((list, index))
+}
+
+fn block_at<'a>(blocks: &'a mut Vec<Value>, path: &[usize]) -> Result<&'a mut Map<String, Value>, String> {
+    let (list, index) = parent_list(blocks, path)?;
+    list.get_mut(index)
+        .ok_or_else(|| format!("no block at index {}", index))?
+        .as_object_mut()
+        .ok_or_else(|| format!("block at index {} is not an object", index))
+}
+
+/// A block's inline text as characters paired with their styles. Only plain
+/// text runs can be edited this way; links and other inline nodes are refused.
+fn styled_chars(block: &Map<String, Value>) -> Result<Vec<StyledChar>, String> {
+    let Some(content) = block.get("content") else {
+        return Ok(Vec::new());
+    };
+    let runs = content.as_array().ok_or("block has no inline content")?;
+
+    let mut chars = Vec::new();
+    for run in runs {
+        if run.get("type").and_then(|t| t.as_str()) != Some("text") {
+            return Err("block contains inline content other than text".to_string());
+        }
+        let text = run.get("text").and_then(|t| t.as_str()).unwrap_or_default();
+        let styles = run.get("styles").and_then(|s| s.as_object()).cloned().unwrap_or_default();
+        chars.extend(text.chars().map(|c| (c, styles.clone())));
+    }
+
+    Ok(chars)
+}
+
+/// Write characters back as text runs, merging neighbours with equal styles
+fn set_styled_chars(block: &mut Map<String, Value>, chars: Vec<StyledChar>) {
+    let mut runs: Vec<(String, Map<String, Value>)> = Vec::new();
+    for (c, styles) in chars {
+        match runs.last_mut() {
+            Some((text, last)) if *last == styles => text.push(c),
+            _ => runs.push((c.to_string(), styles)),
+        }
+    }
+
+    block.insert(
+        "content".to_string(),
+        Value::Array(
+            runs.into_iter()
+                .map(|(text, styles)| serde_json::json!({ "type": "text", "text": text, "styles": styles }))
+                .collect(),
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn text_ops_on_non_object_blocks_are_refused() {
+        let mut blocks = vec![json!(1), json!("text")];
+        for path in [vec![0], vec![1]] {
+            let op = Operation::InsertText { path, offset: 0, text: "a".to_string() };
+            assert!(apply(&mut blocks, &[op]).is_err());
+        }
+        assert_eq!(blocks, vec![json!(1), json!("text")]);
+    }
+
+    #[test]
+    fn insert_text_keeps_neighbouring_styles() {
+        let mut blocks = vec![json!({
+            "type": "paragraph",
+            "content": [{ "type": "text", "text": "ab", "styles": { "bold": true } }],
+        })];
+        let op = Operation::InsertText { path: vec![0], offset: 1, text: "x".to_string() };
+        apply(&mut blocks, &[op]).unwrap();
+        assert_eq!(blocks[0]["content"], json!([{ "type": "text", "text": "axb", "styles": { "bold": true } }]));
+    }
+}
@@ -0,0 +1,69 @@
+use axum::http::{HeaderMap, HeaderValue, Uri};
+use serde::Deserialize;
+
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Optional limit/offset query parameters. Pagination only applies when
+/// `limit` is given, so existing clients keep receiving the full list.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Pagination {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl Pagination {
+    pub fn is_paginated(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    /// LIMIT value for SQLite, where -1 means unbounded
+    pub fn sql_limit(&self) -> i64 {
+        self.limit.map(|l| l.clamp(1, MAX_PAGE_SIZE)).unwrap_or(-1)
+    }
+
+    pub fn sql_offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// X-Total-Count plus an RFC 5988 Link header with first/prev/next/last
+    /// URLs, keeping any other query parameters of the original request.
+    pub fn headers(&self, uri: &Uri, total: i64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if !self.is_paginated() {
+            return headers;
+        }
+
+        let limit = self.sql_limit();
+        let offset = self.sql_offset();
+        let other_params: Vec<&str> = uri
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|p| !p.is_empty() && !p.starts_with("limit=") && !p.starts_with("offset="))
+            .collect();
+
+        let link = |rel: &str, offset: i64| {
+            let mut params = other_params.clone();
+            let page = format!("limit={}&offset={}", limit, offset);
+            params.push(&page);
+            format!("<{}?{}>; rel=\"{}\"", uri.path(), params.join("&"), rel)
+        };
+
+        let last_offset = if total > 0 { (total - 1) / limit * limit } else { 0 };
+        let mut links = vec![link("first", 0)];
+        if offset > 0 {
+            links.push(link("prev", (offset - limit).max(0)));
+        }
+        if offset + limit < total {
+            links.push(link("next", offset + limit));
+        }
+        links.push(link("last", last_offset));
+
+        if let Ok(value) = HeaderValue::from_str(&links.join(", ")) {
+            headers.insert("link", value);
+        }
+        headers.insert("x-total-count", HeaderValue::from(total));
+
+        headers
+    }
+}
@@ -0,0 +1,73 @@
+//! Per-query timing for traces. `traced` runs a query inside a `db` span
+//! carrying the operation, table, row count and elapsed time, nested under
+//! the request's span, and warns when the query is slower than
+//! SLOW_QUERY_MS. Queries that aren't wrapped are still covered by sqlx's
+//! own slow statement warning, set up in db.rs with the same threshold.
+
+use sqlx::sqlite::SqliteQueryResult;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+
+static SLOW_QUERY_MS: AtomicU64 = AtomicU64::new(200);
+
+pub fn set_slow_threshold(threshold: Duration) {
+    SLOW_QUERY_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// How many rows a query returned or changed
+pub trait Rows {
+    fn rows(&self) -> u64;
+}
+
+impl<T> Rows for Vec<T> {
+    fn rows(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl<T> Rows for Option<T> {
+    fn rows(&self) -> u64 {
+        self.is_some() as u64
+    }
+}
+
+impl Rows for SqliteQueryResult {
+    fn rows(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+/// Run `query`, recording it as `operation` on `table`
+pub async fn traced<T: Rows>(
+    operation: &'static str,
+    table: &'static str,
+    query: impl Future<Output = sqlx::Result<T>>,
+) -> sqlx::Result<T> {
+    let span = tracing::debug_span!(
+        "db",
+        operation,
+        table,
+        rows = tracing::field::Empty,
+        elapsed_ms = tracing::field::Empty,
+    );
+
+    let started = Instant::now();
+    let result = query.instrument(span.clone()).await;
+    let elapsed = started.elapsed();
+
+    span.record("elapsed_ms", elapsed.as_millis() as u64);
+    if let Ok(rows) = &result {
+        span.record("rows", rows.rows());
+    }
+    span.in_scope(|| {
+        if elapsed.as_millis() as u64 >= SLOW_QUERY_MS.load(Ordering::Relaxed) {
+            tracing::warn!("Slow query: {} on {} took {:?}", operation, table, elapsed);
+        } else {
+            tracing::debug!("{} on {} took {:?}", operation, table, elapsed);
+        }
+    });
+
+    result
+}
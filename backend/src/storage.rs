@@ -0,0 +1,256 @@
+use crate::config::Config;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where uploaded files live. Keys are the generated upload filenames.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, data: Bytes, content_type: &str) -> anyhow::Result<()>;
+
+    /// Fetch an object, or None if it does not exist
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>>;
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// URL handed to clients (and stored on nodes) for an object
+    fn public_url(&self, key: &str) -> String {
+        format!("/uploads/{}", key)
+    }
+
+    /// Short-lived URL clients can be redirected to instead of proxying the
+    /// bytes through this server, for backends that support it
+    fn presigned_url(&self, _key: &str) -> Option<String> {
+        None
+    }
+
+    /// Local directory backing the store, if any, so it can be served
+    /// directly with precompressed variants
+    fn local_dir(&self) -> Option<&std::path::Path> {
+        None
+    }
+}
+
+/// Keys are generated filenames; refuse anything that could escape the store
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains(['/', '\\']) && key != "." && key != ".."
+}
+
+/// Whether a storage error came from the disk being full (ENOSPC)
+pub fn is_storage_full(error: &anyhow::Error) -> bool {
+    error.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| e.kind() == std::io::ErrorKind::StorageFull)
+}
+
+/// Where uploads are kept, picked with STORAGE_BACKEND
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Local,
+    S3,
+}
+
+impl Backend {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "local" => Some(Backend::Local),
+            "s3" => Some(Backend::S3),
+            _ => None,
+        }
+    }
+}
+
+/// Open the configured backend; S3 reads its own settings from the environment
+pub fn open(config: &Config) -> anyhow::Result<Arc<dyn Storage>> {
+    match config.storage_backend {
+        Backend::S3 => Ok(Arc::new(S3Storage::from_env()?)),
+        Backend::Local => Ok(Arc::new(LocalStorage::new(&config.uploads_dir))),
+    }
+}
+
+/// Files in a directory on disk, served by ServeDir under /uploads
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl LocalStorage {
+    async fn write(&self, path: &std::path::Path, key: &str, data: &Bytes, content_type: &str) -> anyhow::Result<()> {
+        tokio::fs::write(path, data).await?;
+
+        // Text formats get a gzip sibling that ServeDir serves to clients accepting it
+        if content_type == "image/svg+xml" {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            tokio::fs::write(self.dir.join(format!("{}.gz", key)), encoder.finish()?).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Bytes, content_type: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(valid_key(key), "Invalid storage key {:?}", key);
+
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(key);
+        let written = self.write(&path, key, &data, content_type).await;
+
+        // A failed write (typically a full disk) can leave a truncated file behind
+        if written.is_err() {
+            if let Err(e) = self.delete(key).await {
+                tracing::warn!("Failed to remove partial upload {}: {}", key, e);
+            }
+        }
+        written
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        if !valid_key(key) {
+            return Ok(None);
+        }
+
+        match tokio::fs::read(self.dir.join(key)).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(valid_key(key), "Invalid storage key {:?}", key);
+
+        for path in [self.dir.join(key), self.dir.join(format!("{}.gz", key))] {
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn local_dir(&self) -> Option<&std::path::Path> {
+        Some(&self.dir)
+    }
+}
+
+// Signed requests are used immediately, so they only need to outlive the round trip
+const SIGNED_REQUEST_TTL: Duration = Duration::from_secs(60);
+
+/// Objects in an S3-compatible bucket. Configured with S3_BUCKET,
+/// S3_REGION, S3_ENDPOINT (defaults to AWS), S3_ACCESS_KEY_ID and
+/// S3_SECRET_ACCESS_KEY. With S3_PUBLIC_URL set (a public bucket or CDN),
+/// clients get direct links; otherwise /uploads redirects to presigned URLs.
+pub struct S3Storage {
+    bucket: Bucket,
+    credentials: Credentials,
+    public_base_url: Option<String>,
+    presign_ttl: Duration,
+    client: reqwest::Client,
+}
+
+impl S3Storage {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name)
+            .map_err(|_| anyhow::anyhow!("{} must be set when STORAGE_BACKEND=s3", name));
+
+        let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let endpoint = std::env::var("S3_ENDPOINT")
+            .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+        // Custom endpoints (MinIO and friends) usually only support path-style URLs
+        let url_style = if std::env::var("S3_ENDPOINT").is_ok() {
+            UrlStyle::Path
+        } else {
+            UrlStyle::VirtualHost
+        };
+
+        let bucket = Bucket::new(endpoint.parse()?, url_style, var("S3_BUCKET")?, region)?;
+        let credentials = Credentials::new(var("S3_ACCESS_KEY_ID")?, var("S3_SECRET_ACCESS_KEY")?);
+
+        let presign_ttl = Duration::from_secs(
+            std::env::var("S3_PRESIGN_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3600)
+        );
+
+        Ok(Self {
+            bucket,
+            credentials,
+            public_base_url: std::env::var("S3_PUBLIC_URL").ok()
+                .map(|url| url.trim_end_matches('/').to_string()),
+            presign_ttl,
+            client: reqwest::Client::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: Bytes, content_type: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(valid_key(key), "Invalid storage key {:?}", key);
+
+        let url = self.bucket.put_object(Some(&self.credentials), key)
+            .sign(SIGNED_REQUEST_TTL);
+        self.client.put(url)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(data)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        if !valid_key(key) {
+            return Ok(None);
+        }
+
+        let url = self.bucket.get_object(Some(&self.credentials), key)
+            .sign(SIGNED_REQUEST_TTL);
+        let response = self.client.get(url).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.bytes().await?))
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(valid_key(key), "Invalid storage key {:?}", key);
+
+        let url = self.bucket.delete_object(Some(&self.credentials), key)
+            .sign(SIGNED_REQUEST_TTL);
+        self.client.delete(url).send().await?.error_for_status()?;
+
+        Ok(())
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        match &self.public_base_url {
+            Some(base) => format!("{}/{}", base, key),
+            None => format!("/uploads/{}", key),
+        }
+    }
+
+    fn presigned_url(&self, key: &str) -> Option<String> {
+        valid_key(key).then(|| {
+            self.bucket.get_object(Some(&self.credentials), key)
+                .sign(self.presign_ttl)
+                .to_string()
+        })
+    }
+}
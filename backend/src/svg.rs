@@ -0,0 +1,351 @@
+//! SVG upload sanitizing.
+//!
+//! Uploaded SVG is served from /uploads as a document of its own, so any
+//! script in it runs on this origin. Rather than looking for dangerous
+//! patterns, the file is parsed and written back out from an allowlist of
+//! elements and attributes, the way math.rs handles MathML: event handlers,
+//! scripts, foreign content and links to anything but a fragment of the same
+//! file never make it into the output.
+
+// Elements kept as they are. Anything else is dropped with its children,
+// except `a`, whose children are kept without the link.
+const ELEMENTS: &[&str] = &[
+    "svg", "g", "defs", "symbol", "use", "title", "desc", "path", "rect", "circle", "ellipse",
+    "line", "polyline", "polygon", "text", "tspan", "textPath", "linearGradient",
+    "radialGradient", "stop", "clipPath", "mask", "pattern", "marker", "filter",
+    "feBlend", "feColorMatrix", "feComposite", "feDropShadow", "feFlood", "feGaussianBlur",
+    "feMerge", "feMergeNode", "feMorphology", "feOffset",
+];
+const UNWRAPPED: &[&str] = &["a"];
+
+// Geometry and presentation attributes. `href` (and `xlink:href`) is only
+// kept when it points at a fragment of the same file.
+const ATTRIBUTES: &[&str] = &[
+    "id", "class", "style", "transform", "viewBox", "preserveAspectRatio", "version",
+    "width", "height", "x", "y", "x1", "y1", "x2", "y2", "cx", "cy", "r", "rx", "ry", "fx", "fy",
+    "fr", "d", "points", "pathLength", "fill", "fill-opacity", "fill-rule", "stroke",
+    "stroke-width", "stroke-opacity", "stroke-linecap", "stroke-linejoin", "stroke-dasharray",
+    "stroke-dashoffset", "stroke-miterlimit", "opacity", "color", "display", "visibility",
+    "clip-path", "clip-rule", "mask", "filter", "vector-effect", "paint-order",
+    "shape-rendering", "text-rendering", "image-rendering", "font-family", "font-size",
+    "font-weight", "font-style", "font-variant", "text-anchor", "dominant-baseline",
+    "alignment-baseline", "baseline-shift", "letter-spacing", "word-spacing",
+    "text-decoration", "writing-mode", "dx", "dy", "rotate", "textLength", "lengthAdjust",
+    "startOffset", "xml:space", "offset", "stop-color", "stop-opacity", "gradientUnits",
+    "gradientTransform", "spreadMethod", "patternUnits", "patternContentUnits",
+    "patternTransform", "clipPathUnits", "maskUnits", "maskContentUnits", "markerWidth",
+    "markerHeight", "markerUnits", "refX", "refY", "orient", "marker-start", "marker-mid",
+    "marker-end", "filterUnits", "primitiveUnits", "in", "in2", "result", "stdDeviation",
+    "mode", "type", "values", "operator", "k1", "k2", "k3", "k4", "flood-color",
+    "flood-opacity", "radius",
+];
+
+// Nesting limit, so hostile input can't recurse without bound
+const MAX_DEPTH: usize = 256;
+
+enum SvgNode {
+    Element { name: String, attributes: Vec<(String, String)>, children: Vec<SvgNode> },
+    Text(String),
+}
+
+/// Rewrite an SVG from the allowlist. None if the data isn't well-formed
+/// SVG with an <svg> root.
+pub fn sanitize(data: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(data).ok()?;
+    let root = parse(text.trim_start_matches('\u{feff}')).ok()?;
+
+    let mut out = String::with_capacity(text.len());
+    write_svg(&mut out, &root, true);
+    Some(out.into_bytes())
+}
+
+fn parse(source: &str) -> Result<SvgNode, String> {
+    let mut parser = Parser { input: source, pos: 0 };
+    parser.skip_prolog()?;
+    let root = parser.element(0)?;
+    parser.skip_misc()?;
+    if parser.pos < parser.input.len() {
+        return Err("unexpected content after the closing </svg>".to_string());
+    }
+    match &root {
+        SvgNode::Element { name, .. } if name == "svg" => Ok(root),
+        _ => Err("SVG must have an <svg> root element".to_string()),
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    // Whitespace, comments and processing instructions between elements
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<!--") {
+                let end = self.rest().find("-->").ok_or("unterminated comment in SVG")?;
+                self.pos += end + 3;
+            } else if self.rest().starts_with("<?") {
+                let end = self.rest().find("?>").ok_or("unterminated processing instruction in SVG")?;
+                self.pos += end + 2;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_prolog(&mut self) -> Result<(), String> {
+        self.skip_misc()?;
+        if self.rest().starts_with("<!DOCTYPE") {
+            // An internal subset can declare entities; only the plain form is accepted
+            let end = self.rest().find('>').ok_or("unterminated DOCTYPE in SVG")?;
+            if self.rest()[..end].contains('[') {
+                return Err("DOCTYPE declarations with entities are not allowed in SVG".to_string());
+            }
+            self.pos += end + 1;
+            self.skip_misc()?;
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<&'a str, String> {
+        let len = self.rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.')))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err("expected a name in SVG".to_string());
+        }
+        let name = &self.rest()[..len];
+        self.pos += len;
+        Ok(name)
+    }
+
+    fn element(&mut self, depth: usize) -> Result<SvgNode, String> {
+        if depth > MAX_DEPTH {
+            return Err("SVG is nested too deeply".to_string());
+        }
+        if !self.rest().starts_with('<') {
+            return Err("expected an SVG element".to_string());
+        }
+        self.pos += 1;
+        let qualified = self.name()?;
+        // Namespace prefixes, as in <svg:rect>, are dropped from elements
+        let name = qualified.rsplit(':').next().unwrap_or(qualified).to_string();
+
+        let mut attributes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(SvgNode::Element { name, attributes, children: Vec::new() });
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let key = self.name()?.to_string();
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(format!("attribute {} in <{}> has no value", key, name));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = self.rest().chars().next().filter(|c| *c == '"' || *c == '\'')
+                .ok_or_else(|| format!("attribute {} in <{}> must be quoted", key, name))?;
+            self.pos += 1;
+            let end = self.rest().find(quote).ok_or("unterminated attribute value in SVG")?;
+            let value = decode_entities(&self.rest()[..end])?;
+            self.pos += end + 1;
+            attributes.push((key, value));
+        }
+
+        let mut children = Vec::new();
+        loop {
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                let closing = self.name()?;
+                self.skip_whitespace();
+                if closing != qualified || !self.rest().starts_with('>') {
+                    return Err(format!("<{}> is closed by </{}>", qualified, closing));
+                }
+                self.pos += 1;
+                return Ok(SvgNode::Element { name, attributes, children });
+            }
+            if self.rest().starts_with("<![CDATA[") {
+                let end = self.rest().find("]]>").ok_or("unterminated CDATA section in SVG")?;
+                children.push(SvgNode::Text(self.rest()[9..end].to_string()));
+                self.pos += end + 3;
+            } else if self.rest().starts_with("<!--") || self.rest().starts_with("<?") {
+                self.skip_misc()?;
+            } else if self.rest().starts_with("<!") {
+                return Err("declarations are not allowed inside SVG elements".to_string());
+            } else if self.rest().starts_with('<') {
+                children.push(self.element(depth + 1)?);
+            } else if self.rest().is_empty() {
+                return Err(format!("<{}> is never closed", qualified));
+            } else {
+                let end = self.rest().find('<').unwrap_or(self.rest().len());
+                children.push(SvgNode::Text(decode_entities(&self.rest()[..end])?));
+                self.pos += end;
+            }
+        }
+    }
+}
+
+fn decode_entities(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find(';').ok_or("unterminated entity in SVG")? + start;
+        let entity = &rest[start + 1..end];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse::<u32>))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        out.push(decoded.ok_or_else(|| format!("unknown entity &{}; in SVG", entity))?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Every url(...) in a value points inside the file, and nothing in it can
+/// run script or pull in other resources
+fn is_safe_value(value: &str) -> bool {
+    let compact: String = value.chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .flat_map(char::to_lowercase)
+        .collect();
+    if ["javascript:", "expression(", "@import", "behavior:", "-moz-binding"].iter().any(|n| compact.contains(n)) {
+        return false;
+    }
+    compact.match_indices("url(").all(|(i, _)| {
+        compact[i + 4..].trim_start_matches(['"', '\'']).starts_with('#')
+    })
+}
+
+/// The attribute as it should be written out, or None to drop it
+fn allowed_attribute<'k>(key: &'k str, value: &str) -> Option<&'k str> {
+    match key {
+        "href" | "xlink:href" => value.trim().starts_with('#').then_some("href"),
+        _ if ATTRIBUTES.contains(&key) && is_safe_value(value) => Some(key),
+        _ => None,
+    }
+}
+
+/// Re-serialize a parsed tree, keeping only the allowed elements and attributes
+fn write_svg(out: &mut String, node: &SvgNode, root: bool) {
+    match node {
+        SvgNode::Text(text) => out.push_str(&escape_xml(text)),
+        SvgNode::Element { name, children, .. } if UNWRAPPED.contains(&name.as_str()) => {
+            for child in children {
+                write_svg(out, child, false);
+            }
+        }
+        SvgNode::Element { name, attributes, children } => {
+            if !ELEMENTS.contains(&name.as_str()) {
+                return;
+            }
+            out.push('<');
+            out.push_str(name);
+            if root {
+                out.push_str(" xmlns=\"http://www.w3.org/2000/svg\"");
+            }
+            for (key, value) in attributes {
+                if let Some(key) = allowed_attribute(key, value) {
+                    out.push_str(&format!(" {}=\"{}\"", key, escape_xml(value)));
+                }
+            }
+            out.push('>');
+            for child in children {
+                write_svg(out, child, false);
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(svg: &str) -> Option<String> {
+        sanitize(svg.as_bytes()).map(|out| String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn drops_event_handlers() {
+        // Not well-formed XML, so refused outright
+        assert!(clean(r#"<svg/onload="alert(1)"></svg>"#).is_none());
+
+        let out = clean("<svg\nonload=\"alert(1)\"><rect width=\"1\" height=\"1\" onclick='x'/></svg>").unwrap();
+        assert!(!out.contains("onload") && !out.contains("onclick"));
+        assert!(out.contains(r#"<rect width="1" height="1">"#));
+    }
+
+    #[test]
+    fn drops_entity_encoded_javascript_links() {
+        let out = clean(r#"<svg><a href="&#106;avascript:alert(1)"><text>hi</text></a></svg>"#).unwrap();
+        assert!(!out.contains("avascript"));
+        assert!(out.contains("<text>hi</text>"));
+    }
+
+    #[test]
+    fn drops_data_uri_and_external_hrefs() {
+        let out = clean(r##"<svg xmlns:xlink="http://www.w3.org/1999/xlink">
+            <use xlink:href="data:image/svg+xml;base64,PHN2Zz4="/>
+            <use href="https://example.com/x.svg#a"/>
+            <use xlink:href="#shape"/>
+        </svg>"##).unwrap();
+        assert!(!out.contains("data:"));
+        assert!(!out.contains("example.com"));
+        assert!(out.contains(r##"<use href="#shape">"##));
+    }
+
+    #[test]
+    fn drops_scripts_and_foreign_content() {
+        let out = clean(r#"<svg><script>alert(1)</script><foreignObject><iframe/></foreignObject><circle r="2"/></svg>"#).unwrap();
+        assert_eq!(out, r#"<svg xmlns="http://www.w3.org/2000/svg"><circle r="2"></circle></svg>"#);
+    }
+
+    #[test]
+    fn keeps_fragment_urls_only() {
+        let out = clean(r#"<svg><rect fill="url(#g)" stroke="url(https://x/y)" style="fill:url( 'javascript:1')"/></svg>"#).unwrap();
+        assert!(out.contains(r##"fill="url(#g)""##));
+        assert!(!out.contains("stroke"));
+        assert!(!out.contains("style"));
+    }
+
+    #[test]
+    fn refuses_entity_declarations_and_non_svg() {
+        assert!(clean(r#"<!DOCTYPE svg [<!ENTITY x "y">]><svg>&x;</svg>"#).is_none());
+        assert!(clean("<html><body/></html>").is_none());
+        assert!(clean("<svg><g></svg>").is_none());
+    }
+}
@@ -0,0 +1,121 @@
+//! Plain-text extraction from BlockNote content_json, shared by everything
+//! that needs readable text (exports, search, word counts)
+
+use serde_json::Value;
+
+/// Concatenate the inline text of a BlockNote block (without children)
+pub fn block_text(block: &Value) -> String {
+    block.get("content")
+        .and_then(|c| c.as_array())
+        .map(|inline| {
+            inline.iter()
+                .filter_map(inline_text)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Text runs carry "text"; links wrap their own runs in "content"
+fn inline_text(item: &Value) -> Option<String> {
+    if let Some(text) = item.get("text").and_then(|t| t.as_str()) {
+        return Some(text.to_string());
+    }
+
+    item.get("content")
+        .and_then(|c| c.as_array())
+        .map(|runs| runs.iter().filter_map(inline_text).collect())
+}
+
+/// Readable text of one block: its inline text, or the LaTeX source for
+/// equation blocks that keep it in props (converted when they hold MathML)
+fn block_line(block: &Value) -> String {
+    if crate::math::is_equation_block(block) {
+        return crate::math::block_latex(block).unwrap_or_else(|| block_text(block));
+    }
+    block_text(block)
+}
+
+fn collect_lines(blocks: &[Value], lines: &mut Vec<String>) {
+    for block in blocks {
+        let line = block_line(block);
+        if !line.trim().is_empty() {
+            lines.push(line);
+        }
+        if let Some(children) = block.get("children").and_then(|c| c.as_array()) {
+            collect_lines(children, lines);
+        }
+    }
+}
+
+/// Plain text of a list of blocks, one line per non-empty block (nested
+/// blocks follow their parent)
+pub fn blocks_text(blocks: &[Value]) -> String {
+    let mut lines = Vec::new();
+    collect_lines(blocks, &mut lines);
+    lines.join("\n")
+}
+
+/// Plain text of a node's content_json. Unparseable content yields an empty string.
+pub fn plain_text(content_json: &str) -> String {
+    let blocks: Vec<Value> = serde_json::from_str(content_json).unwrap_or_default();
+    blocks_text(&blocks)
+}
+
+/// Number of whitespace-separated words in extracted text
+pub fn word_count(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+/// Extracted text squashed onto one line and cut to at most `max_chars`
+/// characters, with an ellipsis when truncated
+pub fn preview(text: &str, max_chars: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= max_chars {
+        return line;
+    }
+
+    let cut: String = line.chars().take(max_chars.saturating_sub(1)).collect();
+    format!("{}…", cut.trim_end())
+}
+
+/// Deepest array/object nesting in a JSON document, found by scanning the
+/// raw text so hostile input can't recurse through a parser first
+pub fn json_depth(json: &str) -> usize {
+    let mut depth = 0usize;
+    let mut max = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for byte in json.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    max
+}
+
+/// JSON re-serialized with object keys sorted and no whitespace, so content
+/// that means the same is byte-for-byte the same. Array order is kept.
+/// Anything that doesn't parse comes back unchanged.
+pub fn normalize_json(json: &str) -> String {
+    // Without serde_json's preserve_order feature, objects are sorted maps
+    serde_json::from_str::<Value>(json)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|_| json.to_string())
+}
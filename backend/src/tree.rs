@@ -0,0 +1,183 @@
+//! Structural checks on a document's node tree: parent links, sibling order
+//! and indent levels, plus the placement that repairs whatever is wrong
+
+use crate::models::Node;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MissingParent {
+    pub node_id: i64,
+    pub parent_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateOrder {
+    pub parent_id: Option<i64>,
+    pub order_index: f64,
+    pub node_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndentMismatch {
+    pub node_id: i64,
+    pub indent_level: i64,
+    pub depth: i64,
+}
+
+/// Problems found in one document's tree. Gapped groups (ranks other than
+/// 0, 1, 2, ...) are normal after fractional reorders and don't make the tree
+/// invalid; repairing renumbers them anyway.
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeReport {
+    pub valid: bool,
+    pub node_count: usize,
+    pub missing_parents: Vec<MissingParent>,
+    pub cycles: Vec<Vec<i64>>,
+    pub duplicate_order: Vec<DuplicateOrder>,
+    pub gapped_groups: Vec<Option<i64>>,
+    pub indent_mismatches: Vec<IndentMismatch>,
+}
+
+/// Where a node belongs once the tree is repaired
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub id: i64,
+    pub parent_id: Option<i64>,
+    pub order_index: f64,
+    pub indent_level: i64,
+}
+
+/// Check a document's nodes and work out the repaired placement of every
+/// node: orphans and one node of each cycle move to the root, sibling groups
+/// are renumbered from 0 in their current order, and indent levels follow
+/// depth.
+pub fn analyze(nodes: &[Node]) -> (TreeReport, Vec<Placement>) {
+    let by_id: HashMap<i64, &Node> = nodes.iter().map(|n| (n.id, n)).collect();
+
+    let mut missing_parents = Vec::new();
+    let mut parents: HashMap<i64, Option<i64>> = HashMap::new();
+    for node in nodes {
+        let parent = match node.parent_id {
+            Some(parent_id) if !by_id.contains_key(&parent_id) => {
+                missing_parents.push(MissingParent { node_id: node.id, parent_id });
+                None
+            }
+            parent => parent,
+        };
+        parents.insert(node.id, parent);
+    }
+
+    let cycles = find_cycles(nodes, &parents);
+    for cycle in &cycles {
+        // The lowest id in the cycle becomes a root, which breaks it
+        if let Some(&breaker) = cycle.iter().min() {
+            parents.insert(breaker, None);
+        }
+    }
+
+    // Sibling groups in display order, keyed by the repaired parent
+    let mut groups: HashMap<Option<i64>, Vec<&Node>> = HashMap::new();
+    for node in nodes {
+        groups.entry(parents[&node.id]).or_default().push(node);
+    }
+    let mut group_keys: Vec<Option<i64>> = groups.keys().copied().collect();
+    group_keys.sort();
+
+    let mut duplicate_order = Vec::new();
+    let mut gapped_groups = Vec::new();
+    let mut order_indexes = HashMap::new();
+    for key in &group_keys {
+        let group = groups.get_mut(key).unwrap();
+        group.sort_by(|a, b| a.order_index.total_cmp(&b.order_index).then(a.id.cmp(&b.id)));
+
+        for run in group.chunk_by(|a, b| a.order_index == b.order_index) {
+            if run.len() > 1 {
+                duplicate_order.push(DuplicateOrder {
+                    parent_id: *key,
+                    order_index: run[0].order_index,
+                    node_ids: run.iter().map(|n| n.id).collect(),
+                });
+            }
+        }
+        if group.iter().enumerate().any(|(i, n)| n.order_index != i as f64) {
+            gapped_groups.push(*key);
+        }
+        for (i, node) in group.iter().enumerate() {
+            order_indexes.insert(node.id, i as f64);
+        }
+    }
+
+    // Depths from the roots down; the repaired parents are acyclic
+    let mut depths: HashMap<i64, i64> = HashMap::new();
+    let mut pending: Vec<(Option<i64>, i64)> = vec![(None, 0)];
+    while let Some((parent, depth)) = pending.pop() {
+        for node in groups.get(&parent).into_iter().flatten() {
+            depths.insert(node.id, depth);
+            pending.push((Some(node.id), depth + 1));
+        }
+    }
+
+    let mut indent_mismatches = Vec::new();
+    let mut placements = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let depth = depths[&node.id];
+        if node.indent_level != depth {
+            indent_mismatches.push(IndentMismatch {
+                node_id: node.id,
+                indent_level: node.indent_level,
+                depth,
+            });
+        }
+        placements.push(Placement {
+            id: node.id,
+            parent_id: parents[&node.id],
+            order_index: order_indexes[&node.id],
+            indent_level: depth,
+        });
+    }
+
+    let report = TreeReport {
+        valid: missing_parents.is_empty()
+            && cycles.is_empty()
+            && duplicate_order.is_empty()
+            && indent_mismatches.is_empty(),
+        node_count: nodes.len(),
+        missing_parents,
+        cycles,
+        duplicate_order,
+        gapped_groups,
+        indent_mismatches,
+    };
+
+    (report, placements)
+}
+
+/// Parent-link cycles, each listed once, in the order the links are followed
+fn find_cycles(nodes: &[Node], parents: &HashMap<i64, Option<i64>>) -> Vec<Vec<i64>> {
+    let mut settled: HashSet<i64> = HashSet::new();
+    let mut cycles = Vec::new();
+
+    for node in nodes {
+        let mut path: Vec<i64> = Vec::new();
+        let mut on_path: HashSet<i64> = HashSet::new();
+        let mut current = Some(node.id);
+
+        while let Some(id) = current {
+            if settled.contains(&id) {
+                break;
+            }
+            if !on_path.insert(id) {
+                let start = path.iter().position(|&p| p == id).unwrap_or(0);
+                cycles.push(path[start..].to_vec());
+                break;
+            }
+            path.push(id);
+            current = parents.get(&id).copied().flatten();
+        }
+
+        settled.extend(path);
+    }
+
+    cycles
+}
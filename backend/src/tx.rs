@@ -0,0 +1,115 @@
+//! Per-request database transactions. A handler that takes a `Tx` runs all of
+//! its queries in one transaction, which the `transactions` middleware
+//! commits when the handler's response is a success and rolls back otherwise,
+//! so an early `?` or an `ApiError` leaves nothing half-written. Side effects
+//! other clients can see, such as webhooks, are queued with
+//! [`Tx::after_commit`] so they only happen once the writes are visible.
+//!
+//! ```ignore
+//! pub async fn move_thing(mut tx: Tx, ...) -> Result<Json<Thing>, StatusCode> {
+//!     sqlx::query("UPDATE ...").execute(&mut *tx).await...;
+//!     sqlx::query("UPDATE ...").execute(&mut *tx).await...;
+//!     Ok(Json(thing)) // committed after the handler returns
+//! }
+//! ```
+
+use crate::AppState;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::{Sqlite, SqliteConnection, Transaction};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+type AfterCommit = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct TxState {
+    transaction: Option<Transaction<'static, Sqlite>>,
+    after_commit: Vec<AfterCommit>,
+}
+
+// Shared between the middleware and the extractor through request extensions;
+// filled the first time a handler extracts a Tx
+#[derive(Clone, Default)]
+struct TxSlot(Arc<Mutex<TxState>>);
+
+/// Extractor for the request's transaction. Derefs to the connection, so
+/// queries run with `.execute(&mut *tx)`.
+pub struct Tx(OwnedMutexGuard<TxState>);
+
+impl Tx {
+    /// Run `f` once the transaction has committed; dropped if it rolls back
+    pub fn after_commit(&mut self, f: impl FnOnce() + Send + 'static) {
+        self.0.after_commit.push(Box::new(f));
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tx {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let slot = parts.extensions.get::<TxSlot>().cloned().ok_or_else(|| {
+            tracing::error!("Tx extracted on a route without the transactions middleware");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        // One Tx per request; a second would wait on the first forever
+        let mut guard = slot.0.try_lock_owned()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if guard.transaction.is_none() {
+            guard.transaction = Some(state.db.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+        }
+
+        Ok(Tx(guard))
+    }
+}
+
+impl Deref for Tx {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        self.0.transaction.as_ref().expect("transaction begun on extraction")
+    }
+}
+
+impl DerefMut for Tx {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        self.0.transaction.as_mut().expect("transaction begun on extraction")
+    }
+}
+
+/// Commit the request's transaction, if a handler began one, when the
+/// response is 2xx or 3xx; any other response rolls it back.
+pub async fn transactions(mut request: Request, next: Next) -> Response {
+    let slot = TxSlot::default();
+    request.extensions_mut().insert(slot.clone());
+
+    let response = next.run(request).await;
+
+    // The handler has returned, so its Tx (and the lock) is gone
+    let TxState { transaction: Some(tx), after_commit } = std::mem::take(&mut *slot.0.lock().await) else {
+        return response;
+    };
+
+    let status = response.status();
+    if status.is_success() || status.is_redirection() {
+        if let Err(e) = tx.commit().await {
+            tracing::warn!("Failed to commit request transaction: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        for f in after_commit {
+            f();
+        }
+    } else if let Err(e) = tx.rollback().await {
+        tracing::warn!("Failed to roll back request transaction: {}", e);
+    }
+
+    response
+}
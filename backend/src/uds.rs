@@ -0,0 +1,83 @@
+//! Serving over a Unix domain socket, for deployments behind a reverse proxy
+//! on the same host. axum::serve only accepts TCP listeners, so connections
+//! are driven with hyper directly.
+
+use axum::Router;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::net::UnixListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// Serve `app` on a socket at `path` with the given file mode until SIGINT
+/// or SIGTERM, then let open connections finish their requests and remove
+/// the socket file
+pub async fn serve(path: &Path, mode: u32, app: Router) -> anyhow::Result<()> {
+    // A socket left behind by an unclean exit would make bind fail; anything
+    // else at that path is not ours to delete
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        anyhow::ensure!(metadata.file_type().is_socket(), "{} exists and is not a socket", path.display());
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+    tracing::info!("Backend server listening on unix:{} (mode {:o})", path.display(), mode);
+
+    let shutdown = crate::shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // Connections are tracked so shutdown can wait for them; the watch tells
+    // each one to stop taking new requests once the signal arrives
+    let (closing, closing_rx) = watch::channel(());
+    let mut connections = JoinSet::new();
+
+    loop {
+        let stream = tokio::select! {
+            // Reap finished connections as we go so the set stays small
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("Failed to accept unix socket connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let service = TowerToHyperService::new(app.clone());
+        let mut closing_rx = closing_rx.clone();
+        connections.spawn(async move {
+            let connection = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades();
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = closing_rx.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                tracing::debug!("Unix socket connection ended with error: {}", e);
+            }
+        });
+    }
+
+    tracing::info!("Shutting down, removing {}", path.display());
+    std::fs::remove_file(path)?;
+
+    // Callers back up the database after this returns, so every request
+    // still in flight has to have finished writing first
+    drop(listener);
+    let _ = closing.send(());
+    tracing::info!("Waiting for {} open connection(s) to finish", connections.len());
+    while connections.join_next().await.is_some() {}
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Outgoing webhooks for document events. Events are queued to a background
+//! task that POSTs them to every hook registered for them, so a slow or
+//! failing receiver never holds up the request that caused the event.
+//!
+//! Each delivery carries an `X-Webhook-Signature: sha256=<hex>` header, the
+//! HMAC-SHA256 of the raw body under the hook's secret, so receivers can
+//! check it came from this server.
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqlitePool;
+use sqlx::types::Json;
+use sqlx::FromRow;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+pub const EVENTS: &[&str] = &["document.created", "document.updated", "document.published"];
+
+// Attempts per delivery, waiting RETRY_BACKOFF, then 5x that, and so on between them
+const MAX_ATTEMPTS: u32 = 4;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A registered hook; the secret is only shown when it is created
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub events: Json<Vec<String>>,
+    #[serde(serialize_with = "crate::models::serialize_timestamp")]
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub events: Vec<String>,
+    /// Generated when left out
+    pub secret: Option<String>,
+}
+
+#[derive(Debug)]
+struct PendingEvent {
+    event: &'static str,
+    body: String,
+}
+
+/// Handle for emitting webhook events, shared through AppState
+#[derive(Clone)]
+pub struct Webhooks {
+    sender: mpsc::UnboundedSender<PendingEvent>,
+}
+
+impl Webhooks {
+    pub fn spawn(db: SqlitePool, timeout: Duration) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<PendingEvent>();
+
+        // Redirects aren't followed: the signature was made for this URL
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("webhook HTTP client");
+
+        tokio::spawn(async move {
+            while let Some(pending) = receiver.recv().await {
+                let hooks = sqlx::query_as::<_, (i64, String, String)>(
+                    "SELECT id, url, secret FROM webhooks
+                     WHERE EXISTS (SELECT 1 FROM json_each(webhooks.events) WHERE value = ?)"
+                )
+                .bind(pending.event)
+                .fetch_all(&db)
+                .await;
+
+                let hooks = match hooks {
+                    Ok(hooks) => hooks,
+                    Err(e) => {
+                        tracing::error!("Failed to look up webhooks for {}: {}", pending.event, e);
+                        continue;
+                    }
+                };
+                // Each hook retries on its own schedule
+                for (id, url, secret) in hooks {
+                    tokio::spawn(deliver(client.clone(), id, url, secret, pending.event, pending.body.clone()));
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    /// Queue `event` for the hooks registered for it
+    pub fn emit<T: Serialize>(&self, event: &'static str, document: &T) {
+        let body = serde_json::json!({
+            "event": event,
+            "occurred_at": chrono::Utc::now().to_rfc3339(),
+            "document": document,
+        });
+
+        let pending = PendingEvent { event, body: body.to_string() };
+        if self.sender.send(pending).is_err() {
+            tracing::error!("Webhook sender has stopped; dropping {}", event);
+        }
+    }
+}
+
+async fn deliver(client: reqwest::Client, id: i64, url: String, secret: String, event: &'static str, body: String) {
+    let signature = format!("sha256={}", sign(&secret, &body));
+
+    let mut backoff = RETRY_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = client.post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("x-webhook-event", event)
+            .header("x-webhook-signature", &signature)
+            .body(body.clone())
+            .send()
+            .await;
+
+        let problem = match result {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => format!("answered {}", response.status()),
+            Err(e) => e.without_url().to_string(),
+        };
+        tracing::warn!("Webhook {} {} delivery failed (attempt {}/{}): {}", id, event, attempt, MAX_ATTEMPTS, problem);
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 5;
+        }
+    }
+
+    tracing::error!("Webhook {} gave up on {} after {} attempts", id, event, MAX_ATTEMPTS);
+}
+
+/// Hex HMAC-SHA256 of `body` under `secret`
+fn sign(secret: &str, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hex(hmac::sign(&key, body.as_bytes()).as_ref())
+}
+
+/// A random secret for hooks registered without one
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).expect("system random source");
+    hex(&bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -77,12 +77,13 @@ class RetryManager {
   private readonly MAX_RETRIES = 3;
   private readonly RETRY_DELAY = 1000; // 1秒
 
+  // operation 收到当前是第几次尝试（首次为 0）
   async withRetry<T>(
-    operation: () => Promise<T>,
+    operation: (attempt: number) => Promise<T>,
     retries: number = this.MAX_RETRIES
   ): Promise<T> {
     try {
-      return await operation();
+      return await operation(this.MAX_RETRIES - retries);
     } catch (error) {
       if (retries > 0 && this.isRetryableError(error)) {
         await this.delay(this.RETRY_DELAY * (this.MAX_RETRIES - retries + 1));
@@ -244,7 +245,10 @@ export const apiClient: APIClient = {
     },
 
     async delete(id: number): Promise<void> {
-      await retry.withRetry(() => api.delete(`/documents/${id}`));
+      // 仅在重试时忽略 404：首次请求可能已删除成功但响应丢失
+      await retry.withRetry((attempt) =>
+        api.delete(`/documents/${id}`, { params: attempt > 0 ? { ignore_missing: true } : undefined })
+      );
       
       // 使相关缓存失效
       cache.invalidate('/documents');
@@ -309,7 +313,10 @@ export const apiClient: APIClient = {
     },
 
     async delete(id: number): Promise<void> {
-      await retry.withRetry(() => api.delete(`/nodes/${id}`));
+      // 仅在重试时忽略 404：首次请求可能已删除成功但响应丢失
+      await retry.withRetry((attempt) =>
+        api.delete(`/nodes/${id}`, { params: attempt > 0 ? { ignore_missing: true } : undefined })
+      );
       
       // 使相关缓存失效
       cache.invalidate(`/nodes/${id}`);